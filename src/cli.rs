@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+
+pub const USAGE: &str = "usage:
+    rust-distributed-file serve --listen <addr> [--bootstrap <host:port>[,<host:port>...]] [--root <dir>] [--admin <addr>]
+    rust-distributed-file put --node <addr> --key <key> --file <path>
+    rust-distributed-file get --node <addr> --key <key> --out <path>
+    rust-distributed-file status --admin <addr>
+    rust-distributed-file demo";
+
+/// the subcommands of the binary
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// run a node until the process is killed
+    Serve {
+        listen: String,
+        /// `host:port` of the nodes to connect to, resolved when they are dialed
+        bootstrap: Vec<String>,
+        /// where the node stores its files. defaults to storage/<listen>
+        root: Option<String>,
+        /// where to answer health checks, if anywhere
+        admin: Option<String>,
+    },
+    /// store a local file on a running node
+    Put {
+        node: SocketAddr,
+        key: String,
+        file: String,
+    },
+    /// fetch a file from a running node into a local file
+    Get {
+        node: SocketAddr,
+        key: String,
+        out: String,
+    },
+    /// print the health and the peers of a running node, from its admin address
+    Status {
+        admin: SocketAddr,
+    },
+    /// run a three node cluster on localhost and store a file on it
+    Demo,
+}
+
+/// parse the command line arguments, excluding the program name
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (subcommand, rest) = match args.split_first() {
+        Some((subcommand, rest)) => (subcommand.as_str(), rest),
+        None => return Err(String::from("missing subcommand")),
+    };
+    let flags = parse_flags(rest)?;
+
+    let command = match subcommand {
+        "serve" => Command::Serve {
+            listen: flags.required("listen")?,
+            bootstrap: match flags.optional("bootstrap") {
+                Some(nodes) => nodes
+                    .split(',')
+                    .filter(|node| !node.is_empty())
+                    .map(String::from)
+                    .collect(),
+                None => Vec::new(),
+            },
+            root: flags.optional("root"),
+            admin: flags.optional("admin"),
+        },
+        "put" => Command::Put {
+            node: parse_addr(&flags.required("node")?)?,
+            key: flags.required("key")?,
+            file: flags.required("file")?,
+        },
+        "get" => Command::Get {
+            node: parse_addr(&flags.required("node")?)?,
+            key: flags.required("key")?,
+            out: flags.required("out")?,
+        },
+        "status" => Command::Status {
+            admin: parse_addr(&flags.required("admin")?)?,
+        },
+        "demo" => Command::Demo,
+        _ => return Err(format!("unknown subcommand: {}", subcommand)),
+    };
+    flags.ensure_all_used()?;
+
+    Ok(command)
+}
+
+/// `--name value` pairs, remembering which ones the subcommand has looked at
+struct Flags {
+    pairs: Vec<(String, String)>,
+    used: std::cell::RefCell<Vec<String>>,
+}
+
+impl Flags {
+    fn optional(&self, name: &str) -> Option<String> {
+        self.used.borrow_mut().push(name.to_string());
+        self.pairs.iter().rev().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+    }
+
+    fn required(&self, name: &str) -> Result<String, String> {
+        self.optional(name).ok_or(format!("missing --{}", name))
+    }
+
+    /// reject flags the subcommand doesn't know about, they are most likely typos
+    fn ensure_all_used(&self) -> Result<(), String> {
+        let used = self.used.borrow();
+        match self.pairs.iter().find(|(n, _)| !used.contains(n)) {
+            Some((name, _)) => Err(format!("unknown flag: --{}", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_flags(args: &[String]) -> Result<Flags, String> {
+    let mut pairs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name,
+            None => return Err(format!("unexpected argument: {}", arg)),
+        };
+        let value = iter.next().ok_or(format!("missing value for --{}", name))?;
+        pairs.push((name.to_string(), value.clone()));
+    }
+
+    Ok(Flags {
+        pairs,
+        used: std::cell::RefCell::new(Vec::new()),
+    })
+}
+
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse().map_err(|_| format!("invalid address: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        let command = parse_args(&args("serve --listen 127.0.0.1:4000 --bootstrap 127.0.0.1:3000,node2.internal:5000")).unwrap();
+        assert_eq!(command, Command::Serve {
+            listen: String::from("127.0.0.1:4000"),
+            bootstrap: vec![String::from("127.0.0.1:3000"), String::from("node2.internal:5000")],
+            root: None,
+            admin: None,
+        });
+
+        let command = parse_args(&args("serve --listen 127.0.0.1:4000 --admin 127.0.0.1:9000")).unwrap();
+        assert_eq!(command, Command::Serve {
+            listen: String::from("127.0.0.1:4000"),
+            bootstrap: Vec::new(),
+            root: None,
+            admin: Some(String::from("127.0.0.1:9000")),
+        });
+    }
+
+    #[test]
+    fn test_parse_put_and_get() {
+        let command = parse_args(&args("put --node 127.0.0.1:3000 --key some_key --file ./a.txt")).unwrap();
+        assert_eq!(command, Command::Put {
+            node: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            key: String::from("some_key"),
+            file: String::from("./a.txt"),
+        });
+
+        let command = parse_args(&args("get --key some_key --out ./b.txt --node 127.0.0.1:3000")).unwrap();
+        assert_eq!(command, Command::Get {
+            node: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            key: String::from("some_key"),
+            out: String::from("./b.txt"),
+        });
+
+        let command = parse_args(&args("status --admin 127.0.0.1:9000")).unwrap();
+        assert_eq!(command, Command::Status { admin: SocketAddr::from(([127, 0, 0, 1], 9000)) });
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(&args("")).is_err());
+        assert!(parse_args(&args("status")).is_err());
+        assert!(parse_args(&args("fly")).is_err());
+        assert!(parse_args(&args("put --node 127.0.0.1:3000 --key some_key")).is_err());
+        assert!(parse_args(&args("get --node not_an_addr --key k --out o")).is_err());
+        assert!(parse_args(&args("serve --listen 127.0.0.1:3000 --lisen 127.0.0.1:3001")).is_err());
+        assert!(parse_args(&args("serve --listen")).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::mpsc::RecvTimeoutError;
+
+use crate::store::store::StoreError;
+
+/// errors returned by the public api of the file server
+#[derive(Debug)]
+pub enum DfsError {
+    /// reading from or writing to the local store failed
+    Store(StoreError),
+    /// reading the input or talking to a peer failed
+    Io(io::Error),
+    /// the transport layer failed to start or stop
+    Transport(String),
+    /// a message could not be serialized or deserialized
+    Serialization(bincode::Error),
+    /// no reply was received from the peers in time
+    NoReply(RecvTimeoutError),
+}
+
+impl Display for DfsError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DfsError::Store(e) => write!(f, "{}", e),
+            DfsError::Io(e) => write!(f, "io error: {}", e),
+            DfsError::Transport(e) => write!(f, "transport error: {}", e),
+            DfsError::Serialization(e) => write!(f, "serialization error: {}", e),
+            DfsError::NoReply(e) => write!(f, "no reply from peers: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DfsError {}
+
+impl From<StoreError> for DfsError {
+    fn from(e: StoreError) -> Self {
+        DfsError::Store(e)
+    }
+}
+
+impl From<io::Error> for DfsError {
+    fn from(e: io::Error) -> Self {
+        DfsError::Io(e)
+    }
+}
+
+/// the transport trait reports errors as `Box<dyn Error>`, which cannot be sent across threads
+impl From<Box<dyn std::error::Error>> for DfsError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        DfsError::Transport(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for DfsError {
+    fn from(e: bincode::Error) -> Self {
+        DfsError::Serialization(e)
+    }
+}
+
+impl From<RecvTimeoutError> for DfsError {
+    fn from(e: RecvTimeoutError) -> Self {
+        DfsError::NoReply(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_conversions() {
+        assert!(matches!(DfsError::from(StoreError::QuotaExceeded), DfsError::Store(StoreError::QuotaExceeded)));
+        assert!(matches!(DfsError::from(io::Error::from(io::ErrorKind::NotConnected)), DfsError::Io(_)));
+        let transport_err: Box<dyn std::error::Error> = "cannot bind".into();
+        assert!(matches!(DfsError::from(transport_err), DfsError::Transport(e) if e == "cannot bind"));
+        let bincode_err = bincode::deserialize::<String>(&[1]).unwrap_err();
+        assert!(matches!(DfsError::from(bincode_err), DfsError::Serialization(_)));
+        assert!(matches!(DfsError::from(RecvTimeoutError::Timeout), DfsError::NoReply(RecvTimeoutError::Timeout)));
+    }
+}
@@ -24,4 +24,20 @@ pub fn read_all_from_stream(r: &mut dyn io::Read) -> io::Result<Vec<u8>> {
     println!("Read {} bytes in total", result.len());
 
     Ok(result)
-}
\ No newline at end of file
+}
+
+/// like `read_all_from_stream`, for an async stream, e.g. the body of a request in a tokio based service
+#[cfg(feature = "async")]
+pub async fn read_all_from_stream_async<R: tokio::io::AsyncRead + Unpin + ?Sized>(r: &mut R) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut result = Vec::new();
+    if let Err(e) = r.read_to_end(&mut result).await {
+        println!("Error reading from stream: {}", e);
+        return Err(e);
+    }
+
+    println!("Read {} bytes in total", result.len());
+
+    Ok(result)
+}
@@ -3,68 +3,210 @@ extern crate crypto;
 extern crate serde;
 
 // pub mod lib;
+mod cli;
+pub mod error;
 pub mod server;
 pub mod store;
 pub mod transport;
 
-use std::net::SocketAddr;
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream};
 use std::sync::Arc;
-use std::{thread, vec};
+use std::{env, fs, io, process, thread, vec};
 use std::time::Duration;
 
-use server::file_server::{FileServer, FileServerOpts};
-use transport::encoding::DefaultDecoder;
+use cli::Command;
+use server::file_server::{Consistency, ConsumeRetryPolicy, FileServer, FileServerOpts, ServerHandle, WireFormat, DEFAULT_BOOTSTRAP_TIMEOUT, DEFAULT_CLOSE_TIMEOUT, DEFAULT_CONSUME_BATCH, DEFAULT_GOSSIP_HOPS, DEFAULT_MAX_GET_SIZE, FRAME_CONTROL, DEFAULT_RECONNECT_INTERVAL, DEFAULT_SWEEP_INTERVAL};
+use transport::codec::Codec;
+use transport::encoding::{ChecksumDecoder, ChecksumEncoder, TypedFrameDecoder, TypedFrameEncoder};
 use transport::tcp::{self, TcpTransport, TcpTransportOpts};
 
-fn make_server(listen_addr: String, nodes: Vec<SocketAddr>) -> Arc<FileServer<TcpTransport>> {
+/// how long the put and get subcommands wait to connect to the node
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn make_server(listen_addr: String, root_dir: String, nodes: Vec<String>, admin_addr: Option<String>) -> Result<Arc<FileServer<TcpTransport>>, io::Error> {
     // create the transport layer
-    let opts = TcpTransportOpts::new(listen_addr.clone(), Box::new(DefaultDecoder {}));
-    let tcp_transport = tcp::TcpTransport::new(opts);
+    // checksummed so that a frame mangled on the way is dropped rather than stored
+    // control messages are consumed ahead of the file contents, so a large transfer doesn't hold them up
+    let mut frame_decoder = TypedFrameDecoder::new();
+    frame_decoder.priority_types = vec![FRAME_CONTROL];
+    let decoder = ChecksumDecoder::new(Box::new(frame_decoder));
+    let mut opts = TcpTransportOpts::new(listen_addr.clone(), Box::new(decoder));
+    opts.encoder = Arc::new(ChecksumEncoder::new(Arc::new(TypedFrameEncoder {})));
+    // so that a node dialed back by a peer is still a single peer
+    opts.advertise_addr = true;
+    // compress what the peers can decompress
+    opts.codecs = Some(vec![Codec::Deflate, Codec::Identity]);
+    let tcp_transport = tcp::TcpTransport::new(opts)?;
     
     let file_server_opts = FileServerOpts {
-        store_opts: store::store::StoreOpts {
-            root_dir: format!("storage/{}", listen_addr),
-            filename_transform: store::hashlib::filename_transform,
-        },
+        store_opts: store::store::StoreOpts::new(
+            root_dir,
+            store::hashlib::filename_transform,
+        ),
         transport: tcp_transport.clone(),
-        bootstrap_node: nodes,
+        bootstrap_node: Vec::new(),
+        bootstrap_hosts: nodes,
+        consume_retry: ConsumeRetryPolicy::default(),
+        reconnect_interval: Some(DEFAULT_RECONNECT_INTERVAL),
+        wire_format: WireFormat::Bincode,
+        sweep_interval: Some(DEFAULT_SWEEP_INTERVAL),
+        close_timeout: DEFAULT_CLOSE_TIMEOUT,
+        drain_on_shutdown: true,
+        admin_addr,
+        gossip_hops: DEFAULT_GOSSIP_HOPS,
+        bootstrap_timeout: Some(DEFAULT_BOOTSTRAP_TIMEOUT),
+        consistency: Consistency::Local,
+        blocking_consume: true,
+        store_locally: true,
+        consume_batch: DEFAULT_CONSUME_BATCH,
+        max_get_size: DEFAULT_MAX_GET_SIZE,
     };
 
-    let server = FileServer::new(file_server_opts);
-    
-    server
+    Ok(FileServer::new(file_server_opts)?)
+}
+
+/// start a short-lived node connected to `node`, for the subcommands talking to a running node
+fn connect_client(node: SocketAddr) -> Result<ServerHandle<TcpTransport>, String> {
+    let client = make_server("127.0.0.1:0".to_string(), format!("storage/client-{}", process::id()), vec![node.to_string()], None)
+        .map_err(|e| format!("cannot start the client: {}", e))?
+        .start_background();
+
+    if !wait_for(CONNECT_TIMEOUT, || !client.server().peer_addrs().is_empty()) {
+        stop_client(client);
+        return Err(format!("cannot connect to {}", node));
+    }
+
+    Ok(client)
+}
+
+/// stop the client started by `connect_client` and remove what it has stored
+fn stop_client(client: ServerHandle<TcpTransport>) {
+    client.shutdown();
+    let _ = client.join();
+    let _ = fs::remove_dir_all(format!("storage/client-{}", process::id()));
 }
 
 fn main() {
-    let server = make_server(
-        "127.0.0.1:3000".to_string(), 
-        Vec::new(),
-    );
-
-    let p1 = make_server("127.0.0.1:4000".to_string(), vec![SocketAddr::from(([127, 0, 0, 1], 3000))]);
-
-    thread::scope(|s| {
-        // thread for server 1 (aka bootstrap node)
-        s.spawn(|| {
-            server.clone().start().unwrap();
-        });
-        // thread for peer 1
-        s.spawn(|| {
-            let p1a = p1.clone();
-            thread::spawn(move || {
-                p1.clone().start().unwrap();
-            });
-            thread::sleep(Duration::from_secs(5));
-            let key = String::from("some_test_file");
-            let r = vec![1, 2, 3, 4];
-            p1a.clone().store_data(key, &mut r.as_slice());
-        });
-        // thread for peer 2
-        s.spawn(|| {
-            let p2 = make_server("127.0.0.1:5000".to_string(), vec![SocketAddr::from(([127, 0, 0, 1], 3000))]);
-            thread::spawn(move || {
-                p2.clone().start().unwrap();
-            });
-        });
-    });
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let res = match command {
+        Command::Serve { listen, bootstrap, root, admin } => serve(listen, bootstrap, root, admin),
+        Command::Put { node, key, file } => put(node, key, file),
+        Command::Get { node, key, out } => get(node, key, out),
+        Command::Status { admin } => status(admin),
+        Command::Demo => demo(),
+    };
+
+    if let Err(e) = res {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn serve(listen: String, bootstrap: Vec<String>, root: Option<String>, admin: Option<String>) -> Result<(), String> {
+    let root = root.unwrap_or(format!("storage/{}", listen));
+    let server = make_server(listen.clone(), root, bootstrap, admin).map_err(|e| format!("cannot listen on {}: {}", listen, e))?;
+
+    server.start().map_err(|e| e.to_string())
+}
+
+fn put(node: SocketAddr, key: String, file: String) -> Result<(), String> {
+    let data = fs::read(&file).map_err(|e| format!("cannot read {}: {}", file, e))?;
+    let client = connect_client(node)?;
+    let res = client.server().store_data(key, &mut data.as_slice());
+    stop_client(client);
+
+    res.map_err(|e| e.to_string())
+}
+
+fn get(node: SocketAddr, key: String, out: String) -> Result<(), String> {
+    let client = connect_client(node)?;
+    let res = client.server().get_data(key);
+    stop_client(client);
+
+    let data = res.map_err(|e| e.to_string())?;
+    fs::write(&out, data).map_err(|e| format!("cannot write {}: {}", out, e))
+}
+
+/// print what the node answers on its admin address. fail if it is unhealthy
+fn status(admin: SocketAddr) -> Result<(), String> {
+    let mut conn = TcpStream::connect_timeout(&admin, CONNECT_TIMEOUT).map_err(|e| format!("cannot connect to {}: {}", admin, e))?;
+    let mut report = String::new();
+    conn.read_to_string(&mut report).map_err(|e| format!("cannot read from {}: {}", admin, e))?;
+    print!("{}", report);
+
+    match report.starts_with("ok ") {
+        true => Ok(()),
+        false => Err(format!("{} is unhealthy", admin)),
+    }
+}
+
+/// run a three node cluster on localhost: two nodes bootstrap from the first one, the second node stores a file
+/// and the third one fetches it back through the first one
+fn demo() -> Result<(), String> {
+    let key = String::from("some_test_file");
+    let data = vec![1, 2, 3, 4];
+    let bootstrap = SocketAddr::from(([127, 0, 0, 1], 3000));
+    let servers: Vec<Arc<FileServer<TcpTransport>>> = ["127.0.0.1:3000", "127.0.0.1:4000", "127.0.0.1:5000"]
+        .iter()
+        .map(|addr| {
+            let nodes = if addr.parse() == Ok(bootstrap) { Vec::new() } else { vec![bootstrap.to_string()] };
+            make_server(addr.to_string(), format!("storage/{}", addr), nodes, None)
+                .map_err(|e| format!("cannot listen on {}: {}", addr, e))
+        })
+        .collect::<Result<_, _>>()?;
+    let handles: Vec<ServerHandle<TcpTransport>> = servers.iter().map(|server| server.clone().start_background()).collect();
+
+    let res = run_demo(&servers, key, data);
+    for handle in handles.iter() {
+        handle.shutdown();
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    res
+}
+
+fn run_demo(nodes: &[Arc<FileServer<TcpTransport>>], key: String, data: Vec<u8>) -> Result<(), String> {
+    if !wait_for(CONNECT_TIMEOUT, || nodes[0].peer_addrs().len() == nodes.len() - 1) {
+        return Err(format!("only {} of {} nodes joined the bootstrap node", nodes[0].peer_addrs().len(), nodes.len() - 1));
+    }
+    nodes[1].store_data(key.clone(), &mut data.as_slice()).map_err(|e| e.to_string())?;
+
+    // the third node is only connected to the first one, so the file must have been propagated there.
+    // the store message may still be in flight when the first request arrives
+    for _ in 0..3 {
+        if let Ok(fetched) = nodes[2].get_data(key.clone()) {
+            if fetched != data {
+                return Err(format!("{} came back as {:?} instead of {:?}", key, fetched, data));
+            }
+            println!("demo succeeded: {} was stored on one node and fetched from another", key);
+            return Ok(());
+        }
+    }
+
+    Err(format!("{} did not propagate to the other nodes", key))
+}
+
+/// poll the condition until it holds or the timeout is reached. return whether it holds
+fn wait_for(timeout: Duration, cond: impl Fn() -> bool) -> bool {
+    let mut waited = Duration::ZERO;
+    while !cond() {
+        if waited >= timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+        waited += Duration::from_millis(100);
+    }
+
+    true
+}
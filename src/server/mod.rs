@@ -1,16 +1,26 @@
 pub mod file_server {
-    use std::collections::HashMap;
-    use std::net::SocketAddr;
-    use std::sync::mpsc::RecvTimeoutError;
+    use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+    use std::io::Write;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc::{channel, RecvTimeoutError};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
     use std::sync::RwLock;
     use std::sync::{mpsc::{Receiver, Sender}, Arc, Mutex};
+    use std::net::ToSocketAddrs;
+    use std::path::Path;
+    use std::fs;
     use std::{io, thread};
 
+    use bincode::Options;
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
 
+    use crate::error::DfsError;
+    use crate::server::ring::{HashRing, DEFAULT_VNODES};
     use crate::transport::message::Message;
     use crate::{
-        store::store::{Store, StoreOpts}, 
+        store::{hashlib, store::{Metadata, Store, StoreError, StoreOpts}}, 
         transport::transport::{PeerLike, Transport},
     };
 
@@ -19,8 +29,261 @@ pub mod file_server {
         pub store_opts: StoreOpts,
         pub transport: Arc<T>,
         pub bootstrap_node: Vec<SocketAddr>,
+        /// more bootstrap nodes as `host:port` strings, resolved when they are dialed
+        pub bootstrap_hosts: Vec<String>,
+        /// what `run` does when the transport's message channel is disconnected
+        pub consume_retry: ConsumeRetryPolicy,
+        /// how often `start` checks that the bootstrap nodes are still connected and re-dials the ones that are not.  
+        /// None never re-dials them. see `DEFAULT_RECONNECT_INTERVAL`
+        pub reconnect_interval: Option<Duration>,
+        /// how messages are serialized, must match the other nodes
+        pub wire_format: WireFormat,
+        /// how often `start` deletes the files whose ttl has run out, see `FileServer::store_with_ttl`.  
+        /// None never deletes them. see `DEFAULT_SWEEP_INTERVAL`
+        pub sweep_interval: Option<Duration>,
+        /// how long `run` waits for the transport to close on shutdown before stopping anyway.
+        /// see `DEFAULT_CLOSE_TIMEOUT`
+        pub close_timeout: Duration,
+        /// on shutdown, handle the messages already received before closing the transport, so that e.g. a file
+        /// sent just before is still stored. otherwise they are dropped
+        pub drain_on_shutdown: bool,
+        /// where `start` serves the health of the node, see `FileServer::health`. each connection gets a line
+        /// like "ok listening=true peers=2 store=ok", followed by one line per peer from `FileServer::peer_info`,
+        /// and is closed. no admin port if None
+        pub admin_addr: Option<String>,
+        /// how many more times a file stored with `store_data` is passed on by each node receiving it, to the peers
+        /// other than the one it came from, so that it reaches the nodes not connected to this one. a node passes
+        /// each file on once at most. 0 only sends it to the peers. see `DEFAULT_GOSSIP_HOPS`
+        pub gossip_hops: u8,
+        /// how long `start` waits for the bootstrap nodes to be connected before handling messages, so that a file
+        /// stored right after reaches them. None doesn't wait. see `FileServer::bootstrap_network_blocking`
+        pub bootstrap_timeout: Option<Duration>,
+        /// when `store_data` returns, see `Consistency`
+        pub consistency: Consistency,
+        /// have `run` wait for messages with `Transport::consume_blocking`, so that an idle node doesn't wake up
+        /// every second. `shutdown` wakes it up. otherwise `consume` is polled
+        pub blocking_consume: bool,
+        /// keep a copy of the files stored through this node. false makes it a proxy: `store_data` only sends the
+        /// file to the other nodes, and a file received from a peer is forwarded to the other peers without being
+        /// written. `get_data` then always fetches from the peers, and the copy it gets is not kept either.
+        /// with `Consistency::QuorumAck`, a proxy acks a store once it has forwarded it to other peers
+        pub store_locally: bool,
+        /// the most messages `run` handles in a row, taking the ones already received with
+        /// `Transport::consume_batch` after each message it waited for. 1 handles them one at a time.
+        /// see `DEFAULT_CONSUME_BATCH`
+        pub consume_batch: usize,
+        /// the most bytes `get_data` takes from a peer for a single file. a larger reply is dropped and the next
+        /// replica is asked. a file that wouldn't fit in the store is dropped too, see `Store::check_room`.
+        /// see `DEFAULT_MAX_GET_SIZE`
+        pub max_get_size: u64,
     }
 
+    /// a server started by `FileServer::start_background`
+    pub struct ServerHandle<T: Transport> {
+        server: Arc<FileServer<T>>,
+        thread: thread::JoinHandle<Result<(), DfsError>>,
+    }
+
+    impl<T: Transport> ServerHandle<T> {
+        /// the running server, e.g. to store data on it
+        pub fn server(&self) -> &Arc<FileServer<T>> {
+            &self.server
+        }
+
+        /// ask the server to stop. see `join` to wait for it
+        pub fn shutdown(&self) {
+            self.server.clone().shutdown();
+        }
+
+        /// wait for the server to stop and return how `start` ended
+        pub fn join(self) -> Result<(), DfsError> {
+            match self.thread.join() {
+                Ok(res) => res,
+                Err(_) => Err(DfsError::Transport(String::from("the server thread panicked"))),
+            }
+        }
+    }
+
+    /// what `FileServer::health` found, e.g. for the liveness probes of an orchestrator
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Health {
+        /// whether the transport takes new connections
+        pub listening: bool,
+        pub peer_count: usize,
+        /// whether the store could be read
+        pub store_ok: bool,
+    }
+
+    impl Health {
+        /// whether the node can serve requests
+        pub fn is_ok(&self) -> bool {
+            self.listening && self.store_ok
+        }
+    }
+
+    impl std::fmt::Display for Health {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                f,
+                "{} listening={} peers={} store={}",
+                if self.is_ok() { "ok" } else { "unhealthy" },
+                self.listening,
+                self.peer_count,
+                if self.store_ok { "ok" } else { "error" },
+            )
+        }
+    }
+
+    /// a connected peer as seen from this node, see `FileServer::peer_info`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct PeerInfo {
+        pub addr: SocketAddr,
+        /// whether this node dialed the peer, rather than the peer dialing in
+        pub outbound: bool,
+        /// when the peer was added, in seconds since the unix epoch
+        pub connected_at: u64,
+        /// when the peer last answered a ping, in seconds since the unix epoch. None until it does
+        pub last_seen: Option<u64>,
+        /// see `FileServer::peer_latency`
+        pub latency: Option<Duration>,
+    }
+
+    impl std::fmt::Display for PeerInfo {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(
+                f,
+                "peer {} {} connected_at={}",
+                self.addr,
+                if self.outbound { "outbound" } else { "inbound" },
+                self.connected_at,
+            )?;
+            if let Some(last_seen) = self.last_seen {
+                write!(f, " last_seen={}", last_seen)?;
+            }
+            if let Some(latency) = self.latency {
+                write!(f, " latency={:?}", latency)?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// a change to the files held by this node, see `FileServer::subscribe`
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum StoreEvent {
+        /// the file was written, here or by a peer, with its size in bytes
+        Stored(String, u64),
+        Deleted(String),
+    }
+
+    /// a reasonable `FileServerOpts::reconnect_interval`
+    pub const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+    /// attempts of each re-dial of a bootstrap node, see `Transport::try_dial`. the next check tries again anyway
+    const RECONNECT_DIAL_ATTEMPTS: u8 = 2;
+    /// a reasonable `FileServerOpts::sweep_interval`
+    pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+    /// a reasonable `FileServerOpts::close_timeout`
+    pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+    /// a reasonable `FileServerOpts::bootstrap_timeout`
+    pub const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(10);
+    /// how often `bootstrap_network_blocking` checks whether the bootstrap nodes are connected
+    const BOOTSTRAP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+    /// a reasonable `FileServerOpts::gossip_hops`
+    pub const DEFAULT_GOSSIP_HOPS: u8 = 3;
+    /// a reasonable `FileServerOpts::consume_batch`
+    pub const DEFAULT_CONSUME_BATCH: usize = 64;
+    /// a reasonable `FileServerOpts::max_get_size`
+    pub const DEFAULT_MAX_GET_SIZE: u64 = 1024 * 1024 * 1024;
+    /// how many of the latest gossiped files a node remembers having passed on, see `SeenGossip`
+    const MAX_SEEN_GOSSIP: usize = 4096;
+
+    /// how `FileServer::run` handles the transport's message channel being disconnected.  
+    /// the transport may be re-creating its channel, e.g. while it is reconfigured, so `consume` is retried
+    /// after `backoff` and `Transport::reattach` up to `max_attempts` times in a row before `run` gives up with an error
+    #[derive(Debug, Clone, Copy)]
+    pub struct ConsumeRetryPolicy {
+        pub max_attempts: u32,
+        pub backoff: Duration,
+    }
+
+    impl Default for ConsumeRetryPolicy {
+        fn default() -> Self {
+            ConsumeRetryPolicy {
+                max_attempts: 5,
+                backoff: Duration::from_millis(200),
+            }
+        }
+    }
+
+    /// tells a get request from the others sent by this node. see `MessageGet`
+    type RequestId = u64;
+
+    /// the result of the local delete of each key, see `FileServer::delete_prefix`
+    pub type DeleteResults = Vec<(String, Result<(), io::ErrorKind>)>;
+
+    /// the result of storing each file, by key, see `FileServer::store_dir`
+    pub type ImportResults = Vec<(String, Result<(), DfsError>)>;
+
+    /// a `get_data` call waiting for a peer to send the file
+    struct PendingGet {
+        /// the peer currently asked
+        peer: SocketAddr,
+        /// the pieces of the file received so far
+        received: Vec<u8>,
+        /// where to send the file once complete. None is sent when the peer doesn't hold the file
+        sender: Sender<Option<Vec<u8>>>,
+    }
+
+    /// a `get_range` call waiting for a peer to send the range
+    struct PendingRange {
+        /// the peer asked
+        peer: SocketAddr,
+        key: String,
+        offset: u64,
+        /// where to send the range. None is sent when the peer doesn't hold the file
+        sender: Sender<Option<Vec<u8>>>,
+    }
+
+    /// the gossiped files a node has already handled, so that a file going around a loop of nodes is dropped
+    /// the second time. only the latest `MAX_SEEN_GOSSIP` are remembered
+    #[derive(Default)]
+    struct SeenGossip {
+        ids: HashSet<(String, u64)>,
+        order: VecDeque<(String, u64)>,
+    }
+
+    impl SeenGossip {
+        /// remember the file. return whether it was new
+        fn insert(&mut self, origin: &str, id: u64) -> bool {
+            if !self.ids.insert((origin.to_string(), id)) {
+                return false;
+            }
+            self.order.push_back((origin.to_string(), id));
+            if self.order.len() > MAX_SEEN_GOSSIP {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.ids.remove(&oldest);
+                }
+            }
+
+            true
+        }
+    }
+
+    /// the size of the pieces a file is sent in when a peer asks for it, see `MessageType::GetResponse`.
+    /// the file is read from the store as it is sent, so this is about as much of it as is held in memory
+    const GET_RESPONSE_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// the most bytes `get_range` asks a replica for at once, well below the largest message a reply may be
+    const RANGE_PIECE_SIZE: u64 = GET_RESPONSE_CHUNK_SIZE as u64;
+
+    /// `get_data` and `get_range` fail rather than waiting on more peers than this at once
+    const MAX_PENDING_GETS: usize = 1024;
+
+    /// a ping not answered within this is forgotten, its pong is then ignored
+    const PING_TIMEOUT: Duration = Duration::from_secs(30);
+    /// `ping` fails rather than waiting on more pongs than this at once
+    const MAX_PENDING_PINGS: usize = 1024;
+
     // for future me: FileServer is generic since we need to make sure the size of the transport layer is known at compile time
     // the transport layer can be generic in coding level, but in runtime, we need to know the size of the transport layer
     pub struct FileServer<T: Transport> {
@@ -28,12 +291,225 @@ pub mod file_server {
         store: Store,
         shutdown_chan: (Mutex<Sender<bool>>, Mutex<Receiver<bool>>),
         bootstrap_node: Vec<SocketAddr>,
-        peers: RwLock<HashMap<SocketAddr, Arc<RwLock<dyn PeerLike + Sync + Send>>>>
+        bootstrap_hosts: Vec<String>,
+        consume_retry: ConsumeRetryPolicy,
+        reconnect_interval: Option<Duration>,
+        wire_format: WireFormat,
+        /// the most bytes a message may decode from, the largest payload the transport accepts
+        max_message_size: u64,
+        sweep_interval: Option<Duration>,
+        close_timeout: Duration,
+        drain_on_shutdown: bool,
+        admin_addr: Option<String>,
+        /// the address the admin listener is bound to while it serves, see `admin_local_addr`
+        admin_local_addr: Mutex<Option<SocketAddr>>,
+        gossip_hops: u8,
+        bootstrap_timeout: Option<Duration>,
+        consistency: Consistency,
+        blocking_consume: bool,
+        store_locally: bool,
+        consume_batch: usize,
+        max_get_size: u64,
+        /// set by `shutdown` to stop the background threads
+        stopping: AtomicBool,
+        peers: RwLock<HashMap<SocketAddr, Arc<RwLock<dyn PeerLike + Sync + Send>>>>,
+        /// when each peer was added, in seconds since the unix epoch
+        connected_at: Mutex<HashMap<SocketAddr, u64>>,
+        /// the peers and this node, to place the keys on. see `placement_for`
+        ring: RwLock<HashRing>,
+        /// files being received in chunks, by sending peer and key. the peer is known by the listen address it
+        /// advertised, which stays the same when it reconnects, so that the transfer can be resumed. it is never
+        /// taken from the message, so that a peer can't write into the transfer of another one
+        chunk_buffers: Mutex<HashMap<(SocketAddr, String), ChunkBuffer>>,
+        /// `store_in_chunks` calls waiting for a peer to tell where to resume sending a file, by peer and key
+        pending_resumes: Mutex<HashMap<(SocketAddr, String), Sender<u64>>>,
+        /// `get_data` calls waiting for a peer to send the file, by request id. an entry is removed when its
+        /// reply arrives or by the `get_data` call once it stops waiting, so the replies that never arrive don't
+        /// pile up. see `MAX_PENDING_GETS`
+        pending_gets: Mutex<HashMap<RequestId, PendingGet>>,
+        /// `get_range` calls waiting for a peer to send the range, by request id, like `pending_gets`. a range
+        /// nobody is waiting for is dropped
+        pending_ranges: Mutex<HashMap<RequestId, PendingRange>>,
+        /// `store_data` calls waiting for the replicas to ack the file, by request id. each ack sends the address
+        /// of the peer. an entry is removed by the `store_data` call once it stops waiting
+        pending_acks: Mutex<HashMap<RequestId, Sender<SocketAddr>>>,
+        next_request_id: AtomicU64,
+        /// when each unanswered ping was sent, by peer and nonce
+        pending_pings: Mutex<HashMap<(SocketAddr, u64), Instant>>,
+        next_ping_nonce: AtomicU64,
+        /// rolling average of the round trip time to each peer
+        latencies: Mutex<HashMap<SocketAddr, Duration>>,
+        /// when each peer last answered a ping, in seconds since the unix epoch
+        last_seen: Mutex<HashMap<SocketAddr, u64>>,
+        /// when each file stored with a ttl expires, in seconds since the unix epoch, by key. see `sweep_expired`
+        expiries: Mutex<HashMap<String, u64>>,
+        /// see `SeenGossip`
+        seen_gossip: Mutex<SeenGossip>,
+        next_gossip_id: AtomicU64,
+        /// the peers that said bye and whose connection is being closed, to tell a clean departure from a failure
+        departed: Mutex<HashSet<SocketAddr>>,
+        /// see `subscribe`. a subscriber is forgotten once its receiver is dropped
+        subscribers: Mutex<Vec<Sender<StoreEvent>>>,
+    }
+
+    /// sends what is written to it to a peer asking for a file, in `GetResponse` messages of
+    /// `GET_RESPONSE_CHUNK_SIZE` bytes. see `FileServer::handle_get_message`
+    struct GetResponseWriter<'a, T: Transport> {
+        server: &'a Arc<FileServer<T>>,
+        to: SocketAddr,
+        request_id: RequestId,
+        key: String,
+        /// where the bytes in `buf` start in the file
+        offset: u64,
+        buf: Vec<u8>,
+    }
+
+    impl<T: Transport> GetResponseWriter<'_, T> {
+        /// send the first `len` bytes of `buf`
+        fn send(&mut self, len: usize, is_last: bool, meta: Option<Metadata>) -> io::Result<()> {
+            let msg = MessageGetResponse {
+                request_id: self.request_id,
+                key: self.key.clone(),
+                offset: self.offset,
+                data: self.buf.drain(..len).collect(),
+                is_last,
+                meta,
+            };
+            let payload = Payload {
+                from: self.server.transport.clone().addr(),
+                msg_type: MessageType::GetResponse,
+                msg: msg.to_buffer(self.server.wire_format).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            };
+            self.offset += len as u64;
+            self.server.send_to_peer(self.to, payload)
+        }
+
+        /// send what is left as the last piece, along with the metadata of the file
+        fn finish(mut self, meta: Option<Metadata>) -> io::Result<()> {
+            self.send(self.buf.len(), true, meta)
+        }
+    }
+
+    impl<T: Transport> io::Write for GetResponseWriter<'_, T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            while self.buf.len() >= GET_RESPONSE_CHUNK_SIZE {
+                self.send(GET_RESPONSE_CHUNK_SIZE, false, None)?;
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// how long `store_in_chunks` waits for a peer to tell where to resume, before sending the whole file
+    const RESUME_TIMEOUT: Duration = Duration::from_secs(2);
+    /// a transfer in chunks getting no new chunk for this long is dropped along with its temporary file, e.g. when
+    /// the sender went away in the middle of it
+    const CHUNK_TRANSFER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+    /// a chunk may start at most this far past the bytes received without a gap, so that a peer can't make the
+    /// temporary file huge with a single chunk at a far offset
+    const CHUNK_WINDOW: u64 = 64 * 1024 * 1024;
+    /// the most transfers in chunks received at once. the chunks starting another one are dropped
+    const MAX_CHUNK_TRANSFERS: usize = 64;
+    /// how long `get_data` waits for each peer to send the file
+    const GET_TIMEOUT: Duration = Duration::from_secs(5);
+    /// how long `store_data` waits for a majority of the replicas to ack the file, see `Consistency::QuorumAck`
+    const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+    /// weight of the latest sample in the rolling average of the round trip time, as 1 / n
+    const LATENCY_SMOOTHING: u32 = 4;
+
+    /// how the messages exchanged with peers are serialized. every node of a cluster must use the same format
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum WireFormat {
+        /// compact binary encoding
+        #[default]
+        Bincode,
+        /// human readable, e.g. to inspect the traffic, at the cost of larger messages
+        Json,
+    }
+
+    /// what `store_data`, `store_with_meta` and `store_with_ttl` wait for before returning
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum Consistency {
+        /// the file is stored locally, the other replicas get it in the background
+        #[default]
+        Local,
+        /// the file is stored locally and a majority of its replicas, this node included, acked it. fail if they
+        /// don't within `ACK_TIMEOUT`, the file staying stored wherever it already is
+        QuorumAck,
     }
 
+    /// deserialize a message in the given format, bounded by `limit` bytes, the largest payload the transport
+    /// accepts. see `Transport::max_payload_size`
+    fn decode<M: DeserializeOwned>(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<M> {
+        // bincode doesn't count single bytes against the limit, so a long `Vec<u8>` has to be caught here
+        if buf.len() as u64 > limit {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+
+        match format {
+            WireFormat::Bincode => bincode::options()
+                .with_fixint_encoding()
+                .allow_trailing_bytes()
+                .with_limit(limit)
+                // unlike `deserialize`, reading from a reader enforces the limit
+                .deserialize_from(buf),
+            WireFormat::Json => serde_json::from_slice(buf).map_err(json_error),
+        }
+    }
+
+    /// serialize a message in the given format, see `decode`
+    fn encode<M: Serialize>(msg: &M, format: WireFormat) -> bincode::Result<Vec<u8>> {
+        match format {
+            WireFormat::Bincode => bincode::serialize(msg),
+            WireFormat::Json => serde_json::to_vec(msg).map_err(json_error),
+        }
+    }
+
+    /// report a JSON error like the bincode ones, so that callers handle a single error type
+    fn json_error(e: serde_json::Error) -> bincode::Error {
+        Box::new(bincode::ErrorKind::Custom(e.to_string()))
+    }
+
+    /// the first byte of every buffer sent to a peer, see `Payload::to_frame`. with `TypedFrameEncoder` it goes
+    /// ahead of the length prefix, so that the receiver knows whether a large store message is coming before reading it
+    pub const FRAME_CONTROL: u8 = 0;
+    const FRAME_STORE: u8 = 1;
+
     #[derive(Serialize, Deserialize, Debug)]
     enum MessageType {
         Store,
+        /// ask a peer for a byte range of a file it holds. see `MessageGetRange`
+        GetRange,
+        /// reply to `GetRange` carrying the requested bytes. see `MessageRangeData`
+        RangeData,
+        /// ask the peers for a whole file. see `MessageGet`
+        Get,
+        /// reply to `Get` carrying a piece of the file. the file is sent in as many as needed, in order.
+        /// see `MessageGetResponse`
+        GetResponse,
+        /// measure the round trip time to a peer. see `MessagePing`
+        Ping,
+        /// reply to `Ping` echoing its nonce. see `MessagePing`
+        Pong,
+        /// a piece of a file sent in several messages. see `MessageStoreChunk`
+        StoreChunk,
+        /// a file has been deleted, e.g. as it expired. see `MessageDelete`
+        Delete,
+        /// reply to `Get` when the file is not held. see `MessageGet`
+        GetNotFound,
+        /// ask a peer how much of a file sent in chunks it already has. see `MessageStoreResume`
+        StoreResume,
+        /// reply to `StoreResume`. see `MessageResumePoint`
+        ResumePoint,
+        /// the peer is shutting down and about to close the connection. carries nothing
+        Bye,
+        /// reply to `Store` once the file is stored, when the sender asked for it. see `MessageStoreAck`
+        StoreAck,
     }
 
     /// represent the payload of the message in message.rs/Message
@@ -47,12 +523,35 @@ pub mod file_server {
     }
 
     impl Payload {
-        pub fn from_buffer(buf: Vec<u8>) -> Payload {
-            bincode::deserialize(&buf).unwrap()
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<Payload> {
+            decode(buf, format, limit)
+        }
+
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+
+        /// the buffer sent to peers: a frame type byte followed by the message.  
+        /// a store message is sent as a bare `MessageData` so that its type byte alone tells what it is
+        pub fn to_frame(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            match self.msg_type {
+                MessageType::Store => Ok([&[FRAME_STORE][..], &self.msg].concat()),
+                _ => Ok([vec![FRAME_CONTROL], self.to_buffer(format)?].concat()),
+            }
         }
 
-        pub fn to_buffer(&self) -> Vec<u8> {
-            bincode::serialize(&self).unwrap()
+        /// read a buffer made by `to_frame`, received from the peer at `from`
+        pub fn from_frame(from: SocketAddr, buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<Payload> {
+            match buf.split_first() {
+                Some((&FRAME_CONTROL, rest)) => Payload::from_buffer(rest, format, limit),
+                Some((&FRAME_STORE, rest)) => Ok(Payload {
+                    from: from.to_string(),
+                    msg_type: MessageType::Store,
+                    msg: rest.to_vec(),
+                }),
+                Some((frame_type, _)) => Err(Box::new(bincode::ErrorKind::Custom(format!("unknown frame type {}", frame_type)))),
+                None => Err(Box::new(bincode::ErrorKind::Custom(String::from("empty frame")))),
+            }
         }
     }
 
@@ -60,172 +559,3813 @@ pub mod file_server {
     struct MessageData {
         key: String,
         data: Vec<u8>,
+        /// stored along with the data by the receiver, if any
+        meta: Option<Metadata>,
+        /// set when the receiver should pass the file on, see `FileServerOpts::gossip_hops`
+        gossip: Option<Gossip>,
+        /// set when the sender waits for the receiver to ack the file, see `Consistency::QuorumAck`
+        ack_id: Option<RequestId>,
+    }
+
+    /// where a gossiped file comes from, and how much further it goes
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    struct Gossip {
+        /// the address of the node the file was stored on first
+        origin: String,
+        /// tells the files gossiped by the origin apart
+        id: u64,
+        /// how many more times the file is passed on
+        hops_left: u8,
     }
 
+    /// the first byte of a `MessageData` buffer: the data is serialized along with the other fields
+    const DATA_EMBEDDED: u8 = 0;
+    /// the first byte of a `MessageData` buffer: the other fields are serialized without the data, behind a 4 bytes
+    /// big endian length, and followed by the data as it is
+    const DATA_RAW: u8 = 1;
+    /// data larger than this is sent raw. serializing a `Vec<u8>` goes through it byte by byte, and json
+    /// spells out each of them
+    const RAW_DATA_THRESHOLD: usize = 4 * 1024;
+
     /// helper functions for serializing and deserializing the payload
     impl MessageData {
-        pub fn from_buffer(buf: &Vec<u8>) -> MessageData {
-            bincode::deserialize(&buf).unwrap()
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageData> {
+            let cut_short = || Box::new(bincode::ErrorKind::Custom(String::from("store message cut short")));
+            match buf.split_first() {
+                Some((&DATA_EMBEDDED, rest)) => decode(rest, format, limit),
+                Some((&DATA_RAW, rest)) => {
+                    if buf.len() as u64 > limit {
+                        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+                    }
+                    let (len, rest) = rest.split_first_chunk::<4>().ok_or_else(cut_short)?;
+                    let header_len = u32::from_be_bytes(*len) as usize;
+                    if rest.len() < header_len {
+                        return Err(cut_short());
+                    }
+                    let (header, data) = rest.split_at(header_len);
+                    let mut msg: MessageData = decode(header, format, limit)?;
+                    msg.data = data.to_vec();
+
+                    Ok(msg)
+                },
+                Some((layout, _)) => Err(Box::new(bincode::ErrorKind::Custom(format!("unknown store message layout {}", layout)))),
+                None => Err(cut_short()),
+            }
         }
 
-        pub fn to_buffer(&self) -> Vec<u8> {
-            bincode::serialize(&self).unwrap()
+        /// serialize the message, sending the data raw if it is larger than `RAW_DATA_THRESHOLD`
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            if self.data.len() <= RAW_DATA_THRESHOLD {
+                return Ok([vec![DATA_EMBEDDED], encode(self, format)?].concat());
+            }
+
+            let header = encode(&MessageData {
+                key: self.key.clone(),
+                data: Vec::new(),
+                meta: self.meta.clone(),
+                gossip: self.gossip.clone(),
+                ack_id: self.ack_id,
+            }, format)?;
+            let mut buf = Vec::with_capacity(5 + header.len() + self.data.len());
+            buf.push(DATA_RAW);
+            buf.extend_from_slice(&(header.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&header);
+            buf.extend_from_slice(&self.data);
+
+            Ok(buf)
         }
     }
 
-    impl<T: Transport> FileServer<T> {
-        pub fn new(opts: FileServerOpts<T>) -> Arc<FileServer<T>> {
-            let store_opts = opts.store_opts;
-            let transport = opts.transport;
-            let store = Store::new(store_opts);
-            let shutdown_chan_ = std::sync::mpsc::channel();
+    /// request for `len` bytes of the file stored under `key`, starting from `offset`. the reply carries the same
+    /// `request_id`, so that it reaches the `get_range` call that sent the request
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageGetRange {
+        request_id: RequestId,
+        key: String,
+        offset: u64,
+        len: u64,
+    }
 
-            let server = Arc::new(FileServer {
-                transport,
-                store,
-                shutdown_chan: (Mutex::new(shutdown_chan_.0), Mutex::new(shutdown_chan_.1)),
-                bootstrap_node: opts.bootstrap_node,
-                peers: RwLock::new(HashMap::new())
-            });
+    impl MessageGetRange {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageGetRange> {
+            decode(buf, format, limit)
+        }
 
-            server.register_on_peer_cb();
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+    }
+
+    /// the range of the file stored under `key` asked by `MessageGetRange`, tagged with where it starts in the file
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageRangeData {
+        request_id: RequestId,
+        key: String,
+        offset: u64,
+        /// None if the peer doesn't hold the file
+        data: Option<Vec<u8>>,
+    }
 
-            server
+    impl MessageRangeData {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageRangeData> {
+            decode(buf, format, limit)
         }
 
-        /// a blocking function to start the server
-        pub fn start(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
-            // start the transport layer and listen for incoming connections
-            let _ = self.transport.clone().listen_and_accept();
-            self.logger(format!("server running on {}", self.transport.clone().addr()));
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+    }
 
-            self.bootstrap_network();
+    /// request for the file stored under `key`. the reply carries the same `request_id`, so that it reaches the
+    /// `get_data` call that sent the request
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageGet {
+        request_id: RequestId,
+        key: String,
+    }
 
-            self.run()
+    impl MessageGet {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageGet> {
+            decode(buf, format, limit)
         }
 
-        pub fn run(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
-            loop {
-                // break the loop if we receive a shutdown message
-                if let Ok(true) = self.shutdown_chan.1.lock().unwrap().try_recv() {
-                    self.transport.clone().close()?;
-                    break;
-                }
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+    }
 
-                match self.transport.clone().consume() {
-                    Ok(msg) => self.handle_message(&msg),
-                    Err(e) => {
-                        // handle error
-                        match e {
-                            RecvTimeoutError::Timeout => continue,
-                            RecvTimeoutError::Disconnected => break,
-                        }
-                    }
-                };
-            }
+    /// the bytes of the file requested by the `MessageGet` with the same `request_id` starting at `offset`
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageGetResponse {
+        request_id: RequestId,
+        key: String,
+        offset: u64,
+        data: Vec<u8>,
+        /// whether this is the end of the file
+        is_last: bool,
+        /// stored along with the file by the receiver, if any. only sent with the last piece
+        meta: Option<Metadata>,
+    }
 
-            Ok(())
+    impl MessageGetResponse {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageGetResponse> {
+            decode(buf, format, limit)
         }
 
-        pub fn shutdown(self: Arc<Self>) {
-            self.shutdown_chan.0.lock().unwrap().send(true).unwrap();
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
         }
+    }
 
-        /// read from a stream and store in the store  
-        /// will also broadcast the data to all connected peers
-        pub fn store_data(self: &Arc<Self>, key: String, r: &mut dyn io::Read) {
-            let mut buf = vec![0; 1024];
-            let n = r.read(&mut buf).unwrap();
-            self.logger(format!("read {} bytes", n));
-            buf = buf[..n].to_vec();
-            // questionable design choice: we are reading the stream twice
-            match self.store.write(key.clone(), &mut buf) {
-                Ok(_) => {
-                    let payload = Payload {
-                        from: self.transport.clone().addr(),
-                        msg_type: MessageType::Store,
-                        msg: MessageData {
-                            key: key.clone(),
-                            data: buf,
-                        }.to_buffer(),
-                    };
-                    self.broadcast(payload);
-                },
-                Err(e) => {
-                    self.logger(format!("Error writing to store: {}", e));
-                }
-            }
+    /// the receiver of a store message stored the file
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageStoreAck {
+        ack_id: RequestId,
+    }
+
+    impl MessageStoreAck {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageStoreAck> {
+            decode(buf, format, limit)
         }
 
-        /// bootstrap the network by connecting to the bootstrap nodes
-        /// each dial will be done in a separate thread
-        fn bootstrap_network(self: &Arc<Self>) {
-            let nodes = self.bootstrap_node.clone();
-            // lesson for future me: iter() does not work here as we need to pass the node to the thread
-            // this causes a lifetime issue
-            // or maybe i am just not good enough to figure it out
-            let len = nodes.len();
-            for i in 0..len {
-                let node = nodes[i].clone();
-                let t = self.transport.clone();
-                thread::spawn(move || {
-                    t.dial(node).unwrap();
-                });
-            }
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
         }
+    }
 
-        fn register_on_peer_cb(self: &Arc<Self>) {
-            // callback fn when a new peer is connected
-            let cb = {
-                let cloned_self = self.clone();
-                move |peer: Arc<RwLock<T::Peer>>| {
-                    let p = peer.read().unwrap();
-                    cloned_self.logger(format!("{} on_peer: {}", if p.is_outbound() { "outbound" } else { "inbound" },  p.addr()));
-                    cloned_self.peers.write().unwrap().insert(p.addr(), peer.clone());
+    /// a ping or its pong. the nonce matches the pong to the ping it answers
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessagePing {
+        nonce: u64,
+    }
 
-                    true
-                }
-            };
+    impl MessagePing {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessagePing> {
+            decode(buf, format, limit)
+        }
 
-            self.transport.clone().register_on_peer(Box::new(cb));
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
         }
+    }
 
-        /// broadcast the payload to all connected peers  
-        fn broadcast(self: &Arc<Self>, payload: Payload) {
-            self.logger(format!("Broadcasting data: {:?}", payload));
-            let payload_buffer = payload.to_buffer();
-            let peers = self.peers.read().unwrap();
-            for (_, peer) in peers.iter() {
-                let mut p = peer.write().unwrap();
-                p.send(&payload_buffer).unwrap();
-            }
+    /// a piece of the file stored under `key`, starting at `offset`. the chunks of a file may arrive in any order
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageStoreChunk {
+        key: String,
+        /// the `hashlib::get_file_hash` and the size of the whole file, so that the chunks of another version of
+        /// the file, e.g. left by an interrupted transfer, are never mixed in
+        hash: String,
+        size: u64,
+        offset: u64,
+        data: Vec<u8>,
+        /// the chunk ending the file. tells the receiver how large the file is
+        is_last: bool,
+    }
+
+    impl MessageStoreChunk {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageStoreChunk> {
+            decode(buf, format, limit)
         }
 
-        /// handle the message received from the transport layer
-        /// will call the right function based on the message type
-        fn handle_message(self: &Arc<Self>, msg: &Message) {
-            let payload = Payload::from_buffer(msg.payload.clone());
-            match payload.msg_type {
-                MessageType::Store => self.handle_store_message(msg.from, &payload),
-            }
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
         }
-        
-        /// handle the store message
-        fn handle_store_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
-            let peer = match self.peers.read().unwrap().get(&from) {
-                Some(p) => p,
-                None => {
-                    self.logger(format!("Peer {} not found", from));
-                    return;
-                }
-            };
-            let msg_data = MessageData::from_buffer(&payload.msg);
-            self.logger(format!("Received data from {}: {} -> {}", from, msg_data.key, String::from_utf8_lossy(&msg_data.data)));
-            self.store.write(msg_data.key, &mut msg_data.data.as_slice()).unwrap();
+    }
+
+    /// ask for the resume point of the chunks of the file stored under `key`. only the chunks of the same
+    /// version of the file, with the same hash and size, count
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageStoreResume {
+        key: String,
+        hash: String,
+        size: u64,
+    }
+
+    impl MessageStoreResume {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageStoreResume> {
+            decode(buf, format, limit)
         }
 
-        fn logger(&self, msg: String) {
-            println!("[server {}] {}", self.transport.clone().addr() , msg);
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+    }
+
+    /// every byte of the file stored under `key` before `offset` has been received
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageResumePoint {
+        key: String,
+        offset: u64,
+    }
+
+    impl MessageResumePoint {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageResumePoint> {
+            decode(buf, format, limit)
+        }
+
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
+        }
+    }
+
+    /// the file stored under `key` is gone and the copies should be deleted too
+    #[derive(Serialize, Deserialize, Debug)]
+    struct MessageDelete {
+        key: String,
+    }
+
+    impl MessageDelete {
+        pub fn from_buffer(buf: &[u8], format: WireFormat, limit: u64) -> bincode::Result<MessageDelete> {
+            decode(buf, format, limit)
+        }
+
+        pub fn to_buffer(&self, format: WireFormat) -> bincode::Result<Vec<u8>> {
+            encode(self, format)
         }
     }
-}
\ No newline at end of file
+
+    /// the current time in seconds since the unix epoch, as in `Metadata`
+    fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+    }
+
+    /// whether a bootstrap host is among the peers. it may resolve to other addresses than when it was dialed
+    fn host_connected(host: &str, peers: &[SocketAddr]) -> bool {
+        match host.to_socket_addrs() {
+            Ok(mut addrs) => addrs.any(|addr| peers.contains(&addr)),
+            Err(_) => false,
+        }
+    }
+
+    /// the temporary file a transfer in chunks is put together in. the peer is part of the name so that transfers
+    /// of the same key from different peers don't mix
+    fn chunk_temp_name(from: SocketAddr, key: &str) -> String {
+        format!("{}.{}.tmp", key, from)
+    }
+
+    /// the chunks of a file received so far from one peer
+    #[derive(Default)]
+    struct ChunkBuffer {
+        /// the hash and the size of the file the chunks belong to, see `MessageStoreChunk`
+        version: (String, u64),
+        /// offset -> length of each chunk written to the temporary file
+        ranges: BTreeMap<u64, u64>,
+        /// the size of the file, known once the last chunk has arrived
+        size: Option<u64>,
+        /// when the latest chunk was added, None before the first one
+        last_chunk: Option<Instant>,
+    }
+
+    impl ChunkBuffer {
+        fn new(hash: String, size: u64) -> ChunkBuffer {
+            ChunkBuffer { version: (hash, size), ..ChunkBuffer::default() }
+        }
+
+        /// record a chunk, failing if it overlaps another one or lies past the end of the file
+        fn add(&mut self, offset: u64, len: u64, is_last: bool) -> Result<(), String> {
+            let end = offset.checked_add(len).ok_or(format!("chunk at offset {} ends past the largest file size", offset))?;
+            if self.ranges.contains_key(&offset) {
+                return Err(format!("duplicate chunk at offset {}", offset));
+            }
+            if let Some((prev_offset, prev_len)) = self.ranges.range(..offset).next_back() {
+                if prev_offset + prev_len > offset {
+                    return Err(format!("chunk at offset {} overlaps the one at {}", offset, prev_offset));
+                }
+            }
+            if let Some((next_offset, _)) = self.ranges.range(offset..).next() {
+                if end > *next_offset {
+                    return Err(format!("chunk at offset {} overlaps the one at {}", offset, next_offset));
+                }
+            }
+            match (self.size, is_last) {
+                (Some(_), true) => return Err(format!("second last chunk at offset {}", offset)),
+                (Some(size), false) if end > size => return Err(format!("chunk at offset {} is past the end of the file", offset)),
+                (None, true) if self.ranges.range(end..).next().is_some() => {
+                    return Err(format!("last chunk at offset {} is followed by other chunks", offset));
+                },
+                (None, true) => self.size = Some(end),
+                _ => {},
+            }
+            self.ranges.insert(offset, len);
+            self.last_chunk = Some(Instant::now());
+
+            Ok(())
+        }
+
+        /// fail if the chunk doesn't fit the size of the file, or starts too far ahead, see `CHUNK_WINDOW`
+        fn check_bounds(&self, offset: u64, len: u64, is_last: bool) -> Result<(), String> {
+            let size = self.version.1;
+            match offset.checked_add(len) {
+                Some(end) if end > size => Err(format!("chunk at offset {} ends past the {} bytes of the file", offset, size)),
+                Some(end) if is_last && end != size => Err(format!("last chunk at offset {} ends before the {} bytes of the file", offset, size)),
+                Some(_) if offset > self.received_end().saturating_add(CHUNK_WINDOW) => {
+                    Err(format!("chunk at offset {} is too far past the {} bytes received", offset, self.received_end()))
+                },
+                Some(_) => Ok(()),
+                None => Err(format!("chunk at offset {} ends past the largest file size", offset)),
+            }
+        }
+
+        /// whether no chunk has been added for `CHUNK_TRANSFER_TIMEOUT`
+        fn is_stale(&self) -> bool {
+            self.last_chunk.is_none_or(|at| at.elapsed() >= CHUNK_TRANSFER_TIMEOUT)
+        }
+
+        /// the byte ranges not received yet, as (start, end). the end of the file is unknown until the last chunk arrives
+        fn gaps(&self) -> Vec<(u64, u64)> {
+            let mut gaps = Vec::new();
+            let mut expected = 0;
+            for (offset, len) in self.ranges.iter() {
+                if *offset > expected {
+                    gaps.push((expected, *offset));
+                }
+                expected = offset + len;
+            }
+            if let Some(size) = self.size {
+                if expected < size {
+                    gaps.push((expected, size));
+                }
+            }
+
+            gaps
+        }
+
+        /// the end of the bytes received without a gap from the start of the file
+        fn received_end(&self) -> u64 {
+            let mut end = 0;
+            for (offset, len) in self.ranges.iter() {
+                if *offset > end {
+                    break;
+                }
+                end = offset + len;
+            }
+
+            end
+        }
+
+        fn is_complete(&self) -> bool {
+            self.size.is_some() && self.gaps().is_empty()
+        }
+
+        /// the end of the bytes received without a gap from the start of the file, where the sender should resume.
+        /// the chunks past it are forgotten, so that sending them again doesn't look like an overlap
+        fn resume_point(&mut self) -> u64 {
+            let end = self.received_end();
+            self.ranges.retain(|offset, _| *offset < end);
+            if self.size.is_some_and(|size| size > end) {
+                self.size = None;
+            }
+
+            end
+        }
+    }
+
+    impl<T: Transport> FileServer<T> {
+        /// fail if the store can't be opened, e.g. when another node is already using its root directory
+        pub fn new(opts: FileServerOpts<T>) -> Result<Arc<FileServer<T>>, StoreError> {
+            let store_opts = opts.store_opts;
+            let transport = opts.transport;
+            let max_message_size = transport.clone().max_payload_size() as u64;
+            let store = Store::new(store_opts)?;
+            let shutdown_chan_ = std::sync::mpsc::channel();
+
+            let server = Arc::new(FileServer {
+                transport,
+                store,
+                shutdown_chan: (Mutex::new(shutdown_chan_.0), Mutex::new(shutdown_chan_.1)),
+                bootstrap_node: opts.bootstrap_node,
+                bootstrap_hosts: opts.bootstrap_hosts,
+                consume_retry: opts.consume_retry,
+                reconnect_interval: opts.reconnect_interval,
+                wire_format: opts.wire_format,
+                max_message_size,
+                sweep_interval: opts.sweep_interval,
+                close_timeout: opts.close_timeout,
+                drain_on_shutdown: opts.drain_on_shutdown,
+                admin_addr: opts.admin_addr,
+                admin_local_addr: Mutex::new(None),
+                gossip_hops: opts.gossip_hops,
+                bootstrap_timeout: opts.bootstrap_timeout,
+                consistency: opts.consistency,
+                blocking_consume: opts.blocking_consume,
+                store_locally: opts.store_locally,
+                consume_batch: opts.consume_batch,
+                max_get_size: opts.max_get_size,
+                stopping: AtomicBool::new(false),
+                peers: RwLock::new(HashMap::new()),
+                connected_at: Mutex::new(HashMap::new()),
+                ring: RwLock::new(HashRing::new(DEFAULT_VNODES)),
+                chunk_buffers: Mutex::new(HashMap::new()),
+                pending_resumes: Mutex::new(HashMap::new()),
+                pending_gets: Mutex::new(HashMap::new()),
+                pending_ranges: Mutex::new(HashMap::new()),
+                pending_acks: Mutex::new(HashMap::new()),
+                next_request_id: AtomicU64::new(0),
+                pending_pings: Mutex::new(HashMap::new()),
+                next_ping_nonce: AtomicU64::new(0),
+                latencies: Mutex::new(HashMap::new()),
+                last_seen: Mutex::new(HashMap::new()),
+                expiries: Mutex::new(HashMap::new()),
+                seen_gossip: Mutex::new(SeenGossip::default()),
+                next_gossip_id: AtomicU64::new(0),
+                departed: Mutex::new(HashSet::new()),
+                subscribers: Mutex::new(Vec::new()),
+            });
+
+            if let Ok(addr) = server.transport.clone().local_addr() {
+                server.ring.write().unwrap().add_weighted(&addr.to_string(), server.transport.clone().weight());
+            }
+            server.register_on_peer_cb();
+            server.load_expiries();
+
+            Ok(server)
+        }
+
+        /// a blocking function to start the server
+        pub fn start(self: Arc<Self>) -> Result<(), DfsError> {
+            // start the transport layer and listen for incoming connections
+            self.transport.clone().listen_and_accept()?;
+            self.logger(format!("server running on {}", self.transport.clone().addr()));
+            self.serve_admin()?;
+
+            match self.bootstrap_timeout {
+                Some(timeout) => {
+                    let connected = self.bootstrap_network_blocking(timeout);
+                    self.logger(format!("connected to {} of {} bootstrap nodes", connected.len(), self.bootstrap_node.len() + self.bootstrap_hosts.len()));
+                },
+                None => self.bootstrap_network(),
+            }
+            self.supervise_bootstrap_nodes();
+            self.sweep_expired_periodically();
+
+            self.run()
+        }
+
+        /// like `start`, running the server on its own thread instead of blocking
+        pub fn start_background(self: Arc<Self>) -> ServerHandle<T> {
+            let server = self.clone();
+            let thread = thread::spawn(move || server.start());
+
+            ServerHandle { server: self, thread }
+        }
+
+        /// handle the messages from the transport until `shutdown` is called.  
+        /// fail if the message channel stays disconnected, see `ConsumeRetryPolicy`
+        pub fn run(self: &Arc<Self>) -> Result<(), DfsError> {
+            let mut disconnects = 0;
+            loop {
+                // break the loop if we receive a shutdown message
+                if let Ok(true) = self.shutdown_chan.1.lock().unwrap().try_recv() {
+                    if self.drain_on_shutdown {
+                        let msgs = self.transport.clone().drain();
+                        self.logger(format!("handling {} messages left before shutting down", msgs.len()));
+                        for msg in msgs {
+                            self.handle_message(&msg);
+                        }
+                    }
+                    self.close_transport()?;
+                    break;
+                }
+
+                let received = match self.blocking_consume {
+                    true => self.transport.clone().consume_blocking().map_err(|_| RecvTimeoutError::Disconnected),
+                    false => self.transport.clone().consume().map(Some),
+                };
+                match received {
+                    Ok(Some(msg)) => {
+                        disconnects = 0;
+                        self.handle_message(&msg);
+                        // the ones that came in meanwhile, without going back to waiting
+                        for msg in self.transport.clone().consume_batch(self.consume_batch.saturating_sub(1)) {
+                            self.handle_message(&msg);
+                        }
+                    },
+                    // the channel works again
+                    Ok(None) | Err(RecvTimeoutError::Timeout) => disconnects = 0,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if disconnects >= self.consume_retry.max_attempts {
+                            self.logger(format!("message channel still disconnected after {} retries, stopping", disconnects));
+                            return Err(DfsError::Transport(String::from("message channel disconnected")));
+                        }
+                        disconnects += 1;
+                        self.logger(format!("message channel disconnected, retrying ({}/{})", disconnects, self.consume_retry.max_attempts));
+                        thread::sleep(self.consume_retry.backoff);
+                        self.transport.clone().reattach();
+                    },
+                };
+            }
+
+            Ok(())
+        }
+
+        /// close the transport, giving up on it after `close_timeout` so that a close that never returns
+        /// can't keep `run` from stopping. the close then carries on in the background
+        fn close_transport(self: &Arc<Self>) -> Result<(), DfsError> {
+            let (sender, receiver) = channel();
+            let transport = self.transport.clone();
+            thread::spawn(move || {
+                // the error can't be sent across threads as it is
+                let _ = sender.send(transport.close().map_err(|e| e.to_string()));
+            });
+
+            match receiver.recv_timeout(self.close_timeout) {
+                Ok(res) => res.map_err(DfsError::Transport),
+                Err(_) => {
+                    self.logger(format!("Warning: the transport did not close within {:?}, stopping anyway", self.close_timeout));
+                    Ok(())
+                },
+            }
+        }
+
+        /// ask the server to stop. the peers are told first, so that they know it is not a failure
+        pub fn shutdown(self: Arc<Self>) {
+            // set first, so that the peers closing the connections on bye are not taken as failures
+            self.stopping.store(true, Ordering::SeqCst);
+            self.broadcast(Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::Bye,
+                msg: Vec::new(),
+            });
+            self.shutdown_chan.0.lock().unwrap().send(true).unwrap();
+            // `run` may be waiting for a message that never comes
+            self.transport.clone().wake();
+            // the admin listener waits for a connection to notice it
+            if let Some(mut addr) = self.admin_local_addr() {
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    });
+                }
+                let _ = TcpStream::connect(addr);
+            }
+        }
+
+        /// check that the node can serve requests: the transport takes connections and the store can be read
+        pub fn health(&self) -> Health {
+            Health {
+                listening: self.transport.clone().is_listening(),
+                peer_count: self.peers.read().unwrap().len(),
+                store_ok: self.store.stats().is_ok(),
+            }
+        }
+
+        /// answer the connections to `admin_addr` with the health of the node until `shutdown` is called
+        fn serve_admin(self: &Arc<Self>) -> Result<(), DfsError> {
+            let addr = match &self.admin_addr {
+                Some(addr) => addr,
+                None => return Ok(()),
+            };
+            let listener = TcpListener::bind(addr)?;
+            let local_addr = listener.local_addr()?;
+            *self.admin_local_addr.lock().unwrap() = Some(local_addr);
+            self.logger(format!("serving health on {}", local_addr));
+            let server = self.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    // the listener only notices the shutdown on the next connection, which `shutdown` makes
+                    if server.stopping.load(Ordering::SeqCst) {
+                        *server.admin_local_addr.lock().unwrap() = None;
+                        return;
+                    }
+                    let res = stream.and_then(|mut stream| {
+                        writeln!(stream, "{}", server.health())?;
+                        server.peer_info().iter().try_for_each(|info| writeln!(stream, "{}", info))
+                    });
+                    if let Err(e) = res {
+                        server.logger(format!("Error answering a health check: {}", e));
+                    }
+                }
+            });
+
+            Ok(())
+        }
+
+        /// return the address the health is served on, e.g. with the port picked by the OS when `admin_addr` is on
+        /// port 0. None before `start` serves it, once the server is shut down, or without an admin port
+        pub fn admin_local_addr(&self) -> Option<SocketAddr> {
+            *self.admin_local_addr.lock().unwrap()
+        }
+
+        /// return the addresses of the connected peers
+        pub fn peer_addrs(&self) -> Vec<SocketAddr> {
+            self.peers.read().unwrap().keys().copied().collect()
+        }
+
+        /// return a receiver getting a `StoreEvent` for every file this node stores or deletes from then on,
+        /// whether asked locally or by a peer, e.g. to keep an index of the files up to date.  
+        /// each call returns a receiver of its own. the events pile up in it until they are received
+        pub fn subscribe(&self) -> Receiver<StoreEvent> {
+            let (sender, receiver) = channel();
+            self.subscribers.lock().unwrap().push(sender);
+            receiver
+        }
+
+        /// send the event to every subscriber, forgetting the ones that dropped their receiver
+        fn notify(&self, event: StoreEvent) {
+            self.subscribers.lock().unwrap().retain(|sender| sender.send(event.clone()).is_ok());
+        }
+
+        /// return the connected peers with how they are connected and, for the ones that answered a ping, when they
+        /// last did and the round trip time to them. sorted by address
+        pub fn peer_info(&self) -> Vec<PeerInfo> {
+            let connected_at = self.connected_at.lock().unwrap();
+            let last_seen = self.last_seen.lock().unwrap();
+            let latencies = self.latencies.lock().unwrap();
+            let mut infos: Vec<PeerInfo> = self.peers.read().unwrap()
+                .iter()
+                .map(|(addr, peer)| PeerInfo {
+                    addr: *addr,
+                    outbound: peer.read().unwrap().is_outbound(),
+                    connected_at: connected_at.get(addr).copied().unwrap_or(0),
+                    last_seen: last_seen.get(addr).copied(),
+                    latency: latencies.get(addr).copied(),
+                })
+                .collect();
+            infos.sort_by_key(|info| info.addr);
+
+            infos
+        }
+
+        /// send a ping to the peer. the round trip time is recorded when the pong comes back within `PING_TIMEOUT`,
+        /// see `peer_latency`
+        pub fn ping(self: &Arc<Self>, addr: SocketAddr) -> Result<(), DfsError> {
+            let nonce = self.next_ping_nonce.fetch_add(1, Ordering::SeqCst);
+            let payload = Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::Ping,
+                msg: MessagePing { nonce }.to_buffer(self.wire_format)?,
+            };
+            {
+                let mut pending_pings = self.pending_pings.lock().unwrap();
+                // the pings a peer never answered are forgotten here, rather than kept for good
+                pending_pings.retain(|_, sent_at| sent_at.elapsed() < PING_TIMEOUT);
+                if pending_pings.len() >= MAX_PENDING_PINGS {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many pings in flight").into());
+                }
+                pending_pings.insert((addr, nonce), Instant::now());
+            }
+            if let Err(e) = self.send_to_peer(addr, payload) {
+                self.pending_pings.lock().unwrap().remove(&(addr, nonce));
+                return Err(e.into());
+            }
+
+            Ok(())
+        }
+
+        /// return the rolling average of the round trip time to the peer, if a ping to it has been answered
+        pub fn peer_latency(&self, addr: SocketAddr) -> Option<Duration> {
+            self.latencies.lock().unwrap().get(&addr).copied()
+        }
+
+        /// return the nodes, this one included, that `store_data` would store the key on, most preferred first  
+        /// the order is the one of the nodes following the key on the consistent hashing ring of the node addresses,
+        /// so it only depends on the key, the membership and the weights of the nodes. nothing is read or sent
+        pub fn placement_for(&self, key: &str) -> Vec<SocketAddr> {
+            let ring = self.ring.read().unwrap();
+            ring.successors(key, ring.len())
+                .iter()
+                .filter_map(|node| node.parse().ok())
+                .collect()
+        }
+
+        /// start talking to the peer, and placing keys on it in proportion to its weight
+        fn add_peer(&self, addr: SocketAddr, peer: Arc<RwLock<dyn PeerLike + Sync + Send>>) {
+            let weight = peer.read().unwrap().weight();
+            self.peers.write().unwrap().insert(addr, peer);
+            self.connected_at.lock().unwrap().insert(addr, unix_now());
+            self.ring.write().unwrap().add_weighted(&addr.to_string(), weight);
+        }
+
+        /// forget the peer, whose keys move to the next nodes on the ring
+        fn remove_peer(&self, addr: SocketAddr) {
+            self.peers.write().unwrap().remove(&addr);
+            self.connected_at.lock().unwrap().remove(&addr);
+            self.ring.write().unwrap().remove(&addr.to_string());
+        }
+
+        /// read from a stream and store in the store  
+        /// will also send the data to the other nodes picked by `placement_for`, which are all the connected peers
+        /// a peer failing to receive the data is logged rather than returned, as the data is stored locally anyway
+        pub fn store_data(self: &Arc<Self>, key: String, r: &mut dyn io::Read) -> Result<(), DfsError> {
+            self.store_and_replicate(key, r, None)
+        }
+
+        /// like `store_data`, reading the file from an async stream, e.g. for a tokio based service. the store
+        /// is blocking, so the file is stored on the blocking threads of the runtime. needs the `async` feature
+        #[cfg(feature = "async")]
+        pub async fn store_data_async<R: tokio::io::AsyncRead + Unpin>(self: &Arc<Self>, key: String, r: &mut R) -> Result<(), DfsError> {
+            let buf = rust_distributed_file::read_all_from_stream_async(r).await?;
+            let server = self.clone();
+            tokio::task::spawn_blocking(move || server.store_data(key, &mut buf.as_slice()))
+                .await
+                .map_err(io::Error::from)?
+        }
+
+        /// like `store_data`, also storing the metadata of the file here and on the other nodes
+        pub fn store_with_meta(self: &Arc<Self>, key: String, r: &mut dyn io::Read, meta: Metadata) -> Result<(), DfsError> {
+            self.store_and_replicate(key, r, Some(meta))
+        }
+
+        /// return the metadata of a file held locally
+        pub fn read_meta(&self, key: String) -> Result<Metadata, DfsError> {
+            Ok(self.store.read_meta(key)?)
+        }
+
+        /// like `store_data`, the file being deleted here and on the other nodes once `ttl` has passed.
+        /// the expiry is kept in the metadata, so it is only seen by the nodes receiving the file with it.  
+        /// the files are deleted by the sweep of `FileServerOpts::sweep_interval`, so they may outlive their ttl
+        /// by up to that long. None stores the file without expiry
+        pub fn store_with_ttl(self: &Arc<Self>, key: String, r: &mut dyn io::Read, ttl: Option<Duration>) -> Result<(), DfsError> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+            let meta = Metadata {
+                created_at: now.as_secs(),
+                // rounded up, so that the file never expires early
+                expires_at: ttl.map(|ttl| (now + ttl).as_secs_f64().ceil() as u64),
+                ..Metadata::default()
+            };
+            self.store_and_replicate(key, r, Some(meta))
+        }
+
+        fn store_and_replicate(self: &Arc<Self>, key: String, r: &mut dyn io::Read, meta: Option<Metadata>) -> Result<(), DfsError> {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            self.logger(format!("read {} bytes", buf.len()));
+            self.store_local(&key, &buf, meta.as_ref())?;
+            self.replicate(key, buf, meta)
+        }
+
+        /// keep the file stored through this node, unless it is a proxy, see `FileServerOpts::store_locally`
+        fn store_local(self: &Arc<Self>, key: &str, buf: &[u8], meta: Option<&Metadata>) -> Result<(), DfsError> {
+            if !self.store_locally {
+                return Ok(());
+            }
+            // questionable design choice: we are reading the stream twice
+            self.store.write(key.to_string(), buf)?;
+            if let Some(meta) = meta {
+                self.store.write_meta(key.to_string(), meta)?;
+            }
+            self.notify(StoreEvent::Stored(key.to_string(), buf.len() as u64));
+            self.track_expiry(key, meta);
+
+            Ok(())
+        }
+
+        /// read the stream, store it like `store_data` does and hand the store message to `send`, for the variants
+        /// of `store_data` sending the file to some of the peers only
+        fn store_and_send(self: &Arc<Self>, key: String, r: &mut dyn io::Read, send: impl FnOnce(Payload) -> Result<(), io::Error>) -> Result<(), DfsError> {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            self.logger(format!("read {} bytes", buf.len()));
+            self.store_local(&key, &buf, None)?;
+            send(self.store_payload(key, buf, None, None, None)?)?;
+
+            Ok(())
+        }
+
+        /// send a file held locally to the other nodes again, along with its metadata, e.g. to a node that was
+        /// offline when it was stored or lost its copy. wait for the replicas as `store_data` does, see `Consistency`.  
+        /// fail with `StoreError::NotFound` if the file is not held locally
+        pub fn republish(self: &Arc<Self>, key: String) -> Result<(), DfsError> {
+            let buf = self.store.read(key.clone()).map_err(|e| StoreError::from(io::Error::from(e)))?;
+            let meta = self.store.read_meta(key.clone()).ok();
+            self.logger(format!("republishing {} ({} bytes)", key, buf.len()));
+
+            self.replicate(key, buf, meta)
+        }
+
+        /// store each file under the local directory `dir` with `store_data`, e.g. to seed a new cluster with
+        /// existing data. a file is stored under its path relative to `dir`, with '/' between the directories,
+        /// so "dir/photos/cat.jpg" becomes "photos/cat.jpg".  
+        /// a file or a subdirectory that can't be read or stored is reported and skipped. return the result of
+        /// each file, by key. fail only if `dir` itself can't be read
+        pub fn store_dir(self: &Arc<Self>, dir: &Path) -> Result<ImportResults, DfsError> {
+            let mut results = Vec::new();
+            self.store_dir_entries(dir, "", &mut results)?;
+            let stored = results.iter().filter(|(_, res)| res.is_ok()).count();
+            self.logger(format!("stored {} of the {} files under {}", stored, results.len(), dir.display()));
+
+            Ok(results)
+        }
+
+        /// store the files under `dir`, whose keys start with `prefix`, in the order of their names
+        fn store_dir_entries(self: &Arc<Self>, dir: &Path, prefix: &str, results: &mut ImportResults) -> Result<(), io::Error> {
+            let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                let key = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                let path = entry.path();
+                let res = match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => {
+                        match self.store_dir_entries(&path, &format!("{}/", key), results) {
+                            Ok(_) => continue,
+                            Err(e) => Err(e.into()),
+                        }
+                    },
+                    Ok(_) => fs::File::open(&path)
+                        .map_err(DfsError::from)
+                        .and_then(|mut file| self.store_data(key.clone(), &mut file)),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = &res {
+                    self.logger(format!("Error storing {}: {}", path.display(), e));
+                }
+                results.push((key, res));
+            }
+
+            Ok(())
+        }
+
+        /// send the file to the other nodes it is placed on
+        fn replicate(self: &Arc<Self>, key: String, buf: Vec<u8>, meta: Option<Metadata>) -> Result<(), DfsError> {
+            let self_addr = self.transport.clone().addr();
+            let self_local_addr = self.transport.clone().local_addr().ok();
+            let nodes: Vec<SocketAddr> = self.placement_for(&key)
+                .into_iter()
+                .filter(|node| node.to_string() != self_addr && Some(*node) != self_local_addr)
+                .collect();
+            let gossip = match self.gossip_hops {
+                0 => None,
+                hops_left => {
+                    let id = self.next_gossip_id.fetch_add(1, Ordering::SeqCst);
+                    // the file may come back around a loop of nodes
+                    self.seen_gossip.lock().unwrap().insert(&self_addr, id);
+                    Some(Gossip { origin: self_addr.clone(), id, hops_left })
+                }
+            };
+            let acks = match self.consistency {
+                Consistency::QuorumAck if !nodes.is_empty() => {
+                    let ack_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+                    let (sender, receiver) = channel();
+                    self.pending_acks.lock().unwrap().insert(ack_id, sender);
+                    Some((ack_id, receiver))
+                },
+                _ => None,
+            };
+            let payload = self.store_payload(key.clone(), buf, meta, gossip, acks.as_ref().map(|(ack_id, _)| *ack_id))?;
+            self.send_to_nodes(&nodes, payload);
+
+            match acks {
+                Some((ack_id, receiver)) => {
+                    let res = self.wait_for_quorum(&key, &nodes, receiver);
+                    self.pending_acks.lock().unwrap().remove(&ack_id);
+                    res
+                },
+                None => Ok(()),
+            }
+        }
+
+        /// the store message sending the file to the other nodes
+        fn store_payload(&self, key: String, data: Vec<u8>, meta: Option<Metadata>, gossip: Option<Gossip>, ack_id: Option<RequestId>) -> Result<Payload, DfsError> {
+            Ok(Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::Store,
+                msg: MessageData { key, data, meta, gossip, ack_id }.to_buffer(self.wire_format)?,
+            })
+        }
+
+        /// the frame the next `store_data` of the file would hand to the transport for each of the other nodes,
+        /// e.g. to inspect or diff the wire format. nothing is sent or stored, and no gossip or ack id is used up.  
+        /// the transport then adds its own framing, see `TcpTransportOpts::encoder` and `TcpTransportOpts::codecs`
+        pub fn debug_encode_store(&self, key: String, data: &[u8]) -> Result<Vec<u8>, DfsError> {
+            let gossip = match self.gossip_hops {
+                0 => None,
+                hops_left => Some(Gossip {
+                    origin: self.transport.clone().addr(),
+                    id: self.next_gossip_id.load(Ordering::SeqCst),
+                    hops_left,
+                }),
+            };
+            let ack_id = match self.consistency {
+                Consistency::QuorumAck => Some(self.next_request_id.load(Ordering::SeqCst)),
+                Consistency::Local => None,
+            };
+
+            Ok(self.store_payload(key, data.to_vec(), None, gossip, ack_id)?.to_frame(self.wire_format)?)
+        }
+
+        /// wait for a majority of the replicas of `key` to ack it, this node and `nodes`. this node already holds it,
+        /// unless it is a proxy, see `FileServerOpts::store_locally`
+        fn wait_for_quorum(&self, key: &str, nodes: &[SocketAddr], receiver: Receiver<SocketAddr>) -> Result<(), DfsError> {
+            // a majority of the replicas, less this node
+            let held_here = self.store_locally as usize;
+            let needed = (nodes.len() + held_here) / 2 + 1 - held_here;
+            let deadline = Instant::now() + ACK_TIMEOUT;
+            let mut acked = HashSet::new();
+            while acked.len() < needed {
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(addr) => {
+                        if nodes.contains(&addr) {
+                            acked.insert(addr);
+                        }
+                    },
+                    Err(e) => {
+                        self.logger(format!("{} acked by {} of the {} replicas needed", key, acked.len(), needed));
+                        return Err(e.into());
+                    },
+                }
+            }
+
+            Ok(())
+        }
+
+        /// like `store_data`, sending the data to the other nodes in chunks of at most `chunk_size` bytes
+        /// rather than in a single message.  
+        /// each node is first asked how much of the file it already has from an interrupted transfer, and only
+        /// gets the rest. a node not answering within `RESUME_TIMEOUT` gets the whole file
+        pub fn store_in_chunks(self: &Arc<Self>, key: String, r: &mut dyn io::Read, chunk_size: usize) -> Result<(), DfsError> {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf)?;
+            self.store_local(&key, &buf, None)?;
+            let hash = hashlib::get_file_hash(&buf);
+            let self_addr = self.transport.clone().addr();
+            let nodes: Vec<SocketAddr> = self.placement_for(&key)
+                .into_iter()
+                .filter(|node| node.to_string() != self_addr)
+                .collect();
+
+            // ask all the nodes before waiting for any of them
+            let mut resumes = Vec::new();
+            for node in nodes {
+                let (sender, receiver) = channel();
+                self.pending_resumes.lock().unwrap().insert((node, key.clone()), sender);
+                let payload = Payload {
+                    from: self_addr.clone(),
+                    msg_type: MessageType::StoreResume,
+                    msg: MessageStoreResume { key: key.clone(), hash: hash.clone(), size: buf.len() as u64 }.to_buffer(self.wire_format)?,
+                };
+                if let Err(e) = self.send_to_peer(node, payload) {
+                    self.logger(format!("Error sending to {}: {}", node, e));
+                }
+                resumes.push((node, receiver));
+            }
+            for (node, receiver) in resumes {
+                let offset = receiver.recv_timeout(RESUME_TIMEOUT).unwrap_or(0);
+                self.pending_resumes.lock().unwrap().remove(&(node, key.clone()));
+                // a resume point past the end comes from another version of the file
+                let offset = match offset as usize <= buf.len() {
+                    true => offset as usize,
+                    false => 0,
+                };
+                if offset > 0 {
+                    self.logger(format!("{} already has {} bytes of {}, resuming", node, offset, key));
+                }
+                self.send_chunks(node, &key, &buf, &hash, offset, chunk_size)?;
+            }
+
+            Ok(())
+        }
+
+        /// send `buf[offset..]` to the node in chunks of at most `chunk_size` bytes. `hash` is the hash of `buf`
+        fn send_chunks(self: &Arc<Self>, node: SocketAddr, key: &str, buf: &[u8], hash: &str, offset: usize, chunk_size: usize) -> Result<(), DfsError> {
+            // an empty rest is still sent as a single empty chunk, which tells the size of the file
+            let rest = &buf[offset..];
+            let chunks: Vec<&[u8]> = match rest.is_empty() {
+                true => vec![&[]],
+                false => rest.chunks(chunk_size.max(1)).collect(),
+            };
+            let mut offset = offset as u64;
+            for (i, chunk) in chunks.iter().enumerate() {
+                let msg = MessageStoreChunk {
+                    key: key.to_string(),
+                    hash: hash.to_string(),
+                    size: buf.len() as u64,
+                    offset,
+                    data: chunk.to_vec(),
+                    is_last: i == chunks.len() - 1,
+                };
+                let payload = Payload {
+                    from: self.transport.clone().addr(),
+                    msg_type: MessageType::StoreChunk,
+                    msg: msg.to_buffer(self.wire_format)?,
+                };
+                if let Err(e) = self.send_to_peer(node, payload) {
+                    self.logger(format!("Error sending to {}: {}", node, e));
+                    break;
+                }
+                offset += chunk.len() as u64;
+            }
+
+            Ok(())
+        }
+
+        /// read from a stream and store in the store  
+        /// unlike `store_data`, the data is only sent to the peer at `addr` instead of all connected peers.
+        /// fail without storing anything if the peer is not connected
+        pub fn store_to(self: &Arc<Self>, key: String, r: &mut dyn io::Read, addr: SocketAddr) -> Result<(), DfsError> {
+            if !self.peers.read().unwrap().contains_key(&addr) {
+                return Err(DfsError::Io(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is not connected", addr))));
+            }
+            self.store_and_send(key, r, |payload| self.send_to_peer(addr, payload))
+        }
+
+        /// read from a stream and store in the store  
+        /// unlike `store_data`, the data is only sent to the peers that advertised `tag`, e.g. to keep a copy on the
+        /// cold storage nodes only. see `TcpTransportOpts::tags`
+        pub fn store_to_tag(self: &Arc<Self>, key: String, r: &mut dyn io::Read, tag: &str) -> Result<(), DfsError> {
+            self.store_and_send(key, r, |payload| {
+                self.broadcast_to_tag(payload, tag);
+                Ok(())
+            })
+        }
+
+        /// read from a stream and store in the store  
+        /// unlike `store_data`, the data is only sent to the peers this node dialed if `outbound`, or only to the ones
+        /// that dialed it otherwise, e.g. to pass a file along a gossip mesh without sending it back where it came from
+        pub fn store_to_peers(self: &Arc<Self>, key: String, r: &mut dyn io::Read, outbound: bool) -> Result<(), DfsError> {
+            self.store_and_send(key, r, |payload| {
+                match outbound {
+                    true => self.broadcast_outbound(payload),
+                    false => self.broadcast_inbound(payload),
+                }
+                Ok(())
+            })
+        }
+
+        /// return the addresses of the connected peers that advertised the tag
+        pub fn peers_with_tag(&self, tag: &str) -> Vec<SocketAddr> {
+            self.peers.read().unwrap()
+                .iter()
+                .filter(|(_, peer)| peer.read().unwrap().tags().iter().any(|t| t == tag))
+                .map(|(addr, _)| *addr)
+                .collect()
+        }
+
+        /// return the file stored under `key`  
+        /// if it is not held locally, the peers are asked for it one at a time in the order of `placement_for`,
+        /// moving on to the next one when a peer doesn't hold the file or doesn't answer within `GET_TIMEOUT`.
+        /// the first copy received is stored locally, unless this node is a proxy (see `FileServerOpts::store_locally`),
+        /// which then asks the peers on every call. the server must be running to receive the replies
+        pub fn get_data(self: &Arc<Self>, key: String) -> Result<Vec<u8>, DfsError> {
+            if let Ok(data) = self.store.read(key.clone()) {
+                return Ok(data);
+            }
+            self.logger(format!("{} not found locally, fetching from peers", key));
+
+            let self_addr = self.transport.clone().local_addr().ok();
+            let mut timed_out = false;
+            for peer in self.placement_for(&key).into_iter().filter(|node| Some(*node) != self_addr) {
+                // a new id for each peer, so that a late reply from the previous one is ignored
+                let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+                let (sender, receiver) = channel();
+                {
+                    let mut pending_gets = self.pending_gets.lock().unwrap();
+                    if pending_gets.len() >= MAX_PENDING_GETS {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many gets in flight").into());
+                    }
+                    pending_gets.insert(request_id, PendingGet { peer, received: Vec::new(), sender });
+                }
+                let payload = Payload {
+                    from: self.transport.clone().addr(),
+                    msg_type: MessageType::Get,
+                    msg: MessageGet { request_id, key: key.clone() }.to_buffer(self.wire_format)?,
+                };
+                let res = match self.send_to_peer(peer, payload) {
+                    Ok(_) => receiver.recv_timeout(GET_TIMEOUT),
+                    Err(e) => {
+                        self.logger(format!("Error sending to {}: {}", peer, e));
+                        Ok(None)
+                    }
+                };
+                self.pending_gets.lock().unwrap().remove(&request_id);
+                match res {
+                    Ok(Some(data)) => {
+                        if self.store_locally {
+                            self.store.write(key.clone(), &data)?;
+                            self.notify(StoreEvent::Stored(key, data.len() as u64));
+                        }
+                        return Ok(data);
+                    },
+                    Ok(None) => self.logger(format!("{} could not send {}, trying the next replica", peer, key)),
+                    Err(_) => {
+                        self.logger(format!("no reply from {} for {}, trying the next replica", peer, key));
+                        timed_out = true;
+                    },
+                }
+            }
+
+            self.logger(format!("no peer sent {}", key));
+            if timed_out {
+                return Err(RecvTimeoutError::Timeout.into());
+            }
+            Err(StoreError::NotFound.into())
+        }
+
+        /// ask the replicas for `len` bytes of the file stored under `key`, starting from `offset`, and return them.
+        /// useful for fetching the missing tail of a partial copy instead of the whole file. the range is written
+        /// into the local copy at the same offset, unless this node is a proxy, see `FileServerOpts::store_locally`.
+        /// the range is asked for in pieces of at most `RANGE_PIECE_SIZE` bytes, so that each reply fits in a message,
+        /// and is cut short at the end of the file. each piece is asked from the nodes of `placement_for` one at a time,
+        /// as in `get_data`. fail with `StoreError::NotFound` if none of them holds the file.
+        /// the server must be running to receive the replies
+        pub fn get_range(self: &Arc<Self>, key: String, offset: u64, len: u64) -> Result<Vec<u8>, DfsError> {
+            let mut data = Vec::new();
+            loop {
+                let piece_len = (len - data.len() as u64).min(RANGE_PIECE_SIZE);
+                let piece = self.get_range_piece(&key, offset + data.len() as u64, piece_len)?;
+                let end_of_file = (piece.len() as u64) < piece_len;
+                data.extend(piece);
+                if end_of_file || data.len() as u64 >= len {
+                    break;
+                }
+            }
+            if self.store_locally {
+                self.store.write_at(key, offset, &data)?;
+            }
+
+            Ok(data)
+        }
+
+        /// ask the replicas of the key for a single piece of `get_range`, moving on to the next one when a replica
+        /// doesn't hold the file or doesn't answer within `GET_TIMEOUT`
+        fn get_range_piece(self: &Arc<Self>, key: &str, offset: u64, len: u64) -> Result<Vec<u8>, DfsError> {
+            let self_addr = self.transport.clone().local_addr().ok();
+            let peers: Vec<SocketAddr> = self.placement_for(key).into_iter().filter(|node| Some(*node) != self_addr).collect();
+            if peers.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, format!("no peer to ask for {}", key)).into());
+            }
+
+            let mut timed_out = false;
+            for peer in peers {
+                // a new id for each peer, so that a late reply from the previous one is ignored
+                let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+                let (sender, receiver) = channel();
+                {
+                    let mut pending_ranges = self.pending_ranges.lock().unwrap();
+                    if pending_ranges.len() >= MAX_PENDING_GETS {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, "too many ranges in flight").into());
+                    }
+                    pending_ranges.insert(request_id, PendingRange { peer, key: key.to_string(), offset, sender });
+                }
+                let payload = Payload {
+                    from: self.transport.clone().addr(),
+                    msg_type: MessageType::GetRange,
+                    msg: MessageGetRange { request_id, key: key.to_string(), offset, len }.to_buffer(self.wire_format)?,
+                };
+                let res = match self.send_to_peer(peer, payload) {
+                    Ok(_) => receiver.recv_timeout(GET_TIMEOUT),
+                    Err(e) => {
+                        self.logger(format!("Error sending to {}: {}", peer, e));
+                        Ok(None)
+                    }
+                };
+                self.pending_ranges.lock().unwrap().remove(&request_id);
+                match res {
+                    Ok(Some(data)) => {
+                        self.logger(format!("Received {} bytes of {} from offset {} from {}", data.len(), key, offset, peer));
+                        return Ok(data);
+                    },
+                    Ok(None) => self.logger(format!("{} could not send a range of {}, trying the next replica", peer, key)),
+                    Err(_) => {
+                        self.logger(format!("no reply from {} for a range of {}, trying the next replica", peer, key));
+                        timed_out = true;
+                    },
+                }
+            }
+
+            if timed_out {
+                return Err(RecvTimeoutError::Timeout.into());
+            }
+            Err(StoreError::NotFound.into())
+        }
+
+        /// bootstrap the network by connecting to the bootstrap nodes
+        /// each dial will be done in a separate thread
+        fn bootstrap_network(self: &Arc<Self>) {
+            // lesson for future me: iter() does not work here as we need to pass the node to the thread
+            // this causes a lifetime issue
+            // or maybe i am just not good enough to figure it out
+            // (turns out copying the address out of the iterator is enough since SocketAddr is Copy)
+            for &node in self.bootstrap_node.iter() {
+                let cloned_self = self.clone();
+                thread::spawn(move || {
+                    if let Err(e) = cloned_self.transport.dial(node) {
+                        cloned_self.logger(format!("Error dialing bootstrap node {}: {}", node, e));
+                    }
+                });
+            }
+            // a host that cannot be resolved or reached is skipped, the others are still dialed
+            for host in self.bootstrap_hosts.iter() {
+                let cloned_self = self.clone();
+                let host = host.clone();
+                thread::spawn(move || {
+                    if let Err(e) = cloned_self.transport.dial_host(&host) {
+                        cloned_self.logger(format!("Error dialing bootstrap node {}: {}", host, e));
+                    }
+                });
+            }
+        }
+
+        /// like `bootstrap_network`, returning once all the bootstrap nodes are connected or `timeout` has passed.
+        /// return the ones that are connected, as given in `FileServerOpts::bootstrap_node` then `bootstrap_hosts`
+        pub fn bootstrap_network_blocking(self: &Arc<Self>, timeout: Duration) -> Vec<String> {
+            let deadline = Instant::now() + timeout;
+            self.bootstrap_network();
+            loop {
+                let peers = self.peer_addrs();
+                let connected: Vec<String> = self.bootstrap_node.iter()
+                    .filter(|node| peers.contains(node))
+                    .map(|node| node.to_string())
+                    .chain(self.bootstrap_hosts.iter().filter(|host| host_connected(host, &peers)).cloned())
+                    .collect();
+                if connected.len() == self.bootstrap_node.len() + self.bootstrap_hosts.len() || Instant::now() >= deadline {
+                    return connected;
+                }
+                thread::sleep(BOOTSTRAP_POLL_INTERVAL);
+            }
+        }
+
+        /// check every `reconnect_interval`, in the background, that the bootstrap nodes are still connected
+        /// so that a node restarting doesn't leave this one cut off from the network
+        fn supervise_bootstrap_nodes(self: &Arc<Self>) {
+            let interval = match self.reconnect_interval {
+                Some(interval) => interval,
+                None => return,
+            };
+            let cloned_self = self.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    if cloned_self.stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    cloned_self.reconnect_bootstrap_nodes();
+                }
+            });
+        }
+
+        /// delete the expired files every `sweep_interval`, in the background, and drop the stale transfers in chunks
+        fn sweep_expired_periodically(self: &Arc<Self>) {
+            let interval = match self.sweep_interval {
+                Some(interval) => interval,
+                None => return,
+            };
+            let cloned_self = self.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    if cloned_self.stopping.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    cloned_self.sweep_expired();
+                    cloned_self.drop_stale_transfers();
+                }
+            });
+        }
+
+        /// delete the files stored with a ttl that has run out, and tell the peers to delete them too.
+        /// return how many were deleted
+        pub fn sweep_expired(self: &Arc<Self>) -> usize {
+            let now = unix_now();
+            let expired: Vec<String> = {
+                let mut expiries = self.expiries.lock().unwrap();
+                let expired = expiries.iter().filter(|(_, &at)| at <= now).map(|(key, _)| key.clone()).collect();
+                expiries.retain(|_, at| *at > now);
+                expired
+            };
+            for key in expired.iter() {
+                self.logger(format!("{} has expired, deleting it", key));
+                // the file may have been deleted in the meantime
+                match self.delete_everywhere(key) {
+                    Ok(_) | Err(io::ErrorKind::NotFound) => {},
+                    Err(e) => self.logger(format!("Error deleting {}: {}", key, e)),
+                }
+            }
+
+            expired.len()
+        }
+
+        /// delete the files whose original key starts with `prefix`, here and on the other nodes, e.g. the files of
+        /// a tenant under "tenant-42/". return the result of each local delete, by key.  
+        /// the matching keys are listed from the key index before anything is deleted, so a file stored meanwhile
+        /// is left alone. fail with `Unsupported` without the key index, see `StoreOpts::key_index`
+        pub fn delete_prefix(self: &Arc<Self>, prefix: &str) -> Result<DeleteResults, DfsError> {
+            let keys: Vec<String> = self.store.list_keys()?
+                .into_iter()
+                .filter(|key| key.starts_with(prefix))
+                .collect();
+            self.logger(format!("deleting the {} keys under {}", keys.len(), prefix));
+
+            Ok(keys.into_iter()
+                .map(|key| {
+                    let res = self.delete_everywhere(&key);
+                    (key, res)
+                })
+                .collect())
+        }
+
+        /// delete the file locally and tell the peers to delete their copies, whether it was held locally or not.
+        /// return the result of the local delete
+        fn delete_everywhere(self: &Arc<Self>, key: &str) -> Result<(), io::ErrorKind> {
+            self.expiries.lock().unwrap().remove(key);
+            let res = self.store.delete(key.to_string());
+            if res.is_ok() {
+                self.notify(StoreEvent::Deleted(key.to_string()));
+            }
+            match (MessageDelete { key: key.to_string() }).to_buffer(self.wire_format) {
+                Ok(msg) => {
+                    self.broadcast(Payload { from: self.transport.clone().addr(), msg_type: MessageType::Delete, msg });
+                },
+                Err(e) => self.logger(format!("Error serializing delete message: {}", e)),
+            }
+
+            res
+        }
+
+        /// remember when the file expires, or that it doesn't anymore if it was stored again without a ttl
+        fn track_expiry(&self, key: &str, meta: Option<&Metadata>) {
+            let mut expiries = self.expiries.lock().unwrap();
+            match meta.and_then(|meta| meta.expires_at) {
+                Some(at) => expiries.insert(key.to_string(), at),
+                None => expiries.remove(key),
+            };
+        }
+
+        /// pick up the expiries of the files stored before a restart. they can only be found with the key index,
+        /// see `StoreOpts::key_index`
+        fn load_expiries(&self) {
+            let keys = match self.store.list_keys() {
+                Ok(keys) => keys,
+                Err(_) => return,
+            };
+            for key in keys {
+                if let Ok(meta) = self.store.read_meta(key.clone()) {
+                    self.track_expiry(&key, Some(&meta));
+                }
+            }
+        }
+
+        /// re-dial the bootstrap nodes that are not among the peers anymore
+        fn reconnect_bootstrap_nodes(self: &Arc<Self>) {
+            let peers = self.peer_addrs();
+            for &node in self.bootstrap_node.iter().filter(|node| !peers.contains(node)) {
+                self.logger(format!("bootstrap node {} is not connected, re-dialing", node));
+                if let Err(e) = self.transport.try_dial(node, RECONNECT_DIAL_ATTEMPTS) {
+                    self.logger(format!("Error re-dialing bootstrap node {}: {}", node, e));
+                }
+            }
+            for host in self.bootstrap_hosts.iter().filter(|host| !host_connected(host, &peers)) {
+                self.logger(format!("bootstrap node {} is not connected, re-dialing", host));
+                if let Err(e) = self.transport.dial_host(host) {
+                    self.logger(format!("Error re-dialing bootstrap node {}: {}", host, e));
+                }
+            }
+        }
+
+        fn register_on_peer_cb(self: &Arc<Self>) {
+            // callback fn when a new peer is connected
+            let cb = {
+                let cloned_self = self.clone();
+                move |peer: Arc<RwLock<T::Peer>>| {
+                    let p = peer.read().unwrap();
+                    cloned_self.logger(format!("{} on_peer: {}", if p.is_outbound() { "outbound" } else { "inbound" },  p.addr()));
+                    cloned_self.add_peer(p.addr(), peer.clone());
+
+                    true
+                }
+            };
+
+            self.transport.clone().register_on_peer(Box::new(cb));
+
+            // callback fn when a peer is gone, so that nothing is sent to it anymore
+            let cb = {
+                let cloned_self = self.clone();
+                move |addr: SocketAddr| {
+                    cloned_self.handle_peer_disconnect(addr);
+                }
+            };
+
+            self.transport.clone().register_on_peer_disconnect(Box::new(cb));
+        }
+
+        /// forget the peer whose connection is gone. return whether it was expected, as the peer said bye first
+        /// or this server is shutting down
+        fn handle_peer_disconnect(&self, addr: SocketAddr) -> bool {
+            let graceful = self.departed.lock().unwrap().remove(&addr) || self.stopping.load(Ordering::SeqCst);
+            match graceful {
+                true => self.logger(format!("peer left: {}", addr)),
+                false => self.logger(format!("Error: lost the connection to peer {}", addr)),
+            }
+            self.remove_peer(addr);
+            self.latencies.lock().unwrap().remove(&addr);
+            self.last_seen.lock().unwrap().remove(&addr);
+            self.pending_pings.lock().unwrap().retain(|(peer, _), _| *peer != addr);
+
+            graceful
+        }
+
+        /// broadcast the payload to all connected peers  
+        fn broadcast(self: &Arc<Self>, payload: Payload) {
+            self.logger(format!("Broadcasting data: {:?}", payload));
+            let payload_buffer = match payload.to_frame(self.wire_format) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.logger(format!("Error serializing payload: {}", e));
+                    return;
+                }
+            };
+            for (addr, res) in self.transport.clone().broadcast(&payload_buffer) {
+                if let Err(e) = res {
+                    self.logger(format!("Error sending to {}: {}", addr, e));
+                }
+            }
+        }
+
+        /// send the payload to the connected peers that advertised the tag
+        fn broadcast_to_tag(self: &Arc<Self>, payload: Payload, tag: &str) {
+            let nodes = self.peers_with_tag(tag);
+            self.logger(format!("Broadcasting to the {} peers tagged {}", nodes.len(), tag));
+            self.send_to_nodes(&nodes, payload);
+        }
+
+        /// send the payload to the connected peers matching the predicate
+        fn broadcast_filtered(self: &Arc<Self>, payload: Payload, predicate: impl Fn(&PeerInfo) -> bool) {
+            let nodes: Vec<SocketAddr> = self.peer_info()
+                .iter()
+                .filter(|info| predicate(info))
+                .map(|info| info.addr)
+                .collect();
+            self.logger(format!("Broadcasting to {} of the peers", nodes.len()));
+            self.send_to_nodes(&nodes, payload);
+        }
+
+        /// send the payload to the peers this node dialed
+        fn broadcast_outbound(self: &Arc<Self>, payload: Payload) {
+            self.broadcast_filtered(payload, |info| info.outbound);
+        }
+
+        /// send the payload to the peers that dialed this node
+        fn broadcast_inbound(self: &Arc<Self>, payload: Payload) {
+            self.broadcast_filtered(payload, |info| !info.outbound);
+        }
+
+        /// send the payload to each of the given peers. the ones that are not connected are skipped
+        fn send_to_nodes(self: &Arc<Self>, addrs: &[SocketAddr], payload: Payload) {
+            let payload_buffer = match payload.to_frame(self.wire_format) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    self.logger(format!("Error serializing payload: {}", e));
+                    return;
+                }
+            };
+            let peers = self.peers.read().unwrap();
+            for addr in addrs {
+                let peer = match peers.get(addr) {
+                    Some(peer) => peer,
+                    None => continue,
+                };
+                if let Err(e) = peer.write().unwrap().send(&payload_buffer) {
+                    self.logger(format!("Error sending to {}: {}", addr, e));
+                }
+            }
+        }
+
+        /// send the payload to a single connected peer
+        fn send_to_peer(self: &Arc<Self>, addr: SocketAddr, payload: Payload) -> Result<(), io::Error> {
+            let payload_buffer = payload.to_frame(self.wire_format).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let peers = self.peers.read().unwrap();
+            match peers.get(&addr) {
+                Some(peer) => peer.write().unwrap().send(&payload_buffer),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is not connected", addr))),
+            }
+        }
+
+        /// handle the message received from the transport layer
+        /// will call the right function based on the message type.
+        /// malformed messages are logged and skipped so that a bad peer cannot take the server down
+        fn handle_message(self: &Arc<Self>, msg: &Message) {
+            let payload = match Payload::from_frame(msg.from, &msg.payload, self.wire_format, self.max_message_size) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed message from {}: {}", msg.from, e));
+                    return;
+                }
+            };
+            match payload.msg_type {
+                MessageType::Store => self.handle_store_message(msg.from, &payload),
+                MessageType::GetRange => self.handle_get_range_message(msg.from, &payload),
+                MessageType::RangeData => self.handle_range_data_message(msg.from, &payload),
+                MessageType::Get => self.handle_get_message(msg.from, &payload),
+                MessageType::GetResponse => self.handle_get_response_message(msg.from, &payload),
+                MessageType::Ping => self.handle_ping_message(msg.from, &payload),
+                MessageType::Pong => self.handle_pong_message(msg.from, &payload),
+                MessageType::StoreChunk => self.handle_store_chunk_message(msg.from, &payload),
+                MessageType::Delete => self.handle_delete_message(msg.from, &payload),
+                MessageType::GetNotFound => self.handle_get_not_found_message(msg.from, &payload),
+                MessageType::StoreResume => self.handle_store_resume_message(msg.from, &payload),
+                MessageType::ResumePoint => self.handle_resume_point_message(msg.from, &payload),
+                MessageType::Bye => self.handle_bye_message(msg.from),
+                MessageType::StoreAck => self.handle_store_ack_message(msg.from, &payload),
+            }
+        }
+        
+        /// handle the store message
+        fn handle_store_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            if !self.peers.read().unwrap().contains_key(&from) {
+                self.logger(format!("Peer {} not found", from));
+                return;
+            }
+            let msg_data = match MessageData::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(msg_data) => msg_data,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed store message from {}: {}", from, e));
+                    return;
+                }
+            };
+            if let Some(gossip) = &msg_data.gossip {
+                if !self.seen_gossip.lock().unwrap().insert(&gossip.origin, gossip.id) {
+                    self.logger(format!("Dropping {} from {}, already passed on", msg_data.key, from));
+                    return;
+                }
+            }
+            self.logger(format!("Received data from {}: {} -> {}", from, msg_data.key, String::from_utf8_lossy(&msg_data.data)));
+            if !self.store_locally {
+                let ack_id = msg_data.ack_id;
+                // the copies are with the peers it was forwarded to, the sender can count on them
+                if self.forward(from, msg_data) {
+                    if let Some(ack_id) = ack_id {
+                        self.send_store_ack(from, ack_id);
+                    }
+                }
+                return;
+            }
+            if let Err(e) = self.store.write(msg_data.key.clone(), msg_data.data.as_slice()) {
+                self.logger(format!("Error writing to store: {}", e));
+                return;
+            }
+            self.notify(StoreEvent::Stored(msg_data.key.clone(), msg_data.data.len() as u64));
+            self.track_expiry(&msg_data.key, msg_data.meta.as_ref());
+            if let Some(meta) = &msg_data.meta {
+                if let Err(e) = self.store.write_meta(msg_data.key.clone(), meta) {
+                    self.logger(format!("Error writing metadata to store: {}", e));
+                }
+            }
+            if let Some(ack_id) = msg_data.ack_id {
+                self.send_store_ack(from, ack_id);
+            }
+            self.pass_on(from, msg_data);
+        }
+
+        /// tell the peer that the file it asked an ack for is stored
+        fn send_store_ack(self: &Arc<Self>, to: SocketAddr, ack_id: RequestId) {
+            let msg = match (MessageStoreAck { ack_id }).to_buffer(self.wire_format) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Error serializing message: {}", e));
+                    return;
+                }
+            };
+            let payload = Payload { from: self.transport.clone().addr(), msg_type: MessageType::StoreAck, msg };
+            if let Err(e) = self.send_to_peer(to, payload) {
+                self.logger(format!("Error sending to {}: {}", to, e));
+            }
+        }
+
+        /// handle the store ack message by passing it on to the `store_data` call waiting for it
+        fn handle_store_ack_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let ack = match MessageStoreAck::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(ack) => ack,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed store ack message from {}: {}", from, e));
+                    return;
+                }
+            };
+            match self.pending_acks.lock().unwrap().get(&ack.ack_id) {
+                // the call may have just stopped waiting
+                Some(sender) => {
+                    let _ = sender.send(from);
+                },
+                None => self.logger(format!("Ignoring unexpected store ack {} from {}", ack.ack_id, from)),
+            }
+        }
+
+        /// have a proxy send a file received from a peer to the other peers, see `FileServerOpts::store_locally`.
+        /// a gossiped file is passed on as usual. any other file is sent on once, as gossip without hops left, so
+        /// that it doesn't go around a loop of proxies. return whether it was sent to any peer
+        fn forward(self: &Arc<Self>, from: SocketAddr, mut msg_data: MessageData) -> bool {
+            if msg_data.gossip.is_some() {
+                return self.pass_on(from, msg_data);
+            }
+            let self_addr = self.transport.clone().addr();
+            let id = self.next_gossip_id.fetch_add(1, Ordering::SeqCst);
+            self.seen_gossip.lock().unwrap().insert(&self_addr, id);
+            msg_data.gossip = Some(Gossip { origin: self_addr, id, hops_left: 1 });
+            self.pass_on(from, msg_data)
+        }
+
+        /// send a gossiped file to the peers other than the one it came from, if it has hops left.
+        /// return whether it was sent to any peer
+        fn pass_on(self: &Arc<Self>, from: SocketAddr, mut msg_data: MessageData) -> bool {
+            let gossip = match &mut msg_data.gossip {
+                Some(gossip) if gossip.hops_left > 0 => gossip,
+                _ => return false,
+            };
+            gossip.hops_left -= 1;
+            let origin = gossip.origin.clone();
+            // the ack is owed to the sender only
+            msg_data.ack_id = None;
+            let nodes: Vec<SocketAddr> = self.peer_addrs()
+                .into_iter()
+                .filter(|addr| *addr != from && addr.to_string() != origin)
+                .collect();
+            if nodes.is_empty() {
+                return false;
+            }
+            let msg = match msg_data.to_buffer(self.wire_format) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Error serializing message: {}", e));
+                    return false;
+                }
+            };
+            self.logger(format!("Passing {} from {} on to {} peers", msg_data.key, origin, nodes.len()));
+            self.send_to_nodes(&nodes, Payload { from: self.transport.clone().addr(), msg_type: MessageType::Store, msg });
+            true
+        }
+
+        /// handle the bye message by closing the connection to the peer right away, rather than waiting for
+        /// the peer to close it. nothing is sent to it anymore
+        fn handle_bye_message(self: &Arc<Self>, from: SocketAddr) {
+            let peer = match self.peers.read().unwrap().get(&from) {
+                Some(peer) => peer.clone(),
+                None => {
+                    self.logger(format!("Peer {} not found", from));
+                    return;
+                }
+            };
+            self.logger(format!("peer {} is leaving", from));
+            self.departed.lock().unwrap().insert(from);
+            self.remove_peer(from);
+            let res = peer.read().unwrap().close();
+            if let Err(e) = res {
+                self.logger(format!("Error closing the connection to {}: {}", from, e));
+            }
+        }
+
+        /// handle the delete message by deleting the file if it is held locally
+        fn handle_delete_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            if !self.peers.read().unwrap().contains_key(&from) {
+                self.logger(format!("Peer {} not found", from));
+                return;
+            }
+            let msg = match MessageDelete::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed delete message from {}: {}", from, e));
+                    return;
+                }
+            };
+            self.expiries.lock().unwrap().remove(&msg.key);
+            match self.store.delete(msg.key.clone()) {
+                Ok(_) => {
+                    self.logger(format!("Deleted {} as asked by {}", msg.key, from));
+                    self.notify(StoreEvent::Deleted(msg.key));
+                },
+                Err(io::ErrorKind::NotFound) => {},
+                Err(e) => self.logger(format!("Error deleting {}: {}", msg.key, e)),
+            }
+        }
+
+        /// handle the get range message by replying with the requested range if the file is held locally
+        fn handle_get_range_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let req = match MessageGetRange::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(req) => req,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed get range message from {}: {}", from, e));
+                    return;
+                }
+            };
+            // `get_range` asks for pieces of at most `RANGE_PIECE_SIZE`, a larger range is cut short
+            let data = match self.store.read_range(req.key.clone(), req.offset, req.len.min(RANGE_PIECE_SIZE)) {
+                Ok(data) => {
+                    self.logger(format!("Serving {} bytes of {} from offset {} to {}", data.len(), req.key, req.offset, from));
+                    Some(data)
+                },
+                Err(e) => {
+                    self.logger(format!("Cannot serve range of {} to {}: {:?}", req.key, from, e));
+                    None
+                }
+            };
+            let msg = match (MessageRangeData { request_id: req.request_id, key: req.key, offset: req.offset, data }).to_buffer(self.wire_format) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Error serializing message: {}", e));
+                    return;
+                }
+            };
+            let reply = Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::RangeData,
+                msg,
+            };
+            if let Err(e) = self.send_to_peer(from, reply) {
+                self.logger(format!("Error sending to {}: {}", from, e));
+            }
+        }
+
+        /// handle the range data message by passing it on to the `get_range` call waiting for it.
+        /// a range nobody asked this peer for is dropped, so that a peer can't write into the store this way
+        fn handle_range_data_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let msg_data = match MessageRangeData::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(msg_data) => msg_data,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed range data message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let mut pending_ranges = self.pending_ranges.lock().unwrap();
+            let expected = pending_ranges.get(&msg_data.request_id)
+                .is_some_and(|pending| pending.peer == from && pending.key == msg_data.key && pending.offset == msg_data.offset);
+            // the call may have stopped waiting already, in which case the reply is dropped too
+            if !expected {
+                self.logger(format!("Ignoring unexpected range of {} from {}", msg_data.key, from));
+                return;
+            }
+            if let Some(pending) = pending_ranges.remove(&msg_data.request_id) {
+                let _ = pending.sender.send(msg_data.data);
+            }
+        }
+
+        /// handle the get message by replying with the file if it is held locally.  
+        /// the file is streamed from the store in pieces of `GET_RESPONSE_CHUNK_SIZE` bytes as it is read,
+        /// rather than read whole into memory first
+        fn handle_get_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let req = match MessageGet::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(req) => req,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed get message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let mut w = GetResponseWriter {
+                server: self,
+                to: from,
+                request_id: req.request_id,
+                key: req.key.clone(),
+                offset: 0,
+                buf: Vec::with_capacity(GET_RESPONSE_CHUNK_SIZE),
+            };
+            let res = self.store
+                .read_to_writer(req.key.clone(), &mut w)
+                .map_err(io::Error::from)
+                .and_then(|_| w.finish(self.store.read_meta(req.key.clone()).ok()));
+            // the pieces already sent, if any, are dropped by the receiver when it learns the file is not coming
+            if let Err(e) = res {
+                self.logger(format!("Cannot send {} requested by {}: {}", req.key, from, e));
+                self.reply_not_found(from, req);
+            }
+        }
+
+        /// tell the peer asking for the file that it is not held here, so that it asks the next replica
+        fn reply_not_found(self: &Arc<Self>, to: SocketAddr, req: MessageGet) {
+            let msg = match req.to_buffer(self.wire_format) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Error serializing message: {}", e));
+                    return;
+                }
+            };
+            let reply = Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::GetNotFound,
+                msg,
+            };
+            if let Err(e) = self.send_to_peer(to, reply) {
+                self.logger(format!("Error sending to {}: {}", to, e));
+            }
+        }
+
+        /// handle the get not found message by letting the waiting `get_data` call move on to the next peer.
+        /// an answer from a peer that is not the one asked, e.g. one that already timed out, is ignored
+        fn handle_get_not_found_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let req = match MessageGet::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(req) => req,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed get not found message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let mut pending_gets = self.pending_gets.lock().unwrap();
+            if pending_gets.get(&req.request_id).is_some_and(|pending| pending.peer == from) {
+                if let Some(pending) = pending_gets.remove(&req.request_id) {
+                    let _ = pending.sender.send(None);
+                }
+            }
+        }
+
+        /// handle the get response message by handing the file over to the waiting `get_data` call
+        fn handle_get_response_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let msg_data = match MessageGetResponse::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(msg_data) => msg_data,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed get response message from {}: {}", from, e));
+                    return;
+                }
+            };
+            // a reply to a request that timed out, or from another peer than the one asked, finds nobody waiting
+            let complete = {
+                let mut pending_gets = self.pending_gets.lock().unwrap();
+                let pending = match pending_gets.get_mut(&msg_data.request_id) {
+                    Some(pending) if pending.peer == from => pending,
+                    _ => return,
+                };
+                // the pieces come in order over the connection, so one out of place means some got lost
+                if msg_data.offset != pending.received.len() as u64 {
+                    self.logger(format!("{} from {} is missing bytes before {}, dropping it", msg_data.key, from, msg_data.offset));
+                    if let Some(pending) = pending_gets.remove(&msg_data.request_id) {
+                        let _ = pending.sender.send(None);
+                    }
+                    return;
+                }
+                // the peer could keep sending pieces, so the file is given up on once it can't be taken anyway
+                let size = pending.received.len() as u64 + msg_data.data.len() as u64;
+                if size > self.max_get_size || (self.store_locally && self.store.check_room(size).is_err()) {
+                    self.logger(format!("{} from {} is larger than can be taken, dropping it", msg_data.key, from));
+                    if let Some(pending) = pending_gets.remove(&msg_data.request_id) {
+                        let _ = pending.sender.send(None);
+                    }
+                    return;
+                }
+                pending.received.extend_from_slice(&msg_data.data);
+                match msg_data.is_last {
+                    true => pending_gets.remove(&msg_data.request_id),
+                    false => None,
+                }
+            };
+            if let Some(pending) = complete {
+                self.logger(format!("Received {} ({} bytes) from {}", msg_data.key, pending.received.len(), from));
+                if let Some(meta) = msg_data.meta {
+                    if let Err(e) = self.store.write_meta(msg_data.key.clone(), &meta) {
+                        self.logger(format!("Error writing metadata to store: {}", e));
+                    }
+                }
+                let _ = pending.sender.send(Some(pending.received));
+            }
+        }
+
+        /// handle the store chunk message by writing the chunk at its offset into a temporary file, kept out of the store  
+        /// the temporary file replaces the stored file once every byte up to the last chunk has arrived.
+        /// a chunk overlapping another one or not fitting the file aborts the whole transfer, see `ChunkBuffer::check_bounds`
+        fn handle_store_chunk_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            if !self.peers.read().unwrap().contains_key(&from) {
+                self.logger(format!("Peer {} not found", from));
+                return;
+            }
+            let chunk = match MessageStoreChunk::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed store chunk message from {}: {}", from, e));
+                    return;
+                }
+            };
+            self.drop_stale_transfers();
+            let tmp_name = chunk_temp_name(from, &chunk.key);
+            let transfer = (from, chunk.key.clone());
+            let mut buffers = self.chunk_buffers.lock().unwrap();
+            if !buffers.contains_key(&transfer) {
+                // refuse the file before any of it is written rather than halfway through
+                if buffers.len() >= MAX_CHUNK_TRANSFERS {
+                    self.logger(format!("Dropping chunk of {} from {}, {} transfers already in progress", chunk.key, from, buffers.len()));
+                    return;
+                }
+                if let Err(e) = self.store.check_room(chunk.size) {
+                    self.logger(format!("Refusing the {} bytes of {} from {}: {}", chunk.size, chunk.key, from, e));
+                    return;
+                }
+            }
+            let version = (chunk.hash.clone(), chunk.size);
+            let buffer = buffers.entry(transfer.clone()).or_insert_with(|| ChunkBuffer::new(chunk.hash.clone(), chunk.size));
+            if buffer.version != version {
+                // what was received so far belongs to another version of the file
+                self.logger(format!("Restarting the transfer of {} from {}, the file has changed", chunk.key, from));
+                *buffer = ChunkBuffer::new(chunk.hash.clone(), chunk.size);
+                let _ = self.store.discard_temp(tmp_name.clone());
+            }
+
+            let res = buffer.check_bounds(chunk.offset, chunk.data.len() as u64, chunk.is_last)
+                .and_then(|_| buffer.add(chunk.offset, chunk.data.len() as u64, chunk.is_last))
+                .and_then(|_| self.store.write_temp_at(tmp_name.clone(), chunk.offset, &chunk.data).map_err(|e| e.to_string()));
+            if let Err(e) = res {
+                self.logger(format!("Aborting transfer of {} from {}: {}", chunk.key, from, e));
+                buffers.remove(&transfer);
+                let _ = self.store.discard_temp(tmp_name);
+                return;
+            }
+            if !buffer.is_complete() {
+                if buffer.size.is_some() {
+                    self.logger(format!("Waiting for {:?} of {} from {}", buffer.gaps(), chunk.key, from));
+                }
+                return;
+            }
+
+            let size = buffer.size.unwrap_or(0);
+            buffers.remove(&transfer);
+            match self.store.commit_temp(tmp_name, chunk.key.clone()) {
+                Ok(_) => {
+                    self.logger(format!("Received all the chunks of {} from {}", chunk.key, from));
+                    self.notify(StoreEvent::Stored(chunk.key, size));
+                },
+                Err(e) => self.logger(format!("Error finalizing {} from {}: {}", chunk.key, from, e)),
+            }
+        }
+
+        /// drop the transfers in chunks that have stopped getting chunks, see `CHUNK_TRANSFER_TIMEOUT`, and discard
+        /// their temporary files. return how many were dropped
+        fn drop_stale_transfers(self: &Arc<Self>) -> usize {
+            let mut buffers = self.chunk_buffers.lock().unwrap();
+            let stale: Vec<(SocketAddr, String)> = buffers.iter()
+                .filter(|(_, buffer)| buffer.is_stale())
+                .map(|(transfer, _)| transfer.clone())
+                .collect();
+            for (from, key) in stale.iter() {
+                self.logger(format!("Dropping the transfer of {} from {}, no chunk for {:?}", key, from, CHUNK_TRANSFER_TIMEOUT));
+                buffers.remove(&(*from, key.clone()));
+                if let Err(e) = self.store.discard_temp(chunk_temp_name(*from, key)) {
+                    self.logger(format!("Error discarding the partial {} from {}: {}", key, from, e));
+                }
+            }
+
+            stale.len()
+        }
+
+        /// handle the store resume message by telling the peer how much of the file it has already sent.
+        /// the chunks of another version of the file don't count, so the peer starts over from 0
+        fn handle_store_resume_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let req = match MessageStoreResume::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(req) => req,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed store resume message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let offset = match self.chunk_buffers.lock().unwrap().get_mut(&(from, req.key.clone())) {
+                Some(buffer) if buffer.version == (req.hash, req.size) => buffer.resume_point(),
+                _ => 0,
+            };
+            let msg = match (MessageResumePoint { key: req.key, offset }).to_buffer(self.wire_format) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Error serializing message: {}", e));
+                    return;
+                }
+            };
+            let reply = Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::ResumePoint,
+                msg,
+            };
+            if let Err(e) = self.send_to_peer(from, reply) {
+                self.logger(format!("Error sending to {}: {}", from, e));
+            }
+        }
+
+        /// handle the resume point message by handing the offset over to the waiting `store_in_chunks` call
+        fn handle_resume_point_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let msg = match MessageResumePoint::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed resume point message from {}: {}", from, e));
+                    return;
+                }
+            };
+            if let Some(sender) = self.pending_resumes.lock().unwrap().remove(&(from, msg.key)) {
+                let _ = sender.send(msg.offset);
+            }
+        }
+
+        /// handle the ping message by echoing its nonce back
+        fn handle_ping_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let ping = match MessagePing::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(ping) => ping,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed ping message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let reply = Payload {
+                from: self.transport.clone().addr(),
+                msg_type: MessageType::Pong,
+                msg: payload.msg.clone(),
+            };
+            if let Err(e) = self.send_to_peer(from, reply) {
+                self.logger(format!("Error answering ping {} from {}: {}", ping.nonce, from, e));
+            }
+        }
+
+        /// handle the pong message by folding the round trip time into the peer's rolling average
+        fn handle_pong_message(self: &Arc<Self>, from: SocketAddr, payload: &Payload) {
+            let pong = match MessagePing::from_buffer(&payload.msg, self.wire_format, self.max_message_size) {
+                Ok(pong) => pong,
+                Err(e) => {
+                    self.logger(format!("Dropping malformed pong message from {}: {}", from, e));
+                    return;
+                }
+            };
+            let sent_at = match self.pending_pings.lock().unwrap().remove(&(from, pong.nonce)) {
+                Some(sent_at) => sent_at,
+                None => {
+                    self.logger(format!("Ignoring unexpected pong {} from {}", pong.nonce, from));
+                    return;
+                }
+            };
+            let rtt = sent_at.elapsed();
+            self.last_seen.lock().unwrap().insert(from, unix_now());
+            let mut latencies = self.latencies.lock().unwrap();
+            let latency = match latencies.get(&from) {
+                Some(avg) => (*avg * (LATENCY_SMOOTHING - 1) + rtt) / LATENCY_SMOOTHING,
+                None => rtt,
+            };
+            latencies.insert(from, latency);
+            self.logger(format!("Round trip to {}: {:?} (average {:?})", from, rtt, latency));
+        }
+
+        fn logger(&self, msg: String) {
+            println!("[server {}] {}", self.transport.clone().addr() , msg);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use crate::transport::delay::DelayedTransport;
+        use crate::transport::encoding::{TypedFrameDecoder, TypedFrameEncoder, DEFAULT_MAX_PAYLOAD_SIZE};
+        use crate::transport::tcp::{TcpTransport, TcpTransportOpts};
+        use crate::transport::tls::test_certs::TestCa;
+
+        use crate::transport::transport::{OnPeerDisconnectFn, OnPeerFn};
+
+        use super::*;
+
+        /// the largest payload of the transports used in the tests
+        const MAX_MESSAGE_SIZE: u64 = DEFAULT_MAX_PAYLOAD_SIZE as u64;
+
+        /// a peer that records what is sent to it instead of writing to a socket
+        struct MockPeer {
+            addr: SocketAddr,
+            sent: Arc<Mutex<Vec<Vec<u8>>>>,
+            tags: Vec<String>,
+            outbound: bool,
+        }
+
+        impl PeerLike for MockPeer {
+            fn addr(&self) -> SocketAddr {
+                self.addr
+            }
+
+            fn close(&self) -> Result<(), io::Error> {
+                Ok(())
+            }
+
+            fn send(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+                self.sent.lock().unwrap().push(buf.to_vec());
+                Ok(())
+            }
+
+            fn is_outbound(&self) -> bool {
+                self.outbound
+            }
+
+            fn tags(&self) -> Vec<String> {
+                self.tags.clone()
+            }
+        }
+
+        fn make_server(root_dir: &str) -> Arc<FileServer<TcpTransport>> {
+            make_server_on(root_dir, "127.0.0.1:0")
+        }
+
+        fn make_server_on(root_dir: &str, listen_addr: &str) -> Arc<FileServer<TcpTransport>> {
+            FileServer::new(make_opts(root_dir, listen_addr)).unwrap()
+        }
+
+        fn make_opts(root_dir: &str, listen_addr: &str) -> FileServerOpts<TcpTransport> {
+            make_opts_with(root_dir, make_tcp_transport(listen_addr))
+        }
+
+        fn make_tcp_transport(listen_addr: &str) -> Arc<TcpTransport> {
+            let mut opts = TcpTransportOpts::new(listen_addr.to_string(), Box::new(TypedFrameDecoder::new()));
+            opts.encoder = Arc::new(TypedFrameEncoder {});
+            TcpTransport::new(opts).unwrap()
+        }
+
+        fn make_opts_with<T: Transport>(root_dir: &str, transport: Arc<T>) -> FileServerOpts<T> {
+            FileServerOpts {
+                store_opts: StoreOpts::new(root_dir.to_string(), |s| s),
+                transport,
+                bootstrap_node: Vec::new(),
+                bootstrap_hosts: Vec::new(),
+                consume_retry: ConsumeRetryPolicy::default(),
+                reconnect_interval: None,
+                wire_format: WireFormat::Bincode,
+                sweep_interval: None,
+                close_timeout: DEFAULT_CLOSE_TIMEOUT,
+                drain_on_shutdown: false,
+                admin_addr: None,
+                gossip_hops: 0,
+                bootstrap_timeout: None,
+                consistency: Consistency::Local,
+                blocking_consume: false,
+                store_locally: true,
+                consume_batch: DEFAULT_CONSUME_BATCH,
+                max_get_size: DEFAULT_MAX_GET_SIZE,
+            }
+        }
+
+        /// register a mock peer on the server and return the buffer collecting what is sent to it
+        fn add_mock_peer<T: Transport>(server: &Arc<FileServer<T>>, addr: SocketAddr) -> Arc<Mutex<Vec<Vec<u8>>>> {
+            add_tagged_mock_peer(server, addr, &[])
+        }
+
+        /// like `add_mock_peer`, for a peer that advertised the tags
+        fn add_tagged_mock_peer<T: Transport>(server: &Arc<FileServer<T>>, addr: SocketAddr, tags: &[&str]) -> Arc<Mutex<Vec<Vec<u8>>>> {
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let peer = MockPeer { addr, sent: sent.clone(), tags: tags.iter().map(|tag| tag.to_string()).collect(), outbound: false };
+            server.add_peer(addr, Arc::new(RwLock::new(peer)));
+
+            sent
+        }
+
+        /// like `add_mock_peer`, for a peer this server dialed
+        fn add_outbound_mock_peer<T: Transport>(server: &Arc<FileServer<T>>, addr: SocketAddr) -> Arc<Mutex<Vec<Vec<u8>>>> {
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let peer = MockPeer { addr, sent: sent.clone(), tags: Vec::new(), outbound: true };
+            server.add_peer(addr, Arc::new(RwLock::new(peer)));
+
+            sent
+        }
+
+        /// connect `a` to `b` over tcp and serve the messages of both in the background
+        fn connect_servers(a: &Arc<FileServer<TcpTransport>>, b: &Arc<FileServer<TcpTransport>>) {
+            for server in [a, b] {
+                server.transport.clone().listen_and_accept().unwrap();
+                let server = server.clone();
+                thread::spawn(move || server.run().unwrap());
+            }
+            a.transport.dial(b.transport.clone().local_addr().unwrap()).unwrap();
+            assert!(wait_until(|| a.peer_addrs().len() == 1 && b.peer_addrs().len() == 1));
+        }
+
+        #[test]
+        fn test_store_over_tls() {
+            let ca = TestCa::generate();
+            let make_tls_server = |root_dir: &str| {
+                let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(TypedFrameDecoder::new()));
+                opts.encoder = Arc::new(TypedFrameEncoder {});
+                opts.tls = Some(ca.node_config());
+                FileServer::new(make_opts_with(root_dir, TcpTransport::new(opts).unwrap())).unwrap()
+            };
+            let server_a = make_tls_server("test_store_tls_a");
+            let server_b = make_tls_server("test_store_tls_b");
+            connect_servers(&server_a, &server_b);
+
+            // the file goes through the encrypted connection and is stored on the replica
+            let key = String::from("secret_file");
+            let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+            server_a.store_data(key.clone(), &mut &data[..]).unwrap();
+            assert!(wait_until(|| server_b.store.read(key.clone()).is_ok()));
+            assert_eq!(server_b.store.read(key).unwrap(), data);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_get_range_from_peer() {
+            let server_a = make_server("test_store_get_range_a");
+            let server_b = make_server("test_store_get_range_b");
+            connect_servers(&server_a, &server_b);
+
+            // a holds the full file, b only the first 1000 bytes
+            let key = String::from("large_file");
+            let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+            server_a.store.write(key.clone(), &data).unwrap();
+            server_b.store.write(key.clone(), &data[..1000]).unwrap();
+
+            assert_eq!(server_b.get_range(key.clone(), 1000, 1000).unwrap(), data[1000..2000].to_vec());
+            assert_eq!(server_b.store.read(key.clone()).unwrap(), data[..2000].to_vec());
+            assert_eq!(server_b.store.read_range(key.clone(), 1000, 1000).unwrap(), data[1000..2000].to_vec());
+
+            // a range larger than a message is asked for in pieces, and cut short at the end of the file
+            let data: Vec<u8> = (0..RANGE_PIECE_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+            server_a.store.write(key.clone(), &data).unwrap();
+            assert_eq!(server_b.get_range(key.clone(), 2000, u64::MAX / 2).unwrap(), data[2000..].to_vec());
+            assert_eq!(server_b.store.read(key).unwrap(), data);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_with_meta_on_replica() {
+            let server_a = make_server("test_store_meta_a");
+            let server_b = make_server("test_store_meta_b");
+            connect_servers(&server_a, &server_b);
+            let key = String::from("photo");
+            let meta = Metadata {
+                content_type: Some(String::from("image/png")),
+                filename: Some(String::from("cat.png")),
+                created_at: 1700000000,
+                expires_at: None,
+            };
+
+            server_a.store_with_meta(key.clone(), &mut vec![1, 2, 3, 4].as_slice(), meta.clone()).unwrap();
+
+            assert!(wait_until(|| server_b.read_meta(key.clone()).is_ok()));
+            assert_eq!(server_b.store.read(key.clone()).unwrap(), vec![1, 2, 3, 4]);
+            assert_eq!(server_b.read_meta(key.clone()).unwrap(), meta);
+            assert_eq!(server_a.read_meta(key).unwrap(), meta);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_with_ttl_expires() {
+            let mut opts = make_opts("test_store_ttl_a", "127.0.0.1:0");
+            opts.sweep_interval = Some(Duration::from_millis(100));
+            let server_a = FileServer::new(opts).unwrap();
+            let server_b = make_server("test_store_ttl_b");
+            connect_servers(&server_a, &server_b);
+            server_a.sweep_expired_periodically();
+            let key = String::from("ephemeral");
+            let kept = String::from("kept");
+
+            server_a.store_with_ttl(key.clone(), &mut vec![1, 2, 3].as_slice(), Some(Duration::from_secs(1))).unwrap();
+            server_a.store_with_ttl(kept.clone(), &mut vec![4, 5, 6].as_slice(), None).unwrap();
+            assert!(wait_until(|| server_b.store.read(key.clone()).is_ok() && server_b.store.read(kept.clone()).is_ok()));
+            assert!(server_b.read_meta(key.clone()).unwrap().expires_at.is_some());
+
+            // the sweep deletes the file here and the delete reaches the replica
+            assert!(wait_until(|| server_a.store.read(key.clone()).is_err() && server_b.store.read(key.clone()).is_err()));
+            assert_eq!(server_a.store.read(kept.clone()).unwrap(), vec![4, 5, 6]);
+            assert_eq!(server_b.store.read(kept).unwrap(), vec![4, 5, 6]);
+            assert_eq!(server_a.sweep_expired(), 0);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_subscribe_to_store_events() {
+            let server_a = make_server("test_store_events_a");
+            let server_b = make_server("test_store_events_b");
+            connect_servers(&server_a, &server_b);
+            let local = server_a.subscribe();
+            let replicated = [server_b.subscribe(), server_b.subscribe()];
+
+            server_a.store_data(String::from("first"), &mut vec![1, 2, 3].as_slice()).unwrap();
+            server_a.store_data(String::from("second"), &mut vec![4, 5].as_slice()).unwrap();
+
+            // every subscriber gets every event, in order
+            let expected = [StoreEvent::Stored(String::from("first"), 3), StoreEvent::Stored(String::from("second"), 2)];
+            for receiver in std::iter::once(&local).chain(replicated.iter()) {
+                for event in expected.iter() {
+                    assert_eq!(&receiver.recv_timeout(Duration::from_secs(5)).unwrap(), event);
+                }
+            }
+            // a dropped receiver is forgotten on the next event
+            drop(replicated);
+            server_b.notify(StoreEvent::Deleted(String::from("third")));
+            assert!(server_b.subscribers.lock().unwrap().is_empty());
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        /// a transport handing out scripted `consume` results, then timing out
+        struct ScriptedTransport {
+            consumed: Mutex<std::collections::VecDeque<Result<Message, RecvTimeoutError>>>,
+            /// how long `close` takes
+            close_delay: Duration,
+        }
+
+        impl Transport for ScriptedTransport {
+            type Peer = MockPeer;
+
+            fn addr(self: Arc<Self>) -> String {
+                String::from("127.0.0.1:10050")
+            }
+
+            fn local_addr(self: Arc<Self>) -> Result<SocketAddr, io::Error> {
+                Ok(SocketAddr::from(([127, 0, 0, 1], 10050)))
+            }
+
+            fn close(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+                thread::sleep(self.close_delay);
+                Ok(())
+            }
+
+            fn close_peer(self: Arc<Self>, addr: SocketAddr) -> Result<(), io::Error> {
+                Err(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is not connected", addr)))
+            }
+
+            fn drain(self: Arc<Self>) -> Vec<Message> {
+                self.consumed.lock().unwrap().drain(..).filter_map(|res| res.ok()).collect()
+            }
+
+            fn consume(self: Arc<Self>) -> Result<Message, RecvTimeoutError> {
+                match self.consumed.lock().unwrap().pop_front() {
+                    Some(res) => res,
+                    None => {
+                        thread::sleep(Duration::from_millis(10));
+                        Err(RecvTimeoutError::Timeout)
+                    }
+                }
+            }
+
+            fn listen_and_accept(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+
+            fn dial(self: &Arc<Self>, _addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+
+            fn try_dial(self: &Arc<Self>, _addr: SocketAddr, _max_attemps: u8) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+
+            fn send_to(self: &Arc<Self>, _addr: SocketAddr, _buf: &[u8]) -> Result<(), io::Error> {
+                Ok(())
+            }
+
+            fn broadcast(self: Arc<Self>, _buf: &[u8]) -> Vec<(SocketAddr, Result<(), io::Error>)> {
+                Vec::new()
+            }
+
+            fn peer_count(self: Arc<Self>) -> usize {
+                0
+            }
+
+            fn peer_addrs(self: Arc<Self>) -> Vec<SocketAddr> {
+                Vec::new()
+            }
+
+            fn register_on_peer(self: Arc<Self>, _callback: OnPeerFn<MockPeer>) {}
+
+            fn pause_accept(self: Arc<Self>) {}
+
+            fn resume_accept(self: Arc<Self>) {}
+
+            fn is_listening(self: Arc<Self>) -> bool {
+                true
+            }
+
+            fn register_on_peer_disconnect(self: Arc<Self>, _callback: OnPeerDisconnectFn) {}
+        }
+
+        fn make_scripted_server(root_dir: &str, consumed: Vec<Result<Message, RecvTimeoutError>>) -> Arc<FileServer<ScriptedTransport>> {
+            let transport = Arc::new(ScriptedTransport { consumed: Mutex::new(consumed.into()), close_delay: Duration::ZERO });
+            let mut opts = make_opts_with(root_dir, transport);
+            opts.consume_retry = ConsumeRetryPolicy { max_attempts: 3, backoff: Duration::from_millis(10) };
+            FileServer::new(opts).unwrap()
+        }
+
+        #[test]
+        fn test_run_survives_transient_disconnect() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10051));
+            let key = String::from("after_reconnect");
+            let payload = Payload {
+                from: addr.to_string(),
+                msg_type: MessageType::Store,
+                msg: MessageData { key: key.clone(), data: vec![1, 2], meta: None, gossip: None, ack_id: None }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            let consumed = vec![
+                Err(RecvTimeoutError::Disconnected),
+                Err(RecvTimeoutError::Disconnected),
+                Ok(Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() }),
+            ];
+            let server = make_scripted_server("test_store_reconnect", consumed);
+            add_mock_peer(&server, addr);
+
+            let runner = {
+                let server = server.clone();
+                thread::spawn(move || server.run())
+            };
+            assert!(wait_until(|| server.store.read(key.clone()).is_ok()));
+            server.clone().shutdown();
+            assert!(runner.join().unwrap().is_ok());
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_run_reattaches_tcp_transport() {
+            let transport = Arc::new(DelayedTransport::new(make_tcp_transport("127.0.0.1:0")));
+            let mut opts = make_opts_with("test_store_reattach", transport);
+            opts.consume_retry = ConsumeRetryPolicy { max_attempts: 3, backoff: Duration::from_millis(10) };
+            let server = FileServer::new(opts).unwrap();
+            server.transport.clone().listen_and_accept().unwrap();
+            server.transport.inner.disconnect_lanes();
+
+            let runner = {
+                let server = server.clone();
+                thread::spawn(move || server.run())
+            };
+            assert!(wait_until(|| server.transport.reattaches.load(Ordering::SeqCst) > 0));
+            // the messages sent once the channel is re-created are handled
+            let client = make_tcp_transport("127.0.0.1:0");
+            let server_addr = server.transport.clone().local_addr().unwrap();
+            client.dial(server_addr).unwrap();
+            let key = String::from("after_reattach");
+            let payload = Payload {
+                from: client.clone().addr(),
+                msg_type: MessageType::Store,
+                msg: MessageData { key: key.clone(), data: vec![1, 2], meta: None, gossip: None, ack_id: None }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            client.send_to(server_addr, &payload.to_frame(WireFormat::Bincode).unwrap()).unwrap();
+            assert!(wait_until(|| server.store.read(key.clone()).is_ok()));
+
+            server.clone().shutdown();
+            assert!(runner.join().unwrap().is_ok());
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_run_gives_up_on_lasting_disconnect() {
+            let consumed = (0..4).map(|_| Err(RecvTimeoutError::Disconnected)).collect();
+            let server = make_scripted_server("test_store_disconnected", consumed);
+
+            assert!(matches!(server.run(), Err(DfsError::Transport(_))));
+        }
+
+        #[test]
+        fn test_shutdown_with_slow_close() {
+            let transport = Arc::new(ScriptedTransport { consumed: Mutex::new(Default::default()), close_delay: Duration::from_secs(30) });
+            let mut opts = make_opts_with("test_store_slow_close", transport);
+            opts.close_timeout = Duration::from_millis(200);
+            let server = FileServer::new(opts).unwrap();
+
+            let runner = {
+                let server = server.clone();
+                thread::spawn(move || server.run())
+            };
+            let start = Instant::now();
+            server.clone().shutdown();
+            assert!(wait_until(|| runner.is_finished()));
+            assert!(runner.join().unwrap().is_ok());
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+
+        #[test]
+        fn test_shutdown_drains_received_messages() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10066));
+            let keys: Vec<String> = (0..5).map(|i| format!("in_flight_{}", i)).collect();
+            let consumed = keys
+                .iter()
+                .map(|key| {
+                    let payload = Payload {
+                        from: addr.to_string(),
+                        msg_type: MessageType::Store,
+                        msg: MessageData { key: key.clone(), data: vec![1, 2], meta: None, gossip: None, ack_id: None }.to_buffer(WireFormat::Bincode).unwrap(),
+                    };
+                    Ok(Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() })
+                })
+                .collect();
+            let transport = Arc::new(ScriptedTransport { consumed: Mutex::new(consumed), close_delay: Duration::ZERO });
+            let mut opts = make_opts_with("test_store_drain", transport);
+            opts.drain_on_shutdown = true;
+            let server = FileServer::new(opts).unwrap();
+            add_mock_peer(&server, addr);
+
+            // the shutdown is seen before any message is consumed
+            server.clone().shutdown();
+            server.run().unwrap();
+
+            for key in keys {
+                assert_eq!(server.store.read(key).unwrap(), vec![1, 2]);
+            }
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_health() {
+            let mut opts = make_opts("test_store_health", "127.0.0.1:0");
+            opts.admin_addr = Some(String::from("127.0.0.1:0"));
+            let server = FileServer::new(opts).unwrap();
+            // nothing is accepted before the server starts
+            assert!(!server.health().is_ok());
+            assert_eq!(server.admin_local_addr(), None);
+
+            let handle = server.clone().start_background();
+            assert!(wait_until(|| server.health().listening && server.admin_local_addr().is_some()));
+            let admin_addr = server.admin_local_addr().unwrap();
+            for port in [10071, 10072] {
+                add_mock_peer(&server, SocketAddr::from(([127, 0, 0, 1], port)));
+            }
+            let health = server.health();
+            assert_eq!(health, Health { listening: true, peer_count: 2, store_ok: true });
+            assert_eq!(health.peer_count, server.peer_addrs().len());
+
+            let mut lines = String::new();
+            io::Read::read_to_string(&mut std::net::TcpStream::connect(admin_addr).unwrap(), &mut lines).unwrap();
+            let lines: Vec<&str> = lines.lines().collect();
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[0], "ok listening=true peers=2 store=ok");
+            assert!(lines[1].starts_with("peer 127.0.0.1:10071 inbound connected_at="), "{}", lines[1]);
+
+            handle.shutdown();
+            handle.join().unwrap();
+            // the admin listener stops with the server and gives its port back
+            assert!(wait_until(|| server.admin_local_addr().is_none()));
+            assert!(wait_until(|| TcpListener::bind(admin_addr).is_ok()));
+        }
+
+        #[test]
+        fn test_peer_info_direction() {
+            let server_a = make_server("test_store_peer_info_a");
+            let server_b = make_server("test_store_peer_info_b");
+            connect_servers(&server_a, &server_b);
+
+            // a dialed b
+            let info_a = server_a.peer_info();
+            assert_eq!(info_a.len(), 1);
+            assert_eq!(info_a[0].addr, server_b.transport.clone().local_addr().unwrap());
+            assert!(info_a[0].outbound);
+            assert!(info_a[0].connected_at > 0);
+            assert_eq!((info_a[0].last_seen, info_a[0].latency), (None, None));
+            let info_b = server_b.peer_info();
+            assert_eq!(info_b.len(), 1);
+            assert!(!info_b[0].outbound);
+
+            server_a.ping(info_a[0].addr).unwrap();
+            assert!(wait_until(|| server_a.peer_info()[0].latency.is_some()));
+            assert!(server_a.peer_info()[0].last_seen.is_some());
+        }
+
+        /// a store chunk message from `from` carrying `data[offset..end]`
+        fn chunk_message(from: SocketAddr, key: &str, data: &[u8], offset: usize, end: usize) -> Message {
+            let chunk = MessageStoreChunk {
+                key: key.to_string(),
+                hash: hashlib::get_file_hash(data),
+                size: data.len() as u64,
+                offset: offset as u64,
+                data: data[offset..end].to_vec(),
+                is_last: end == data.len(),
+            };
+            let payload = Payload {
+                from: from.to_string(),
+                msg_type: MessageType::StoreChunk,
+                msg: chunk.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            Message { from, payload: payload.to_frame(WireFormat::Bincode).unwrap() }
+        }
+
+        #[test]
+        fn test_chunks_reassembled_out_of_order() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10061));
+            let server = make_server("test_store_chunks");
+            add_mock_peer(&server, addr);
+            let key = "chunked";
+            let data: Vec<u8> = (0..10).collect();
+
+            server.handle_message(&chunk_message(addr, key, &data, 8, 10));
+            server.handle_message(&chunk_message(addr, key, &data, 0, 4));
+            // nothing is stored under the key until every chunk is there, and the partial file isn't listed
+            assert!(server.store.read(key.to_string()).is_err());
+            assert!(server.store.list().unwrap().is_empty());
+            server.handle_message(&chunk_message(addr, key, &data, 4, 8));
+
+            assert_eq!(server.store.read(key.to_string()).unwrap(), data);
+            assert_eq!(server.store.list().unwrap(), vec![key.to_string()]);
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_duplicate_chunk_aborts_transfer() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10062));
+            let server = make_server("test_store_chunks_duplicate");
+            add_mock_peer(&server, addr);
+            let key = "chunked";
+            let data: Vec<u8> = (0..10).collect();
+
+            server.handle_message(&chunk_message(addr, key, &data, 0, 4));
+            server.handle_message(&chunk_message(addr, key, &data, 0, 4));
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+
+            // the rest of the aborted transfer doesn't complete the file
+            server.handle_message(&chunk_message(addr, key, &data, 4, 10));
+            assert!(server.store.read(key.to_string()).is_err());
+
+            let mut buffer = ChunkBuffer::default();
+            buffer.add(0, 4, false).unwrap();
+            assert!(buffer.add(2, 4, false).is_err());
+            buffer.add(6, 4, true).unwrap();
+            assert_eq!(buffer.gaps(), vec![(4, 6)]);
+            assert!(buffer.add(8, 4, false).is_err());
+            let _ = server.store.clear();
+        }
+
+        #[test]
+        fn test_overflowing_chunk_aborts_transfer() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10088));
+            let server = make_server("test_store_chunks_overflow");
+            add_mock_peer(&server, addr);
+            let data: Vec<u8> = (0..10).collect();
+            server.handle_message(&chunk_message(addr, "chunked", &data, 0, 4));
+
+            let chunk = MessageStoreChunk {
+                key: String::from("chunked"),
+                hash: hashlib::get_file_hash(&data),
+                size: data.len() as u64,
+                offset: u64::MAX - 1,
+                data: vec![1, 2, 3, 4],
+                is_last: false,
+            };
+            let payload = Payload { from: addr.to_string(), msg_type: MessageType::StoreChunk, msg: chunk.to_buffer(WireFormat::Bincode).unwrap() };
+            server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+            assert_eq!(server.store.used_bytes(), 0);
+            assert!(ChunkBuffer::default().add(u64::MAX, 1, true).is_err());
+            let _ = server.store.clear();
+        }
+
+        #[test]
+        fn test_chunks_bounded() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10092));
+            let mut opts = make_opts("test_store_chunks_bounded", "127.0.0.1:0");
+            opts.store_opts.max_file_size = Some(1 << 40);
+            let server = FileServer::new(opts).unwrap();
+            add_mock_peer(&server, addr);
+            let send_chunk = |key: &str, size: u64, offset: u64, data: Vec<u8>| {
+                let chunk = MessageStoreChunk { key: key.to_string(), hash: String::new(), size, offset, data, is_last: false };
+                let payload = Payload { from: addr.to_string(), msg_type: MessageType::StoreChunk, msg: chunk.to_buffer(WireFormat::Bincode).unwrap() };
+                server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+            };
+
+            // a file larger than the store takes is refused before anything is written
+            send_chunk("too_large", (1 << 40) + 1, 0, vec![1, 2, 3, 4]);
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+            // a chunk past the size of the file, or far ahead of what has been received, aborts the transfer
+            send_chunk("past_the_end", 10, 8, vec![1, 2, 3, 4]);
+            send_chunk("far_ahead", 1 << 40, CHUNK_WINDOW + 1, vec![1, 2, 3, 4]);
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+            assert_eq!(server.store.used_bytes(), 0);
+
+            // only so many transfers at once
+            for i in 0..MAX_CHUNK_TRANSFERS {
+                send_chunk(&format!("file_{}", i), 2, 0, vec![1]);
+            }
+            send_chunk("one_too_many", 2, 0, vec![1]);
+            let buffers = server.chunk_buffers.lock().unwrap();
+            assert_eq!(buffers.len(), MAX_CHUNK_TRANSFERS);
+            assert!(!buffers.contains_key(&(addr, String::from("one_too_many"))));
+            drop(buffers);
+            assert_eq!(server.store.used_bytes(), MAX_CHUNK_TRANSFERS as u64);
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_stale_transfer_dropped() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10089));
+            let server = make_server("test_store_chunks_stale");
+            add_mock_peer(&server, addr);
+            let data: Vec<u8> = (0..10).collect();
+            server.handle_message(&chunk_message(addr, "abandoned", &data, 0, 4));
+            assert_eq!(server.drop_stale_transfers(), 0);
+            assert_eq!(server.store.used_bytes(), 4);
+
+            // the sender went away long ago
+            for buffer in server.chunk_buffers.lock().unwrap().values_mut() {
+                buffer.last_chunk = Instant::now().checked_sub(CHUNK_TRANSFER_TIMEOUT);
+            }
+            assert_eq!(server.drop_stale_transfers(), 1);
+            assert!(server.chunk_buffers.lock().unwrap().is_empty());
+            assert_eq!(server.store.used_bytes(), 0);
+
+            // a late chunk starts over rather than completing the file
+            server.handle_message(&chunk_message(addr, "abandoned", &data, 4, 10));
+            assert!(server.store.read(String::from("abandoned")).is_err());
+            let _ = server.store.clear();
+        }
+
+        #[test]
+        fn test_store_in_chunks() {
+            let server_a = make_server("test_store_in_chunks_a");
+            let server_b = make_server("test_store_in_chunks_b");
+            connect_servers(&server_a, &server_b);
+            let key = String::from("big_file");
+            let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+
+            server_a.store_in_chunks(key.clone(), &mut data.as_slice(), 1024).unwrap();
+
+            assert!(wait_until(|| server_b.store.read(key.clone()).is_ok()));
+            assert_eq!(server_b.store.read(key).unwrap(), data);
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_in_chunks_resumes() {
+            let addr_b = SocketAddr::from(([127, 0, 0, 1], 10068));
+            let addr_a = SocketAddr::from(([127, 0, 0, 1], 10069));
+            let spoofer = SocketAddr::from(([127, 0, 0, 1], 10070));
+            let server_a = make_server("test_store_resume_a");
+            let server_b = make_server("test_store_resume_b");
+            let sent_to_b = add_mock_peer(&server_a, addr_b);
+            let sent_to_a = add_mock_peer(&server_b, addr_a);
+            let sent_to_spoofer = add_mock_peer(&server_b, spoofer);
+            let key = String::from("resumed_file");
+            let data: Vec<u8> = (0..10).collect();
+
+            // wait for the next message sent to the mock peer
+            let next_sent = |sent: &Arc<Mutex<Vec<Vec<u8>>>>| {
+                assert!(wait_until(|| !sent.lock().unwrap().is_empty()));
+                sent.lock().unwrap().remove(0)
+            };
+            // store the data from a to b, relaying the messages by hand and returning the chunks a has sent
+            let store = |data: &[u8], sent_to_a: &Arc<Mutex<Vec<Vec<u8>>>>| {
+                let sender = server_a.clone();
+                let (key, data) = (key.clone(), data.to_vec());
+                let handle = thread::spawn(move || sender.store_in_chunks(key, &mut data.as_slice(), 4).unwrap());
+                server_b.handle_message(&Message { from: addr_a, payload: next_sent(&sent_to_b) });
+                server_a.handle_message(&Message { from: addr_b, payload: next_sent(sent_to_a) });
+                handle.join().unwrap();
+                sent_to_b.lock().unwrap().drain(..).collect::<Vec<_>>()
+            };
+            let offsets = |chunks: &[Vec<u8>]| -> Vec<u64> {
+                chunks
+                    .iter()
+                    .map(|frame| {
+                        let payload = Payload::from_frame(addr_a, frame, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+                        MessageStoreChunk::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().offset
+                    })
+                    .collect()
+            };
+
+            // the connection drops after the first chunk
+            let chunks = store(&data, &sent_to_a);
+            assert_eq!(chunks.len(), 3);
+            server_b.handle_message(&Message { from: addr_a, payload: chunks[0].clone() });
+            server_b.remove_peer(addr_a);
+            assert!(server_b.store.read(key.clone()).is_err());
+
+            // another peer claiming to be a neither adds to the transfer of a nor learns where it stands
+            server_b.handle_message(&Message { from: spoofer, payload: chunks[1].clone() });
+            assert_eq!(server_b.chunk_buffers.lock().unwrap()[&(addr_a, key.clone())].ranges.len(), 1);
+            let resume = Payload {
+                from: addr_a.to_string(),
+                msg_type: MessageType::StoreResume,
+                msg: MessageStoreResume { key: key.clone(), hash: hashlib::get_file_hash(&data), size: 10 }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            server_b.handle_message(&Message { from: spoofer, payload: resume.to_frame(WireFormat::Bincode).unwrap() });
+            let reply = Payload::from_frame(addr_b, &next_sent(&sent_to_spoofer), WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(MessageResumePoint::from_buffer(&reply.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().offset, 0);
+
+            // a reconnects and only sends what b is missing
+            let sent_to_a = add_mock_peer(&server_b, addr_a);
+            let chunks = store(&data, &sent_to_a);
+            assert_eq!(offsets(&chunks), vec![4, 8]);
+            for frame in chunks {
+                server_b.handle_message(&Message { from: addr_a, payload: frame });
+            }
+            assert_eq!(server_b.store.read(key.clone()).unwrap(), data);
+
+            // a transfer of an older version of the file is not finished with the tail of the new one
+            let old: Vec<u8> = (10..20).collect();
+            let new: Vec<u8> = (20..30).collect();
+            let chunks = store(&old, &sent_to_a);
+            server_b.handle_message(&Message { from: addr_a, payload: chunks[0].clone() });
+            let chunks = store(&new, &sent_to_a);
+            assert_eq!(offsets(&chunks), vec![0, 4, 8]);
+            server_b.handle_message(&Message { from: addr_a, payload: chunks[1].clone() });
+            server_b.handle_message(&Message { from: addr_a, payload: chunks[2].clone() });
+            assert_eq!(server_b.store.read(key.clone()).unwrap(), data);
+            server_b.handle_message(&Message { from: addr_a, payload: chunks[0].clone() });
+            assert_eq!(server_b.store.read(key).unwrap(), new);
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_to_single_peer() {
+            let server = make_server("test_store_store_to");
+            let addrs: Vec<SocketAddr> = (10011..10014).map(|port| SocketAddr::from(([127, 0, 0, 1], port))).collect();
+            let sent: Vec<_> = addrs.iter().map(|addr| add_mock_peer(&server, *addr)).collect();
+
+            let key = String::from("placed_file");
+            server.store_to(key.clone(), &mut vec![1, 2, 3, 4].as_slice(), addrs[1]).unwrap();
+
+            assert_eq!(server.store.read(key.clone()).unwrap(), vec![1, 2, 3, 4]);
+            assert!(sent[0].lock().unwrap().is_empty());
+            assert_eq!(sent[1].lock().unwrap().len(), 1);
+            assert!(sent[2].lock().unwrap().is_empty());
+            let payload = Payload::from_frame(addrs[0], &sent[1].lock().unwrap()[0], WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(MessageData::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().key, key);
+
+            // storing to a peer that isn't connected fails without touching the store
+            let unknown = SocketAddr::from(([127, 0, 0, 1], 10019));
+            let res = server.store_to(String::from("not_stored"), &mut vec![1].as_slice(), unknown);
+            assert!(res.is_err());
+            assert!(server.store.read(String::from("not_stored")).is_err());
+
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_placement_for() {
+            let server = make_server("test_store_placement");
+            let self_addr = server.transport.clone().local_addr().unwrap();
+            let addrs: Vec<SocketAddr> = (10031..10034).map(|port| SocketAddr::from(([127, 0, 0, 1], port))).collect();
+            for addr in addrs.iter() {
+                add_mock_peer(&server, *addr);
+            }
+
+            let placement = server.placement_for("placed_file");
+            assert_eq!(placement, server.placement_for("placed_file"));
+            let mut nodes = placement.clone();
+            nodes.sort();
+            let mut expected = addrs.clone();
+            expected.push(self_addr);
+            expected.sort();
+            assert_eq!(nodes, expected);
+
+            // a new node doesn't change the order of the existing ones
+            let newcomer = SocketAddr::from(([127, 0, 0, 1], 10034));
+            add_mock_peer(&server, newcomer);
+            let with_newcomer: Vec<SocketAddr> = server.placement_for("placed_file")
+                .into_iter()
+                .filter(|node| *node != newcomer)
+                .collect();
+            assert_eq!(with_newcomer, placement);
+        }
+
+        #[test]
+        fn test_peer_latency() {
+            let addr_a = SocketAddr::from(([127, 0, 0, 1], 10041));
+            let addr_b = SocketAddr::from(([127, 0, 0, 1], 10042));
+            let server_a = make_server("test_store_latency_a");
+            let server_b = make_server("test_store_latency_b");
+            let sent_to_b = add_mock_peer(&server_a, addr_b);
+            let sent_to_a = add_mock_peer(&server_b, addr_a);
+            assert_eq!(server_a.peer_latency(addr_b), None);
+
+            // deliver the ping and its pong by hand, holding the ping back to simulate a slow link
+            let delay = Duration::from_millis(100);
+            server_a.ping(addr_b).unwrap();
+            thread::sleep(delay);
+            let ping = sent_to_b.lock().unwrap().pop().unwrap();
+            server_b.handle_message(&Message { from: addr_a, payload: ping });
+            let pong = sent_to_a.lock().unwrap().pop().unwrap();
+            server_a.handle_message(&Message { from: addr_b, payload: pong.clone() });
+
+            let latency = server_a.peer_latency(addr_b).unwrap();
+            assert!(latency >= delay && latency < delay * 3, "latency {:?}", latency);
+            // a pong answering no pending ping is ignored
+            server_a.handle_message(&Message { from: addr_b, payload: pong });
+            assert_eq!(server_a.peer_latency(addr_b), Some(latency));
+
+            // the pings never answered are forgotten on the next ping once they are too old
+            let long_ago = Instant::now().checked_sub(PING_TIMEOUT).unwrap();
+            server_a.pending_pings.lock().unwrap().insert((addr_b, u64::MAX), long_ago);
+            server_a.ping(addr_b).unwrap();
+            assert_eq!(server_a.pending_pings.lock().unwrap().len(), 1);
+            assert!(!server_a.pending_pings.lock().unwrap().contains_key(&(addr_b, u64::MAX)));
+
+            // and no more than `MAX_PENDING_PINGS` are waited on at once
+            let now = Instant::now();
+            server_a.pending_pings.lock().unwrap().extend((0..MAX_PENDING_PINGS as u64).map(|nonce| ((addr_a, nonce), now)));
+            assert!(server_a.ping(addr_b).is_err());
+        }
+
+        #[test]
+        fn test_get_data_from_peer() {
+            let server_a = make_server("test_store_get_data_a");
+            let server_b = make_server("test_store_get_data_b");
+            connect_servers(&server_a, &server_b);
+            let key = String::from("remote_file");
+            server_b.store.write(key.clone(), &[1, 2, 3, 4]).unwrap();
+
+            assert_eq!(server_a.get_data(key.clone()).unwrap(), vec![1, 2, 3, 4]);
+            // the file is now held locally
+            assert_eq!(server_a.store.read(key).unwrap(), vec![1, 2, 3, 4]);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_get_data_falls_back_to_next_replica() {
+            let server_a = make_server("test_store_get_fallback_a");
+            let server_b = make_server("test_store_get_fallback_b");
+            let server_c = make_server("test_store_get_fallback_c");
+            connect_servers(&server_a, &server_b);
+            server_c.transport.clone().listen_and_accept().unwrap();
+            let cloned_c = server_c.clone();
+            thread::spawn(move || cloned_c.run().unwrap());
+            server_a.transport.dial(server_c.transport.clone().local_addr().unwrap()).unwrap();
+            assert!(wait_until(|| server_a.peer_addrs().len() == 2 && server_c.peer_addrs().len() == 1));
+
+            // only the replica asked second holds the file
+            let key = String::from("remote_file");
+            let self_addr = server_a.transport.clone().local_addr().unwrap();
+            let replicas: Vec<SocketAddr> = server_a.placement_for(&key).into_iter().filter(|node| *node != self_addr).collect();
+            let holder = [&server_b, &server_c]
+                .into_iter()
+                .find(|server| server.transport.clone().local_addr().unwrap() == replicas[1])
+                .unwrap();
+            holder.store.write(key.clone(), &[1, 2, 3, 4]).unwrap();
+
+            let start = Instant::now();
+            assert_eq!(server_a.get_data(key.clone()).unwrap(), vec![1, 2, 3, 4]);
+            // the first replica said it doesn't hold the file rather than letting the request time out
+            assert!(start.elapsed() < GET_TIMEOUT);
+            assert_eq!(server_a.store.read(key).unwrap(), vec![1, 2, 3, 4]);
+
+            // nobody holds this one
+            assert!(matches!(server_a.get_data(String::from("missing")), Err(DfsError::Store(StoreError::NotFound))));
+
+            for server in [&server_a, &server_b, &server_c] {
+                server.clone().shutdown();
+            }
+            server_a.store.clear().unwrap();
+            holder.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_concurrent_gets_routed_by_request_id() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10074));
+            let server = make_server("test_store_concurrent_gets");
+            let sent = add_mock_peer(&server, addr);
+
+            let handles: Vec<_> = ["first", "second"]
+                .into_iter()
+                .map(|key| {
+                    let server = server.clone();
+                    thread::spawn(move || server.get_data(key.to_string()))
+                })
+                .collect();
+            assert!(wait_until(|| sent.lock().unwrap().len() == 2));
+
+            // answer in the reverse order, each with the bytes of its own key
+            let requests: Vec<MessageGet> = sent.lock().unwrap()
+                .iter()
+                .map(|frame| {
+                    let payload = Payload::from_frame(addr, frame, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+                    MessageGet::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap()
+                })
+                .collect();
+            assert_ne!(requests[0].request_id, requests[1].request_id);
+            for req in requests.into_iter().rev() {
+                let data = req.key.as_bytes().to_vec();
+                let msg = MessageGetResponse { request_id: req.request_id, key: req.key, offset: 0, data, is_last: true, meta: None };
+                let payload = Payload {
+                    from: addr.to_string(),
+                    msg_type: MessageType::GetResponse,
+                    msg: msg.to_buffer(WireFormat::Bincode).unwrap(),
+                };
+                server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+            }
+
+            let fetched: Vec<Vec<u8>> = handles.into_iter().map(|handle| handle.join().unwrap().unwrap()).collect();
+            assert_eq!(fetched, vec![b"first".to_vec(), b"second".to_vec()]);
+            assert!(server.pending_gets.lock().unwrap().is_empty());
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_get_range_only_from_asked_peer() {
+            let addr_a = SocketAddr::from(([127, 0, 0, 1], 10090));
+            let addr_b = SocketAddr::from(([127, 0, 0, 1], 10091));
+            let server = make_server("test_store_get_range_asked");
+            let sent_to_a = add_mock_peer(&server, addr_a);
+            let sent_to_b = add_mock_peer(&server, addr_b);
+            let key = String::from("large_file");
+            server.store.write(key.clone(), &[0; 4]).unwrap();
+            let range_data = |request_id: RequestId, data: Option<Vec<u8>>| Payload {
+                from: String::new(),
+                msg_type: MessageType::RangeData,
+                msg: MessageRangeData { request_id, key: key.clone(), offset: 4, data }.to_buffer(WireFormat::Bincode).unwrap(),
+            }.to_frame(WireFormat::Bincode).unwrap();
+
+            // a range nobody asked for is dropped
+            server.handle_message(&Message { from: addr_a, payload: range_data(0, Some(vec![9; 4])) });
+            assert_eq!(server.store.read(key.clone()).unwrap(), vec![0; 4]);
+
+            let getter = server.clone();
+            let handle = thread::spawn(move || getter.get_range(String::from("large_file"), 4, 4));
+            assert!(wait_until(|| sent_to_a.lock().unwrap().len() + sent_to_b.lock().unwrap().len() == 1));
+            let (asked, other, sent) = match sent_to_a.lock().unwrap().pop() {
+                Some(frame) => (addr_a, addr_b, frame),
+                None => (addr_b, addr_a, sent_to_b.lock().unwrap().pop().unwrap()),
+            };
+            let payload = Payload::from_frame(asked, &sent, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            let req = MessageGetRange::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+
+            // only the replica asked may answer, and only with the request id it was sent
+            server.handle_message(&Message { from: other, payload: range_data(req.request_id, Some(vec![9; 4])) });
+            server.handle_message(&Message { from: asked, payload: range_data(req.request_id + 1, Some(vec![9; 4])) });
+            server.handle_message(&Message { from: asked, payload: range_data(req.request_id, Some(vec![1, 2, 3, 4])) });
+            assert_eq!(handle.join().unwrap().unwrap(), vec![1, 2, 3, 4]);
+            assert_eq!(server.store.read(key.clone()).unwrap(), vec![0, 0, 0, 0, 1, 2, 3, 4]);
+            assert!(server.pending_ranges.lock().unwrap().is_empty());
+
+            // the replica doesn't hold the file
+            let getter = server.clone();
+            let handle = thread::spawn(move || getter.get_range(String::from("large_file"), 8, 4));
+            assert!(wait_until(|| !server.pending_ranges.lock().unwrap().is_empty()));
+            let request_id = *server.pending_ranges.lock().unwrap().keys().next().unwrap();
+            let payload = Payload {
+                from: String::new(),
+                msg_type: MessageType::RangeData,
+                msg: MessageRangeData { request_id, key: key.clone(), offset: 8, data: None }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            server.handle_message(&Message { from: asked, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+            // the other replica is asked next, and doesn't hold it either
+            assert!(wait_until(|| {
+                let pending_ranges = server.pending_ranges.lock().unwrap();
+                pending_ranges.values().any(|pending| pending.peer == other)
+            }));
+            let request_id = *server.pending_ranges.lock().unwrap().keys().next().unwrap();
+            let payload = Payload {
+                from: String::new(),
+                msg_type: MessageType::RangeData,
+                msg: MessageRangeData { request_id, key: key.clone(), offset: 8, data: None }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            server.handle_message(&Message { from: other, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+            assert!(matches!(handle.join().unwrap(), Err(DfsError::Store(StoreError::NotFound))));
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_get_response_streamed_in_chunks() {
+            let addr_a = SocketAddr::from(([127, 0, 0, 1], 10075));
+            let addr_b = SocketAddr::from(([127, 0, 0, 1], 10076));
+            let server_a = make_server("test_store_get_streamed_a");
+            let server_b = make_server("test_store_get_streamed_b");
+            let sent_to_b = add_mock_peer(&server_a, addr_b);
+            let sent_to_a = add_mock_peer(&server_b, addr_a);
+            let key = String::from("large_file");
+            let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+            server_a.store.write(key.clone(), &data).unwrap();
+
+            // b asks a for the file, relaying the messages by hand
+            let getter = server_b.clone();
+            let handle = thread::spawn(move || getter.get_data(String::from("large_file")));
+            assert!(wait_until(|| sent_to_a.lock().unwrap().len() == 1));
+            let request = sent_to_a.lock().unwrap().remove(0);
+            server_a.handle_message(&Message { from: addr_b, payload: request });
+
+            // a sends the file in pieces no larger than the chunk size
+            let frames: Vec<Vec<u8>> = sent_to_b.lock().unwrap().drain(..).collect();
+            assert_eq!(frames.len(), data.len().div_ceil(GET_RESPONSE_CHUNK_SIZE));
+            for (i, frame) in frames.iter().enumerate() {
+                let payload = Payload::from_frame(addr_a, frame, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+                let piece = MessageGetResponse::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+                assert_eq!(piece.offset, (i * GET_RESPONSE_CHUNK_SIZE) as u64);
+                assert!(piece.data.len() <= GET_RESPONSE_CHUNK_SIZE);
+                assert_eq!(piece.is_last, i == frames.len() - 1);
+            }
+            for frame in frames {
+                server_b.handle_message(&Message { from: addr_a, payload: frame });
+            }
+
+            assert_eq!(handle.join().unwrap().unwrap(), data);
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_get_response_bounded() {
+            let addr_a = SocketAddr::from(([127, 0, 0, 1], 10093));
+            let addr_b = SocketAddr::from(([127, 0, 0, 1], 10094));
+            let server_a = make_server("test_store_get_bounded_a");
+            let mut opts = make_opts("test_store_get_bounded_b", "127.0.0.1:0");
+            opts.max_get_size = 100_000;
+            let server_b = FileServer::new(opts).unwrap();
+            let sent_to_b = add_mock_peer(&server_a, addr_b);
+            let sent_to_a = add_mock_peer(&server_b, addr_a);
+            let key = String::from("large_file");
+            server_a.store.write(key.clone(), &vec![7; 200_000]).unwrap();
+
+            let getter = server_b.clone();
+            let handle = thread::spawn(move || getter.get_data(String::from("large_file")));
+            assert!(wait_until(|| sent_to_a.lock().unwrap().len() == 1));
+            let request = sent_to_a.lock().unwrap().remove(0);
+            server_a.handle_message(&Message { from: addr_b, payload: request });
+
+            // the request is given up on once the pieces go over the limit, and the rest is dropped
+            for frame in sent_to_b.lock().unwrap().drain(..) {
+                server_b.handle_message(&Message { from: addr_a, payload: frame });
+            }
+            assert!(matches!(handle.join().unwrap(), Err(DfsError::Store(StoreError::NotFound))));
+            assert!(server_b.pending_gets.lock().unwrap().is_empty());
+            assert!(server_b.store.read(key).is_err());
+            server_a.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_bye_removes_peer() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10077));
+            let server = make_server("test_store_bye");
+            add_mock_peer(&server, addr);
+            let bye = Payload { from: addr.to_string(), msg_type: MessageType::Bye, msg: Vec::new() };
+
+            server.handle_message(&Message { from: addr, payload: bye.to_frame(WireFormat::Bincode).unwrap() });
+            assert!(server.peer_addrs().is_empty());
+            assert!(!server.ring.read().unwrap().contains(&addr.to_string()));
+            // the connection closing afterwards is expected, unlike one that drops without a bye
+            assert!(server.handle_peer_disconnect(addr));
+            assert!(!server.handle_peer_disconnect(addr));
+
+            // a node shutting down says bye to its peers, which let it go right away
+            let server_a = make_server("test_store_bye_a");
+            let server_b = make_server("test_store_bye_b");
+            connect_servers(&server_a, &server_b);
+            server_a.clone().shutdown();
+            assert!(wait_until(|| server_b.peer_addrs().is_empty() && server_a.peer_addrs().is_empty()));
+            // the departure is forgotten once the connection is closed, which happens after the peer is removed
+            assert!(wait_until(|| server_b.departed.lock().unwrap().is_empty()));
+            server_b.clone().shutdown();
+        }
+
+        /// poll the condition until it holds or a few seconds have passed
+        fn wait_until(cond: impl Fn() -> bool) -> bool {
+            for _ in 0..50 {
+                if cond() {
+                    return true;
+                }
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+            cond()
+        }
+
+        #[test]
+        fn test_peer_removed_on_disconnect() {
+            let server = make_server("test_store_disconnect");
+            server.transport.clone().listen_and_accept().unwrap();
+            let listen_addr = server.transport.clone().local_addr().unwrap();
+
+            let conn = std::net::TcpStream::connect(listen_addr).unwrap();
+            let peer_addr = conn.local_addr().unwrap();
+            assert!(wait_until(|| server.peer_addrs().contains(&peer_addr)));
+
+            conn.shutdown(std::net::Shutdown::Both).unwrap();
+            assert!(wait_until(|| !server.peer_addrs().contains(&peer_addr)));
+        }
+
+        #[test]
+        fn test_bootstrap_node_redialed() {
+            let bootstrap = make_server("test_store_redial_bootstrap");
+            bootstrap.transport.clone().listen_and_accept().unwrap();
+            let bootstrap_addr = bootstrap.transport.clone().local_addr().unwrap();
+            let b = bootstrap.clone();
+            thread::spawn(move || b.run().unwrap());
+
+            let mut opts = make_opts("test_store_redial", "127.0.0.1:0");
+            opts.bootstrap_node = vec![bootstrap_addr];
+            opts.reconnect_interval = Some(Duration::from_millis(200));
+            let server = FileServer::new(opts).unwrap();
+            let s = server.clone();
+            thread::spawn(move || s.start().unwrap());
+            // the dialing side registers the peer before the accepting side does
+            assert!(wait_until(|| server.peer_addrs() == vec![bootstrap_addr] && bootstrap.peer_addrs().len() == 1));
+
+            // the bootstrap node drops the connection, e.g. as it restarts
+            let inbound = bootstrap.peer_addrs()[0];
+            bootstrap.peers.read().unwrap()[&inbound].read().unwrap().close().unwrap();
+            assert!(wait_until(|| !bootstrap.peer_addrs().contains(&inbound)));
+
+            // the connection comes back from a new port
+            assert!(wait_until(|| bootstrap.peer_addrs().len() == 1 && bootstrap.peer_addrs()[0] != inbound));
+            assert!(wait_until(|| server.peer_addrs() == vec![bootstrap_addr]));
+            server.shutdown();
+            bootstrap.shutdown();
+        }
+
+        #[test]
+        fn test_bootstrap_network_blocking() {
+            let bootstrap = make_server("test_store_join_bootstrap");
+            bootstrap.transport.clone().listen_and_accept().unwrap();
+            let bootstrap_addr = bootstrap.transport.clone().local_addr().unwrap();
+            let b = bootstrap.clone();
+            thread::spawn(move || b.run().unwrap());
+            // nothing listens there anymore
+            let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+            let mut opts = make_opts("test_store_join", "127.0.0.1:0");
+            opts.bootstrap_node = vec![bootstrap_addr];
+            let server = FileServer::new(opts).unwrap();
+            let start = Instant::now();
+            assert_eq!(server.bootstrap_network_blocking(Duration::from_secs(5)), vec![bootstrap_addr.to_string()]);
+            // the peer is there as soon as it returns, well before the timeout
+            assert_eq!(server.peer_addrs(), vec![bootstrap_addr]);
+            assert!(start.elapsed() < Duration::from_secs(5));
+
+            // a node that can't be reached holds it up until the timeout, the others are still returned
+            let mut opts = make_opts("test_store_join_partial", "127.0.0.1:0");
+            opts.bootstrap_node = vec![bootstrap_addr, unreachable];
+            let partial = FileServer::new(opts).unwrap();
+            let start = Instant::now();
+            assert_eq!(partial.bootstrap_network_blocking(Duration::from_millis(500)), vec![bootstrap_addr.to_string()]);
+            assert!(start.elapsed() >= Duration::from_millis(500));
+
+            server.shutdown();
+            partial.shutdown();
+            bootstrap.shutdown();
+        }
+
+        #[test]
+        fn test_start_background() {
+            let handle = make_server("test_store_background").start_background();
+            let key = String::from("background");
+            handle.server().store_data(key.clone(), &mut vec![1, 2, 3].as_slice()).unwrap();
+            assert_eq!(handle.server().get_data(key).unwrap(), vec![1, 2, 3]);
+
+            handle.server().store.clear().unwrap();
+            handle.shutdown();
+            assert!(handle.join().is_ok());
+        }
+
+        #[test]
+        fn test_blocking_consume() {
+            let transport = Arc::new(DelayedTransport::new(make_tcp_transport("127.0.0.1:0")));
+            let mut opts = make_opts_with("test_store_blocking_consume", transport.clone());
+            opts.blocking_consume = true;
+            let handle = FileServer::new(opts).unwrap().start_background();
+
+            // idle for longer than a polling `consume` waits, the loop is still in its first call
+            thread::sleep(Duration::from_millis(1500));
+            assert_eq!(transport.consumes.load(Ordering::SeqCst), 1);
+
+            let start = Instant::now();
+            handle.shutdown();
+            assert!(handle.join().is_ok());
+            assert!(start.elapsed() < Duration::from_millis(500), "stopped in {:?}", start.elapsed());
+            assert_eq!(transport.consumes.load(Ordering::SeqCst), 1);
+        }
+
+        #[test]
+        fn test_quorum_ack() {
+            let mut opts = make_opts("test_store_quorum_ack", "127.0.0.1:0");
+            opts.consistency = Consistency::QuorumAck;
+            let server = FileServer::new(opts).unwrap();
+            let b = SocketAddr::from(([127, 0, 0, 1], 10083));
+            let c = SocketAddr::from(([127, 0, 0, 1], 10084));
+            let sent_to_b = add_mock_peer(&server, b);
+            add_mock_peer(&server, c);
+
+            let key = String::from("acked");
+            let storing = {
+                let server = server.clone();
+                let key = key.clone();
+                thread::spawn(move || server.store_data(key, &mut vec![1, 2, 3].as_slice()))
+            };
+
+            // of the 3 replicas, only this node holds the file until a peer acks it
+            assert!(wait_until(|| !sent_to_b.lock().unwrap().is_empty()));
+            let sent = sent_to_b.lock().unwrap()[0].clone();
+            let payload = Payload::from_frame(server.transport.clone().local_addr().unwrap(), &sent, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            let ack_id = MessageData::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().ack_id.unwrap();
+            thread::sleep(Duration::from_millis(300));
+            assert!(!storing.is_finished());
+
+            let ack = Payload {
+                from: b.to_string(),
+                msg_type: MessageType::StoreAck,
+                msg: MessageStoreAck { ack_id }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            server.handle_message(&Message { from: b, payload: ack.to_frame(WireFormat::Bincode).unwrap() });
+            storing.join().unwrap().unwrap();
+            assert!(server.pending_acks.lock().unwrap().is_empty());
+            assert_eq!(server.store.read(key).unwrap(), vec![1, 2, 3]);
+
+            server.store.clear().unwrap();
+        }
+
+        #[cfg(feature = "async")]
+        #[test]
+        fn test_store_data_async() {
+            let server = make_server("test_store_async");
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let key = String::from("from_async");
+            let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+            runtime.block_on(server.store_data_async(key.clone(), &mut data.as_slice())).unwrap();
+            assert_eq!(server.store.read(key).unwrap(), data);
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_delete_prefix() {
+            let server_a = {
+                let mut opts = make_opts("test_store_delete_prefix_a", "127.0.0.1:0");
+                opts.store_opts.key_index = true;
+                FileServer::new(opts).unwrap()
+            };
+            let server_b = make_server("test_store_delete_prefix_b");
+            connect_servers(&server_a, &server_b);
+            assert!(server_a.delete_prefix("tenant-42/").unwrap().is_empty());
+            let keys = ["tenant-42/a", "tenant-42/nested/b", "tenant-420/c", "tenant-4/d", "other"];
+            for key in keys {
+                server_a.store.write(key.to_string(), &[1, 2, 3]).unwrap();
+                server_b.store.write(key.to_string(), &[1, 2, 3]).unwrap();
+            }
+
+            let mut deleted = server_a.delete_prefix("tenant-42/").unwrap();
+            deleted.sort();
+            assert_eq!(deleted, vec![(String::from("tenant-42/a"), Ok(())), (String::from("tenant-42/nested/b"), Ok(()))]);
+            assert_eq!(server_a.store.list_keys().unwrap(), vec!["other", "tenant-4/d", "tenant-420/c"]);
+
+            // the copies are deleted too, the other keys are left alone
+            assert!(wait_until(|| keys[..2].iter().all(|key| server_b.store.read(key.to_string()).is_err())));
+            for key in keys[2..].iter() {
+                assert!(server_b.store.read(key.to_string()).is_ok());
+            }
+
+            // without the key index, the keys can't be listed
+            assert!(matches!(server_b.delete_prefix("tenant-4"), Err(DfsError::Io(e)) if e.kind() == io::ErrorKind::Unsupported));
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_republish() {
+            let server_a = make_server("test_store_republish_a");
+            let server_b = make_server("test_store_republish_b");
+            connect_servers(&server_a, &server_b);
+
+            // a got the file while b was away
+            let key = String::from("republished");
+            server_a.store.write(key.clone(), &[1, 2, 3]).unwrap();
+            assert!(server_b.store.read(key.clone()).is_err());
+
+            server_a.republish(key.clone()).unwrap();
+            assert!(wait_until(|| server_b.store.read(key.clone()).ok() == Some(vec![1, 2, 3])));
+
+            assert!(matches!(server_a.republish(String::from("missing")), Err(DfsError::Store(StoreError::NotFound))));
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_proxy_node() {
+            let mut opts = make_opts("test_store_proxy", "127.0.0.1:0");
+            opts.store_locally = false;
+            let proxy = FileServer::new(opts).unwrap();
+            let server_b = make_server("test_store_proxy_b");
+            let mut opts = make_opts("test_store_proxy_c", "127.0.0.1:0");
+            opts.consistency = Consistency::QuorumAck;
+            let server_c = FileServer::new(opts).unwrap();
+            // c - proxy - b
+            connect_servers(&proxy, &server_b);
+            server_c.transport.clone().listen_and_accept().unwrap();
+            let c = server_c.clone();
+            thread::spawn(move || c.run().unwrap());
+            server_c.transport.dial(proxy.transport.clone().local_addr().unwrap()).unwrap();
+            assert!(wait_until(|| proxy.peer_addrs().len() == 2 && server_c.peer_addrs().len() == 1));
+
+            proxy.store_data(String::from("from_proxy"), &mut vec![1, 2, 3].as_slice()).unwrap();
+            assert!(wait_until(|| server_b.store.read(String::from("from_proxy")).ok() == Some(vec![1, 2, 3])));
+
+            // stored through the proxy by a node b is not connected to. the proxy acks it once forwarded
+            server_c.store_data(String::from("from_c"), &mut vec![4, 5, 6].as_slice()).unwrap();
+            assert!(wait_until(|| server_b.store.read(String::from("from_c")).ok() == Some(vec![4, 5, 6])));
+
+            // the other ways of storing through the proxy
+            let b_addr = server_b.transport.clone().local_addr().unwrap();
+            let large: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+            proxy.store_in_chunks(String::from("chunked"), &mut large.as_slice(), 1000).unwrap();
+            proxy.store_to(String::from("to_b"), &mut vec![7].as_slice(), b_addr).unwrap();
+            proxy.store_to_tag(String::from("to_tag"), &mut vec![8].as_slice(), "cold").unwrap();
+            proxy.store_to_peers(String::from("to_peers"), &mut vec![9].as_slice(), true).unwrap();
+            assert!(wait_until(|| server_b.store.read(String::from("chunked")).ok() == Some(large.clone())));
+            assert!(wait_until(|| server_b.store.read(String::from("to_b")).ok() == Some(vec![7])));
+            assert!(wait_until(|| server_b.store.read(String::from("to_peers")).ok() == Some(vec![9])));
+
+            // fetched from b every time
+            assert_eq!(proxy.get_data(String::from("from_proxy")).unwrap(), vec![1, 2, 3]);
+            assert!(proxy.store.read(String::from("from_proxy")).is_err());
+            for key in ["from_c", "chunked", "to_b", "to_tag", "to_peers"] {
+                assert!(proxy.store.read(String::from(key)).is_err());
+            }
+            // nothing was ever written to it
+            assert!(!std::path::Path::new("test_store_proxy").exists());
+
+            for server in [&server_b, &server_c] {
+                server.store.clear().unwrap();
+            }
+        }
+
+        #[test]
+        fn test_store_dir() {
+            let src = "test_store_dir_src";
+            fs::create_dir_all(format!("{}/nested", src)).unwrap();
+            fs::write(format!("{}/a.txt", src), [1, 2, 3]).unwrap();
+            fs::write(format!("{}/b.txt", src), [4, 5]).unwrap();
+            fs::write(format!("{}/nested/c.txt", src), [6]).unwrap();
+            let server = make_server("test_store_dir");
+
+            let results = server.store_dir(Path::new(src)).unwrap();
+
+            let keys: Vec<&str> = results.iter().map(|(key, _)| key.as_str()).collect();
+            assert_eq!(keys, vec!["a.txt", "b.txt", "nested/c.txt"]);
+            assert!(results.iter().all(|(_, res)| res.is_ok()));
+            assert_eq!(server.store.read(String::from("a.txt")).unwrap(), vec![1, 2, 3]);
+            assert_eq!(server.store.read(String::from("b.txt")).unwrap(), vec![4, 5]);
+            assert_eq!(server.store.read(String::from("nested/c.txt")).unwrap(), vec![6]);
+
+            assert!(server.store_dir(Path::new("test_store_dir_missing")).is_err());
+            server.store.clear().unwrap();
+            fs::remove_dir_all(src).unwrap();
+        }
+
+        #[test]
+        fn test_debug_encode_store() {
+            let mut opts = make_opts("test_store_debug_encode", "127.0.0.1:0");
+            opts.gossip_hops = 2;
+            let server = FileServer::new(opts).unwrap();
+            let peer = SocketAddr::from(([127, 0, 0, 1], 10086));
+            let sent = add_mock_peer(&server, peer);
+            let key = String::from("inspected");
+
+            let frame = server.debug_encode_store(key.clone(), &[1, 2, 3]).unwrap();
+            let payload = Payload::from_frame(peer, &frame, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert!(matches!(payload.msg_type, MessageType::Store));
+            let msg_data = MessageData::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(msg_data.key, key);
+            assert_eq!(msg_data.data, vec![1, 2, 3]);
+            assert!(msg_data.meta.is_none() && msg_data.ack_id.is_none());
+            let gossip = msg_data.gossip.unwrap();
+            assert_eq!((gossip.id, gossip.hops_left), (0, 2));
+            // nothing sent or stored
+            assert!(sent.lock().unwrap().is_empty());
+            assert!(server.store.read(key.clone()).is_err());
+
+            // the very bytes the peer gets
+            server.store_data(key.clone(), &mut vec![1, 2, 3].as_slice()).unwrap();
+            assert_eq!(sent.lock().unwrap().clone(), vec![frame]);
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_to_tag() {
+            let server = make_server("test_store_to_tag");
+            let cold = SocketAddr::from(([127, 0, 0, 1], 10063));
+            let edge = SocketAddr::from(([127, 0, 0, 1], 10064));
+            let sent_to_cold = add_tagged_mock_peer(&server, cold, &["cold", "eu"]);
+            let sent_to_edge = add_tagged_mock_peer(&server, edge, &["edge", "eu"]);
+            assert_eq!(server.peers_with_tag("cold"), vec![cold]);
+
+            let key = String::from("archived");
+            server.store_to_tag(key.clone(), &mut vec![1, 2, 3].as_slice(), "cold").unwrap();
+
+            assert_eq!(server.store.read(key.clone()).unwrap(), vec![1, 2, 3]);
+            assert!(sent_to_edge.lock().unwrap().is_empty());
+            let sent = sent_to_cold.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let payload = Payload::from_frame(server.transport.clone().local_addr().unwrap(), &sent[0], WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(MessageData::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().key, key);
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_broadcast_outbound() {
+            let server = make_server("test_store_broadcast_outbound");
+            let dialed = SocketAddr::from(([127, 0, 0, 1], 10078));
+            let dialing = SocketAddr::from(([127, 0, 0, 1], 10079));
+            let sent_to_dialed = add_outbound_mock_peer(&server, dialed);
+            let sent_to_dialing = add_mock_peer(&server, dialing);
+            let ping = || Payload {
+                from: server.transport.clone().addr(),
+                msg_type: MessageType::Ping,
+                msg: MessagePing { nonce: 1 }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+
+            server.broadcast_outbound(ping());
+            assert_eq!(sent_to_dialed.lock().unwrap().len(), 1);
+            assert!(sent_to_dialing.lock().unwrap().is_empty());
+
+            server.broadcast_inbound(ping());
+            assert_eq!(sent_to_dialed.lock().unwrap().len(), 1);
+            assert_eq!(sent_to_dialing.lock().unwrap().len(), 1);
+
+            // the file goes the same way
+            server.store_to_peers(String::from("gossip"), &mut vec![1, 2].as_slice(), true).unwrap();
+            assert_eq!(sent_to_dialed.lock().unwrap().len(), 2);
+            assert_eq!(sent_to_dialing.lock().unwrap().len(), 1);
+            server.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_gossip_reaches_nodes_beyond_peers() {
+            // a - b - c, a and c are not connected
+            let servers: Vec<Arc<FileServer<TcpTransport>>> = ["a", "b", "c"].iter()
+                .map(|name| {
+                    let mut opts = make_opts(&format!("test_store_gossip_{}", name), "127.0.0.1:0");
+                    opts.gossip_hops = 2;
+                    FileServer::new(opts).unwrap()
+                })
+                .collect();
+            for server in servers.iter() {
+                server.transport.clone().listen_and_accept().unwrap();
+                let server = server.clone();
+                thread::spawn(move || server.run().unwrap());
+            }
+            let addr_b = servers[1].transport.clone().local_addr().unwrap();
+            servers[0].transport.dial(addr_b).unwrap();
+            servers[2].transport.dial(addr_b).unwrap();
+            assert!(wait_until(|| servers[1].peer_addrs().len() == 2));
+
+            let key = String::from("gossiped");
+            servers[0].store_data(key.clone(), &mut vec![1, 2, 3].as_slice()).unwrap();
+            assert!(wait_until(|| servers[2].store.read(key.clone()).is_ok()));
+            assert_eq!(servers[2].store.read(key).unwrap(), vec![1, 2, 3]);
+            for server in servers {
+                server.store.clear().unwrap();
+                server.shutdown();
+            }
+        }
+
+        #[test]
+        fn test_gossip_passed_on_once() {
+            let server = make_server("test_store_gossip_once");
+            let left = SocketAddr::from(([127, 0, 0, 1], 10080));
+            let right = SocketAddr::from(([127, 0, 0, 1], 10081));
+            let sent_to_left = add_mock_peer(&server, left);
+            let sent_to_right = add_mock_peer(&server, right);
+            let store = |from: SocketAddr, hops_left: u8| {
+                let gossip = Gossip { origin: String::from("127.0.0.1:10082"), id: 1, hops_left };
+                let msg = MessageData { key: String::from("looping"), data: vec![1], meta: None, gossip: Some(gossip), ack_id: None };
+                let payload = Payload { from: from.to_string(), msg_type: MessageType::Store, msg: msg.to_buffer(WireFormat::Bincode).unwrap() };
+                Message { from, payload: payload.to_frame(WireFormat::Bincode).unwrap() }
+            };
+
+            // passed on to the other peer only, with one hop less
+            server.handle_message(&store(left, 1));
+            assert!(sent_to_left.lock().unwrap().is_empty());
+            let sent = sent_to_right.lock().unwrap().clone();
+            assert_eq!(sent.len(), 1);
+            let payload = Payload::from_frame(right, &sent[0], WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(MessageData::from_buffer(&payload.msg, WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().gossip.unwrap().hops_left, 0);
+
+            // coming back around a loop, it is dropped
+            server.store.delete(String::from("looping")).unwrap();
+            server.handle_message(&store(right, 1));
+            assert!(sent_to_left.lock().unwrap().is_empty());
+            assert!(server.store.read(String::from("looping")).is_err());
+        }
+
+        #[test]
+        fn test_oversized_message_rejected() {
+            // a store message larger than any payload the transport would accept
+            let msg = MessageData { key: String::from("bomb"), data: vec![0; MAX_MESSAGE_SIZE as usize], meta: None, gossip: None, ack_id: None };
+            let res = MessageData::from_buffer(&msg.to_buffer(WireFormat::Bincode).unwrap(), WireFormat::Bincode, MAX_MESSAGE_SIZE);
+            assert!(matches!(res, Err(e) if matches!(*e, bincode::ErrorKind::SizeLimit)));
+
+            // a key claiming to be longer than the limit is rejected before it is read
+            let mut buf = [&[DATA_EMBEDDED][..], &(MAX_MESSAGE_SIZE + 1).to_le_bytes()].concat();
+            buf.extend_from_slice(b"bomb");
+            let res = MessageData::from_buffer(&buf, WireFormat::Bincode, MAX_MESSAGE_SIZE);
+            assert!(matches!(res, Err(e) if matches!(*e, bincode::ErrorKind::SizeLimit)));
+
+            // messages within the limit still decode
+            let msg = MessageData { key: String::from("fine"), data: vec![1, 2, 3], meta: None, gossip: None, ack_id: None };
+            assert_eq!(MessageData::from_buffer(&msg.to_buffer(WireFormat::Bincode).unwrap(), WireFormat::Bincode, MAX_MESSAGE_SIZE).unwrap().data, vec![1, 2, 3]);
+        }
+
+        #[test]
+        fn test_message_limit_follows_transport() {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(TypedFrameDecoder { max_payload_size: 1024, priority_types: Vec::new() }));
+            opts.encoder = Arc::new(TypedFrameEncoder {});
+            let server = FileServer::new(make_opts_with("test_store_message_limit", TcpTransport::new(opts).unwrap())).unwrap();
+            assert_eq!(server.max_message_size, 1024);
+
+            // a message larger than the transport accepts is rejected, even below the default limit
+            let msg = MessageData { key: "k".repeat(2048), data: Vec::new(), meta: None, gossip: None, ack_id: None };
+            let buf = msg.to_buffer(WireFormat::Bincode).unwrap();
+            let res = MessageData::from_buffer(&buf, WireFormat::Bincode, server.max_message_size);
+            assert!(matches!(res, Err(e) if matches!(*e, bincode::ErrorKind::SizeLimit)));
+            assert!(MessageData::from_buffer(&buf, WireFormat::Bincode, MAX_MESSAGE_SIZE).is_ok());
+        }
+
+        #[test]
+        fn test_large_store_data_sent_raw() {
+            let data: Vec<u8> = (0..=255).cycle().take(100 * 1024).collect();
+            for format in [WireFormat::Bincode, WireFormat::Json] {
+                let msg = MessageData { key: String::from("large"), data: data.clone(), meta: None, gossip: None, ack_id: None };
+                let buf = msg.to_buffer(format).unwrap();
+
+                // the data follows the other fields as it is, rather than being serialized with them
+                assert_eq!(buf[0], DATA_RAW);
+                assert!(buf.ends_with(&data));
+                assert!(buf.len() < data.len() + 100, "{} bytes", buf.len());
+                let decoded = MessageData::from_buffer(&buf, format, MAX_MESSAGE_SIZE).unwrap();
+                assert_eq!((decoded.key, decoded.data), (String::from("large"), data.clone()));
+
+                // small messages keep it embedded
+                let msg = MessageData { key: String::from("small"), data: vec![1, 2, 3], meta: None, gossip: None, ack_id: None };
+                let buf = msg.to_buffer(format).unwrap();
+                assert_eq!(buf[0], DATA_EMBEDDED);
+                assert_eq!(MessageData::from_buffer(&buf, format, MAX_MESSAGE_SIZE).unwrap().data, vec![1, 2, 3]);
+            }
+
+            // a header claiming more than what follows is rejected
+            let buf = [&[DATA_RAW][..], &100u32.to_be_bytes(), b"short"].concat();
+            assert!(MessageData::from_buffer(&buf, WireFormat::Bincode, MAX_MESSAGE_SIZE).is_err());
+        }
+
+        #[test]
+        fn test_payload_wire_formats() {
+            for format in [WireFormat::Bincode, WireFormat::Json] {
+                let msg = MessageGet { request_id: 7, key: String::from("some_key") }.to_buffer(format).unwrap();
+                let payload = Payload { from: String::from("127.0.0.1:10065"), msg_type: MessageType::Get, msg };
+
+                let decoded = Payload::from_buffer(&payload.to_buffer(format).unwrap(), format, MAX_MESSAGE_SIZE).unwrap();
+                assert_eq!(decoded.from, payload.from);
+                assert!(matches!(decoded.msg_type, MessageType::Get));
+                assert_eq!(MessageGet::from_buffer(&decoded.msg, format, MAX_MESSAGE_SIZE).unwrap().key, "some_key");
+            }
+
+            // json is readable on the wire, and the formats don't decode each other
+            let buf = MessageGet { request_id: 7, key: String::from("some_key") }.to_buffer(WireFormat::Json).unwrap();
+            assert_eq!(String::from_utf8(buf.clone()).unwrap(), r#"{"request_id":7,"key":"some_key"}"#);
+            assert!(MessageGet::from_buffer(&buf, WireFormat::Bincode, MAX_MESSAGE_SIZE).is_err());
+        }
+
+        #[test]
+        fn test_store_over_json() {
+            let mut opts_a = make_opts("test_store_json_a", "127.0.0.1:0");
+            opts_a.wire_format = WireFormat::Json;
+            let mut opts_b = make_opts("test_store_json_b", "127.0.0.1:0");
+            opts_b.wire_format = WireFormat::Json;
+            let server_a = FileServer::new(opts_a).unwrap();
+            let server_b = FileServer::new(opts_b).unwrap();
+            connect_servers(&server_a, &server_b);
+            let key = String::from("json_file");
+
+            server_a.store_data(key.clone(), &mut vec![1, 2, 3, 4].as_slice()).unwrap();
+
+            assert!(wait_until(|| server_b.store.read(key.clone()).is_ok()));
+            assert_eq!(server_b.store.read(key.clone()).unwrap(), vec![1, 2, 3, 4]);
+
+            server_a.clone().shutdown();
+            server_b.clone().shutdown();
+            server_a.store.clear().unwrap();
+            server_b.store.clear().unwrap();
+        }
+
+        #[test]
+        fn test_store_message_with_escaping_key_rejected() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10067));
+            let server = make_server("test_store_escaping_key");
+            add_mock_peer(&server, addr);
+            let payload = Payload {
+                from: addr.to_string(),
+                msg_type: MessageType::Store,
+                msg: MessageData { key: String::from("../test_store_escaped_by_peer"), data: vec![1, 2, 3], meta: None, gossip: None, ack_id: None }
+                    .to_buffer(WireFormat::Bincode)
+                    .unwrap(),
+            };
+
+            server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+
+            assert!(!std::path::Path::new("test_store_escaped_by_peer").exists());
+        }
+
+        #[test]
+        fn test_malformed_payload_is_skipped() {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 10003));
+            let server = make_server("test_store_malformed");
+            add_mock_peer(&server, addr);
+
+            // garbage that isn't a payload at all
+            server.handle_message(&Message { from: addr, payload: vec![0xff; 7] });
+            // a valid payload wrapping a truncated store message
+            let payload = Payload {
+                from: addr.to_string(),
+                msg_type: MessageType::Store,
+                msg: vec![1, 2, 3],
+            };
+            server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+
+            // the server keeps handling well-formed messages afterwards
+            let key = String::from("after_garbage");
+            let payload = Payload {
+                from: addr.to_string(),
+                msg_type: MessageType::Store,
+                msg: MessageData { key: key.clone(), data: vec![1, 2, 3, 4], meta: None, gossip: None, ack_id: None }.to_buffer(WireFormat::Bincode).unwrap(),
+            };
+            server.handle_message(&Message { from: addr, payload: payload.to_frame(WireFormat::Bincode).unwrap() });
+            assert_eq!(server.store.read(key).unwrap(), vec![1, 2, 3, 4]);
+
+            server.store.clear().unwrap();
+        }
+
+        /// build the transport of a new node, listening on an address of its choosing
+        type MakeTransportFn<T> = fn() -> Arc<T>;
+
+        /// the behaviour expected of a cluster whatever transport it runs on. run once per transport,
+        /// `name` keeping the store roots of each run apart
+        fn transport_contract<T: Transport>(name: &str, make_transport: MakeTransportFn<T>) {
+            let root = |node: &str| format!("test_store_contract_{}_{}", name, node);
+
+            // bootstrap: b joins the cluster through a
+            let a = FileServer::new(make_opts_with(&root("a"), make_transport())).unwrap().start_background();
+            assert!(wait_until(|| a.server().transport.clone().is_listening()));
+            let a_addr = a.server().transport.clone().local_addr().unwrap();
+            let mut opts = make_opts_with(&root("b"), make_transport());
+            opts.bootstrap_node = vec![a_addr];
+            opts.bootstrap_timeout = Some(Duration::from_secs(5));
+            let b = FileServer::new(opts).unwrap().start_background();
+            assert!(wait_until(|| a.server().peer_addrs().len() == 1 && b.server().peer_addrs().len() == 1));
+            assert_eq!(b.server().peer_addrs(), vec![a_addr]);
+
+            // store: the file is replicated to the other node
+            let key = String::from("contract_stored");
+            a.server().store_data(key.clone(), &mut vec![1, 2, 3].as_slice()).unwrap();
+            assert!(wait_until(|| b.server().store.read(key.clone()).ok() == Some(vec![1, 2, 3])));
+
+            // get: a file held by the other node only is fetched from it
+            let key = String::from("contract_fetched");
+            b.server().store.write(key.clone(), &[4, 5, 6]).unwrap();
+            assert_eq!(a.server().get_data(key.clone()).unwrap(), vec![4, 5, 6]);
+            assert_eq!(a.server().store.read(key).unwrap(), vec![4, 5, 6]);
+
+            // delete: an expired file is deleted on both nodes
+            let key = String::from("contract_deleted");
+            a.server().store_with_ttl(key.clone(), &mut vec![7].as_slice(), Some(Duration::from_millis(1))).unwrap();
+            assert!(wait_until(|| b.server().store.read(key.clone()).is_ok()));
+            assert!(wait_until(|| a.server().sweep_expired() == 1));
+            assert!(a.server().store.read(key.clone()).is_err());
+            assert!(wait_until(|| b.server().store.read(key.clone()).is_err()));
+
+            for handle in [a, b] {
+                handle.server().store.clear().unwrap();
+                handle.shutdown();
+                assert!(handle.join().is_ok());
+            }
+        }
+
+        #[test]
+        fn test_transport_contract_tcp() {
+            transport_contract("tcp", || make_tcp_transport("127.0.0.1:0"));
+        }
+
+        #[test]
+        fn test_transport_contract_delayed() {
+            transport_contract("delayed", || {
+                let mut transport = DelayedTransport::new(make_tcp_transport("127.0.0.1:0"));
+                transport.send_delay = Duration::from_millis(10);
+                Arc::new(transport)
+            });
+        }
+    }
+}
+
+pub mod ring;
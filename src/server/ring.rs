@@ -0,0 +1,192 @@
+use std::collections::BTreeMap;
+
+use crate::store::hashlib::hash_u64;
+
+/// a reasonable number of virtual nodes per node, see `HashRing::new`
+pub const DEFAULT_VNODES: usize = 128;
+
+/// consistent hashing of keys onto nodes. each node is hashed onto the ring at `vnodes` points, and a key belongs
+/// to the nodes met first going clockwise from its own hash. adding or removing a node only moves the keys
+/// next to its points, and the many points per node spread the keys evenly.  
+/// a node added with a weight gets that many times more points, and about as many times more keys
+pub struct HashRing {
+    vnodes: usize,
+    /// point on the ring -> node id
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    /// an empty ring hashing each node onto `vnodes` points
+    pub fn new(vnodes: usize) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// add the node, if it is not on the ring yet
+    pub fn add(&mut self, node: &str) {
+        self.add_weighted(node, 1);
+    }
+
+    /// add the node with `weight` times the points of `add`, e.g. its disk size in some unit, so that it gets
+    /// `weight` times the keys. a node already on the ring keeps its weight until it is removed
+    pub fn add_weighted(&mut self, node: &str, weight: u32) {
+        if self.contains(node) {
+            return;
+        }
+        for i in 0..self.vnodes * weight.max(1) as usize {
+            // on the unlikely collision of two points, the first node keeps it
+            self.points.entry(point(node, i)).or_insert_with(|| node.to_string());
+        }
+    }
+
+    /// remove the node, if it is on the ring
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    /// return whether the node is on the ring
+    pub fn contains(&self, node: &str) -> bool {
+        self.points.values().any(|owner| owner == node)
+    }
+
+    /// return the number of distinct nodes on the ring
+    pub fn len(&self) -> usize {
+        let mut nodes: Vec<&String> = self.points.values().collect();
+        nodes.sort();
+        nodes.dedup();
+
+        nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// return up to `n` distinct nodes holding the key, in the order they follow the key on the ring
+    pub fn successors(&self, key: &str, n: usize) -> Vec<String> {
+        let start = hash_u64(key);
+        let mut nodes: Vec<String> = Vec::new();
+        for owner in self.points.range(start..).chain(self.points.range(..start)).map(|(_, owner)| owner) {
+            if nodes.len() >= n {
+                break;
+            }
+            if !nodes.contains(owner) {
+                nodes.push(owner.clone());
+            }
+        }
+
+        nodes
+    }
+}
+
+/// where the `i`th virtual node of the node lies on the ring
+fn point(node: &str, i: usize) -> u64 {
+    hash_u64(&format!("{}#{}", node, i))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn make_ring(nodes: &[&str]) -> HashRing {
+        let mut ring = HashRing::new(DEFAULT_VNODES);
+        for node in nodes {
+            ring.add(node);
+        }
+
+        ring
+    }
+
+    /// the first node of each key
+    fn owners(ring: &HashRing, keys: &[String]) -> Vec<String> {
+        keys.iter().map(|key| ring.successors(key, 1).remove(0)).collect()
+    }
+
+    fn keys() -> Vec<String> {
+        (0..10000).map(|i| format!("key_{}", i)).collect()
+    }
+
+    #[test]
+    fn test_successors() {
+        let ring = make_ring(&["a", "b", "c"]);
+        assert_eq!(ring.len(), 3);
+        assert!(ring.contains("b") && !ring.contains("d"));
+
+        let nodes = ring.successors("some_key", 2);
+        assert_eq!(nodes.len(), 2);
+        assert_ne!(nodes[0], nodes[1]);
+        assert_eq!(ring.successors("some_key", 2), nodes);
+        // more nodes than the ring holds
+        let mut all = ring.successors("some_key", 5);
+        assert_eq!(all[..2], nodes[..]);
+        all.sort();
+        assert_eq!(all, vec!["a", "b", "c"]);
+
+        assert!(HashRing::new(DEFAULT_VNODES).successors("some_key", 1).is_empty());
+    }
+
+    #[test]
+    fn test_even_distribution() {
+        let nodes = ["10.0.0.1:3000", "10.0.0.2:3000", "10.0.0.3:3000", "10.0.0.4:3000"];
+        let ring = make_ring(&nodes);
+        let keys = keys();
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for owner in owners(&ring, &keys) {
+            *counts.entry(owner).or_default() += 1;
+        }
+        let fair = keys.len() / nodes.len();
+        for node in nodes {
+            let count = counts[node];
+            assert!(count > fair * 3 / 4 && count < fair * 5 / 4, "{} holds {} keys", node, count);
+        }
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let mut ring = HashRing::new(DEFAULT_VNODES);
+        ring.add_weighted("large", 3);
+        ring.add("small");
+        assert_eq!(ring.len(), 2);
+        let keys = keys();
+
+        let large = owners(&ring, &keys).iter().filter(|owner| *owner == "large").count();
+        let small = keys.len() - large;
+        // about 3 times as many keys, with the same tolerance as the even distribution
+        assert!(large > small * 3 * 3 / 4 && large < small * 3 * 5 / 4, "large holds {} keys, small {}", large, small);
+
+        // adding it again doesn't change its weight
+        ring.add("large");
+        assert_eq!(owners(&ring, &keys).iter().filter(|owner| *owner == "large").count(), large);
+        ring.remove("large");
+        assert!(owners(&ring, &keys).iter().all(|owner| owner == "small"));
+    }
+
+    #[test]
+    fn test_stable_on_membership_change() {
+        let mut ring = make_ring(&["a", "b", "c", "d"]);
+        let keys = keys();
+        let before = owners(&ring, &keys);
+
+        // only keys moving to the new node change owner
+        ring.add("e");
+        let with_e = owners(&ring, &keys);
+        let moved = before.iter().zip(with_e.iter()).filter(|(old, new)| old != new).count();
+        assert!(before.iter().zip(with_e.iter()).all(|(old, new)| old == new || new == "e"));
+        assert!(moved > 0 && moved < keys.len() / 3);
+
+        // and removing it moves them back where they were
+        ring.remove("e");
+        assert!(!ring.contains("e"));
+        assert_eq!(owners(&ring, &keys), before);
+
+        // removing another node only moves its own keys
+        ring.remove("b");
+        let without_b = owners(&ring, &keys);
+        assert!(before.iter().zip(without_b.iter()).all(|(old, new)| old == new || old == "b"));
+    }
+}
@@ -0,0 +1,388 @@
+use std::{collections::HashMap, fs, io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write}, path::Path, sync::Mutex, time::SystemTime};
+
+/// where the store keeps the content of its files.
+/// paths are the ones built by the store, i.e. `<root dir>/<transformed key>` for the stored files and
+/// `<root dir>.<suffix>` for its sidecars. a missing file is reported as `ErrorKind::NotFound`
+pub trait StorageBackend: Send + Sync {
+    /// return a stream to the content of the file
+    fn read_stream(&self, path: &str) -> Result<Box<dyn Read>, io::Error>;
+
+    /// replace the content of the file with the stream, creating the file if needed. return the number of bytes written
+    fn write_stream(&self, path: &str, r: &mut dyn Read) -> Result<u64, io::Error>;
+
+    fn delete(&self, path: &str) -> Result<(), io::Error>;
+
+    fn exists(&self, path: &str) -> bool;
+
+    /// return the paths of all the files under the directory, recursively
+    fn list(&self, dir: &str) -> Result<Vec<String>, io::Error>;
+
+    /// size of the file in bytes
+    fn size(&self, path: &str) -> Result<u64, io::Error>;
+
+    /// when the file was last written
+    fn modified(&self, path: &str) -> Result<SystemTime, io::Error>;
+
+    /// move the file to `to`, replacing the file already there if any
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error>;
+
+    /// read at most `len` bytes of the file, starting from `offset`
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, io::Error> {
+        let mut r = self.read_stream(path)?;
+        io::copy(&mut r.by_ref().take(offset), &mut io::sink())?;
+        let mut buf = Vec::new();
+        r.take(len).read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// write the buffer into the file starting from `offset`, creating the file if needed.
+    /// existing bytes outside the range are kept
+    fn write_at(&self, path: &str, offset: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let end = offset.checked_add(buf.len() as u64)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::FileTooLarge, format!("cannot write past offset {}", offset)))?;
+        let mut content = read_or_empty(self, path)?;
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[end - buf.len()..end].copy_from_slice(buf);
+        self.write_stream(path, &mut content.as_slice())?;
+
+        Ok(())
+    }
+
+    /// add the buffer to the end of the file, creating the file if needed
+    fn append(&self, path: &str, buf: &[u8]) -> Result<(), io::Error> {
+        let mut content = read_or_empty(self, path)?;
+        content.extend_from_slice(buf);
+        self.write_stream(path, &mut content.as_slice())?;
+
+        Ok(())
+    }
+
+    /// remove all the files under the directory
+    fn remove_all(&self, dir: &str) -> Result<(), io::Error> {
+        for path in self.list(dir)? {
+            self.delete(&path)?;
+        }
+
+        Ok(())
+    }
+
+    /// like `remove_all`, keeping the directory itself, e.g. with the permissions set on it.
+    /// backends without directories have nothing more to keep
+    fn remove_contents(&self, dir: &str) -> Result<(), io::Error> {
+        self.remove_all(dir)
+    }
+
+    /// remove the empty directories under the directory, but not the directory itself.
+    /// return the number of directories removed. backends without directories have nothing to remove
+    fn remove_empty_dirs(&self, _dir: &str) -> Result<u64, io::Error> {
+        Ok(0)
+    }
+}
+
+/// keeps the files on the local filesystem. this is what the store uses unless `StoreOpts::backend` is set
+pub struct FsBackend {
+    /// the directories `dir_mode` is applied up to. directories outside of them are left alone
+    pub roots: Vec<String>,
+    /// unix mode bits set on the directories created under `roots`. None keeps the umask default
+    pub dir_mode: Option<u32>,
+    /// unix mode bits set on the files created. None keeps the umask default
+    pub file_mode: Option<u32>,
+    /// make sure every write has reached the disk before returning
+    pub sync: bool,
+}
+
+impl FsBackend {
+    pub fn new(roots: Vec<String>) -> FsBackend {
+        FsBackend {
+            roots,
+            dir_mode: None,
+            file_mode: None,
+            sync: false,
+        }
+    }
+
+    /// create the directories leading to the file, applying `dir_mode` to them up to their root directory
+    fn create_parent_dirs(&self, path: &str) -> Result<(), io::Error> {
+        let parent = match Path::new(path).parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(parent)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = self.dir_mode {
+            use std::os::unix::fs::PermissionsExt;
+
+            let root = match self.roots.iter().find(|root| parent.starts_with(root)) {
+                Some(root) => Path::new(root),
+                None => return Ok(()),
+            };
+            for dir in parent.ancestors() {
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+                if dir == root {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// apply `file_mode` to a file the backend has created
+    fn set_file_mode(&self, path: &str) -> Result<(), io::Error> {
+        #[cfg(unix)]
+        if let Some(mode) = self.file_mode {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+
+        Ok(())
+    }
+
+    /// open the file for writing, creating it and the directories leading to it if needed
+    fn open_for_write(&self, path: &str, opts: &mut fs::OpenOptions) -> Result<fs::File, io::Error> {
+        self.create_parent_dirs(path)?;
+        let file = opts.create(true).open(path)?;
+        self.set_file_mode(path)?;
+
+        Ok(file)
+    }
+}
+
+impl StorageBackend for FsBackend {
+    fn read_stream(&self, path: &str) -> Result<Box<dyn Read>, io::Error> {
+        Ok(Box::new(BufReader::new(fs::File::open(path)?)))
+    }
+
+    fn write_stream(&self, path: &str, r: &mut dyn Read) -> Result<u64, io::Error> {
+        let mut w = self.open_for_write(path, fs::OpenOptions::new().write(true).truncate(true))?;
+        let bytes_written = io::copy(r, &mut w)?;
+        if self.sync {
+            w.sync_all()?;
+        }
+
+        Ok(bytes_written)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), io::Error> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        Path::new(path).is_file()
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, io::Error> {
+        Ok(list_files(Path::new(dir)))
+    }
+
+    fn size(&self, path: &str) -> Result<u64, io::Error> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn modified(&self, path: &str) -> Result<SystemTime, io::Error> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        // the destination may live in a directory that doesn't exist yet (e.g. cas_path_transform)
+        self.create_parent_dirs(to)?;
+        fs::rename(from, to)
+    }
+
+    fn read_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, io::Error> {
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        file.take(len).read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn write_at(&self, path: &str, offset: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let mut w = self.open_for_write(path, fs::OpenOptions::new().write(true).truncate(false))?;
+        w.seek(SeekFrom::Start(offset))?;
+        w.write_all(buf)?;
+        if self.sync {
+            w.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn append(&self, path: &str, buf: &[u8]) -> Result<(), io::Error> {
+        let mut w = self.open_for_write(path, fs::OpenOptions::new().append(true))?;
+        w.write_all(buf)?;
+        if self.sync {
+            w.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn remove_all(&self, dir: &str) -> Result<(), io::Error> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn remove_contents(&self, dir: &str) -> Result<(), io::Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            match entry.file_type()?.is_dir() {
+                true => fs::remove_dir_all(entry.path())?,
+                false => fs::remove_file(entry.path())?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_empty_dirs(&self, dir: &str) -> Result<u64, io::Error> {
+        remove_empty_dirs(Path::new(dir))
+    }
+}
+
+/// a file of `MemBackend`
+struct MemFile {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+/// keeps the files in memory, e.g. for tests. everything is lost when it is dropped
+#[derive(Default)]
+pub struct MemBackend {
+    files: Mutex<HashMap<String, MemFile>>,
+}
+
+impl MemBackend {
+    pub fn new() -> MemBackend {
+        MemBackend::default()
+    }
+}
+
+impl StorageBackend for MemBackend {
+    fn read_stream(&self, path: &str) -> Result<Box<dyn Read>, io::Error> {
+        match self.files.lock().unwrap().get(path) {
+            Some(file) => Ok(Box::new(io::Cursor::new(file.data.clone()))),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn write_stream(&self, path: &str, r: &mut dyn Read) -> Result<u64, io::Error> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        let bytes_written = data.len() as u64;
+        self.files.lock().unwrap().insert(path.to_string(), MemFile { data, modified: SystemTime::now() });
+
+        Ok(bytes_written)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), io::Error> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, io::Error> {
+        let prefix = format!("{}/", dir);
+        Ok(self.files.lock().unwrap().keys().filter(|path| path.starts_with(&prefix)).cloned().collect())
+    }
+
+    fn size(&self, path: &str) -> Result<u64, io::Error> {
+        match self.files.lock().unwrap().get(path) {
+            Some(file) => Ok(file.data.len() as u64),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn modified(&self, path: &str) -> Result<SystemTime, io::Error> {
+        match self.files.lock().unwrap().get(path) {
+            Some(file) => Ok(file.modified),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(from) {
+            Some(file) => {
+                files.insert(to.to_string(), file);
+                Ok(())
+            },
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_all(&self, dir: &str) -> Result<(), io::Error> {
+        let prefix = format!("{}/", dir);
+        self.files.lock().unwrap().retain(|path, _| !path.starts_with(&prefix));
+
+        Ok(())
+    }
+}
+
+/// the whole content of the file, or nothing if it doesn't exist
+fn read_or_empty<B: StorageBackend + ?Sized>(backend: &B, path: &str) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::new();
+    match backend.read_stream(path) {
+        Ok(mut r) => {
+            r.read_to_end(&mut buf)?;
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    Ok(buf)
+}
+
+/// remove the empty directories under the directory, recursively, but not the directory itself.
+/// return the number of directories removed
+fn remove_empty_dirs(path: &Path) -> Result<u64, io::Error> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let dir = entry.path();
+        removed += remove_empty_dirs(&dir)?;
+        if fs::read_dir(&dir)?.next().is_none() {
+            fs::remove_dir(&dir)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// all the files under the directory, recursively
+fn list_files(path: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .flat_map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => list_files(&entry.path()),
+            Ok(_) => vec![entry.path().to_string_lossy().to_string()],
+            Err(_) => Vec::new(),
+        })
+        .collect()
+}
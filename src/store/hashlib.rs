@@ -1,3 +1,5 @@
+use std::io::{self, Read};
+
 use crypto::{md5, sha1, digest::Digest};
 
 const CAS_BLOCK_SIZE: usize = 5;
@@ -11,13 +13,12 @@ pub fn cas_path_transform(s: String) -> String {
     let hash_str = hasher.result_str();
 
     let slice_len = hash_str.len() / CAS_BLOCK_SIZE;
-    let mut path = vec![String::new(); slice_len];
-
-    for i in 0..slice_len {
-        let start = i * CAS_BLOCK_SIZE;
-        let end = start + CAS_BLOCK_SIZE;
-        path[i] = hash_str[start..end].to_string();
-    }
+    let path: Vec<&str> = (0..slice_len)
+        .map(|i| {
+            let start = i * CAS_BLOCK_SIZE;
+            &hash_str[start..start + CAS_BLOCK_SIZE]
+        })
+        .collect();
 
     path.join("/")
 }
@@ -29,14 +30,70 @@ pub fn filename_transform(s: String) -> String {
     hasher.result_str()
 }
 
+/// pick one of `n` root directories for the key by hashing it
+pub fn root_placement(key: &str, n: usize) -> usize {
+    if n <= 1 {
+        return 0;
+    }
+
+    (hash_u64(key) % n as u64) as usize
+}
+
+/// pick one of `n` shard directories for the key by hashing it.
+/// other bits of the hash than `root_placement` are used, so that the files of each root spread over all the shards
+pub fn shard_placement(key: &str, n: usize) -> usize {
+    if n <= 1 {
+        return 0;
+    }
+
+    (hash_u64(key).rotate_left(32) % n as u64) as usize
+}
+
+/// the first 8 bytes of the sha1 hash of the string
+pub fn hash_u64(s: &str) -> u64 {
+    let mut hasher = sha1::Sha1::new();
+    hasher.input_str(s);
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&hash[..8]);
+
+    u64::from_be_bytes(prefix)
+}
+
 pub fn get_file_hash(buf: &[u8]) -> String {
-    println!("buf: {:?}", buf);
     let mut hasher = md5::Md5::new();
     hasher.input(buf);
 
     hasher.result_str()
 }
 
+/// a reader hashing what is read through it, so that data can be hashed while it is copied
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: md5::Md5,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader { inner, hasher: md5::Md5::new() }
+    }
+
+    /// the `get_file_hash` of everything read so far
+    pub fn hash(&mut self) -> String {
+        self.hasher.result_str()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.input(&buf[..n]);
+
+        Ok(n)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +114,15 @@ mod tests {
         let expected_hash = "08d6c05a21512a79a1dfeb9d2a8f262f".to_string();
         assert_eq!(actual_hash, expected_hash);
     }
+
+    #[test]
+    fn test_hashing_reader() {
+        let buf: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let mut r = HashingReader::new(buf.as_slice());
+        let mut copied = Vec::new();
+        io::copy(&mut r, &mut copied).unwrap();
+
+        assert_eq!(copied, buf);
+        assert_eq!(r.hash(), get_file_hash(&buf));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+use std::io;
+
+use super::encoding::DEFAULT_MAX_PAYLOAD_SIZE;
+
+/// payloads smaller than this are sent as they are, compressing them gains next to nothing
+pub const MIN_COMPRESS_SIZE: usize = 256;
+/// the deflate level, from 0 to 10. 6 is the usual tradeoff between speed and size
+const DEFLATE_LEVEL: u8 = 6;
+
+/// how the payload of a message is encoded on the wire. each peer advertises the codecs it supports when it
+/// connects, and each message is sent with the best one both sides support, see `TcpTransportOpts::codecs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// sent as it is. supported by every node, so that two nodes always have a codec in common
+    Identity,
+    /// compressed with deflate
+    Deflate,
+}
+
+impl Codec {
+    /// the byte telling the codec of a message on the wire
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Identity => 0,
+            Codec::Deflate => 1,
+        }
+    }
+
+    /// None for a codec this node doesn't know, e.g. advertised by a newer peer
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::Identity),
+            1 => Some(Codec::Deflate),
+            _ => None,
+        }
+    }
+
+    fn encode(self, buf: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::Identity => buf.to_vec(),
+            Codec::Deflate => miniz_oxide::deflate::compress_to_vec(buf, DEFLATE_LEVEL),
+        }
+    }
+
+    /// fail with `InvalidData` if the buffer can't be decoded, or decodes to more than `DEFAULT_MAX_PAYLOAD_SIZE`
+    fn decode(self, buf: &[u8]) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Codec::Identity => Ok(buf.to_vec()),
+            Codec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(buf, DEFAULT_MAX_PAYLOAD_SIZE)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid deflate payload: {}", e))),
+        }
+    }
+}
+
+/// the codecs of `ours` that the peer supports too, in our order of preference. Identity is always among them
+pub fn common_codecs(ours: &[Codec], theirs: &[Codec]) -> Vec<Codec> {
+    let mut common: Vec<Codec> = ours.iter().copied().filter(|codec| theirs.contains(codec)).collect();
+    if !common.contains(&Codec::Identity) {
+        common.push(Codec::Identity);
+    }
+
+    common
+}
+
+/// encode the payload with the first of the codecs that makes it smaller, Identity if none does.
+/// the first byte is left as it is, so that it is still the message type for `TypedFrameEncoder`, and the codec
+/// goes at the end, like the checksum of `ChecksumEncoder`
+pub fn encode(codecs: &[Codec], buf: &[u8]) -> Vec<u8> {
+    let (first, rest) = match buf.split_first() {
+        Some((first, rest)) => (Some(*first), rest),
+        None => (None, buf),
+    };
+    let (codec, body) = match rest.len() >= MIN_COMPRESS_SIZE {
+        true => codecs.iter()
+            .filter(|codec| **codec != Codec::Identity)
+            .map(|codec| (*codec, codec.encode(rest)))
+            .find(|(_, body)| body.len() < rest.len())
+            .unwrap_or((Codec::Identity, rest.to_vec())),
+        false => (Codec::Identity, rest.to_vec()),
+    };
+
+    let mut encoded = Vec::with_capacity(body.len() + 2);
+    encoded.extend(first);
+    encoded.extend_from_slice(&body);
+    encoded.push(codec.id());
+    encoded
+}
+
+/// the payload given to `encode`. fail with `InvalidData` if the codec is unknown or the payload can't be decoded
+pub fn decode(buf: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let (id, buf) = buf.split_last().ok_or(io::Error::new(io::ErrorKind::InvalidData, "payload without a codec"))?;
+    let codec = Codec::from_id(*id).ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("unknown codec {}", id)))?;
+    let (first, rest) = match buf.split_first() {
+        Some((first, rest)) => (*first, rest),
+        None => return Ok(Vec::new()),
+    };
+
+    let body = codec.decode(rest)?;
+    let mut decoded = Vec::with_capacity(1 + body.len());
+    decoded.push(first);
+    decoded.extend_from_slice(&body);
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_picks_best_common_codec() {
+        let compressible: Vec<u8> = [vec![7], vec![b'a'; 4096]].concat();
+
+        let both = common_codecs(&[Codec::Deflate, Codec::Identity], &[Codec::Identity, Codec::Deflate]);
+        assert_eq!(both, vec![Codec::Deflate, Codec::Identity]);
+        let encoded = encode(&both, &compressible);
+        assert_eq!(encoded.last(), Some(&Codec::Deflate.id()));
+        assert_eq!(encoded[0], 7);
+        assert!(encoded.len() < compressible.len());
+        assert_eq!(decode(&encoded).unwrap(), compressible);
+
+        // a plaintext only peer gets the payload as it is
+        let plain = common_codecs(&[Codec::Deflate, Codec::Identity], &[Codec::Identity]);
+        assert_eq!(plain, vec![Codec::Identity]);
+        let encoded = encode(&plain, &compressible);
+        assert_eq!(encoded, [compressible.clone(), vec![Codec::Identity.id()]].concat());
+        assert_eq!(decode(&encoded).unwrap(), compressible);
+
+        // too small, or not smaller once compressed
+        let mut state: u32 = 2463534242;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                // xorshift, so that there is nothing to compress
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        for buf in [vec![], vec![7, 1, 2, 3], random] {
+            let encoded = encode(&both, &buf);
+            assert_eq!(encoded.last(), Some(&Codec::Identity.id()));
+            assert_eq!(decode(&encoded).unwrap(), buf);
+        }
+
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[7, 1, 2, 42]).is_err());
+    }
+}
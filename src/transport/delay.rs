@@ -0,0 +1,199 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::message::Message;
+use super::transport::{OnPeerDisconnectFn, OnPeerFn, Transport};
+
+/// a transport slowing down another one by fixed amounts, to test timeouts and retries without depending on
+/// how fast the network happens to be. the delays are added before the inner transport is called
+pub struct DelayedTransport<T: Transport> {
+    pub inner: Arc<T>,
+    pub consume_delay: Duration,
+    /// added to `send_to` and `broadcast`
+    pub send_delay: Duration,
+    pub dial_delay: Duration,
+    /// the next dials to fail, after their delay, without reaching the inner transport
+    pub failing_dials: AtomicUsize,
+    /// the number of dials so far, failed or not
+    pub dial_attempts: AtomicUsize,
+    /// the number of `consume` and `consume_blocking` calls so far
+    pub consumes: AtomicUsize,
+    /// the number of `reattach` calls so far
+    pub reattaches: AtomicUsize,
+}
+
+impl<T: Transport> DelayedTransport<T> {
+    /// wrap the transport without any delay
+    pub fn new(inner: Arc<T>) -> DelayedTransport<T> {
+        DelayedTransport {
+            inner,
+            consume_delay: Duration::ZERO,
+            send_delay: Duration::ZERO,
+            dial_delay: Duration::ZERO,
+            failing_dials: AtomicUsize::new(0),
+            dial_attempts: AtomicUsize::new(0),
+            consumes: AtomicUsize::new(0),
+            reattaches: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T: Transport> Transport for DelayedTransport<T> {
+    type Peer = T::Peer;
+
+    fn addr(self: Arc<Self>) -> String {
+        self.inner.clone().addr()
+    }
+
+    fn local_addr(self: Arc<Self>) -> Result<SocketAddr, io::Error> {
+        self.inner.clone().local_addr()
+    }
+
+    fn close(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.clone().close()
+    }
+
+    fn close_peer(self: Arc<Self>, addr: SocketAddr) -> Result<(), io::Error> {
+        self.inner.clone().close_peer(addr)
+    }
+
+    fn consume(self: Arc<Self>) -> Result<Message, RecvTimeoutError> {
+        thread::sleep(self.consume_delay);
+        self.consumes.fetch_add(1, Ordering::SeqCst);
+        self.inner.clone().consume()
+    }
+
+    fn consume_blocking(self: Arc<Self>) -> Result<Option<Message>, RecvError> {
+        thread::sleep(self.consume_delay);
+        self.consumes.fetch_add(1, Ordering::SeqCst);
+        self.inner.clone().consume_blocking()
+    }
+
+    fn wake(self: Arc<Self>) {
+        self.inner.clone().wake()
+    }
+
+    fn reattach(self: Arc<Self>) {
+        self.inner.clone().reattach();
+        self.reattaches.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn drain(self: Arc<Self>) -> Vec<Message> {
+        self.inner.clone().drain()
+    }
+
+    fn consume_batch(self: Arc<Self>, max: usize) -> Vec<Message> {
+        thread::sleep(self.consume_delay);
+        self.inner.clone().consume_batch(max)
+    }
+
+    fn max_payload_size(self: Arc<Self>) -> usize {
+        self.inner.clone().max_payload_size()
+    }
+
+    fn listen_and_accept(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.clone().listen_and_accept()
+    }
+
+    fn dial(self: &Arc<Self>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        thread::sleep(self.dial_delay);
+        self.dial_attempts.fetch_add(1, Ordering::SeqCst);
+        let failing = self.failing_dials.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if failing.is_ok() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::ConnectionRefused, format!("dial to {} failed on purpose", addr))));
+        }
+        self.inner.dial(addr)
+    }
+
+    fn send_to(self: &Arc<Self>, addr: SocketAddr, buf: &[u8]) -> Result<(), io::Error> {
+        thread::sleep(self.send_delay);
+        self.inner.send_to(addr, buf)
+    }
+
+    fn broadcast(self: Arc<Self>, buf: &[u8]) -> Vec<(SocketAddr, Result<(), io::Error>)> {
+        thread::sleep(self.send_delay);
+        self.inner.clone().broadcast(buf)
+    }
+
+    fn peer_count(self: Arc<Self>) -> usize {
+        self.inner.clone().peer_count()
+    }
+
+    fn peer_addrs(self: Arc<Self>) -> Vec<SocketAddr> {
+        self.inner.clone().peer_addrs()
+    }
+
+    fn register_on_peer(self: Arc<Self>, callback: OnPeerFn<Self::Peer>) {
+        self.inner.clone().register_on_peer(callback)
+    }
+
+    fn pause_accept(self: Arc<Self>) {
+        self.inner.clone().pause_accept()
+    }
+
+    fn resume_accept(self: Arc<Self>) {
+        self.inner.clone().resume_accept()
+    }
+
+    fn is_listening(self: Arc<Self>) -> bool {
+        self.inner.clone().is_listening()
+    }
+
+    fn register_on_peer_disconnect(self: Arc<Self>, callback: OnPeerDisconnectFn) {
+        self.inner.clone().register_on_peer_disconnect(callback)
+    }
+
+    fn max_dial_backoff(self: Arc<Self>) -> Duration {
+        self.inner.clone().max_dial_backoff()
+    }
+
+    fn weight(self: Arc<Self>) -> u32 {
+        self.inner.clone().weight()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::transport::encoding::DefaultDecoder;
+    use crate::transport::tcp::{TcpTransport, TcpTransportOpts};
+
+    use super::*;
+
+    fn tcp_transport() -> Arc<TcpTransport> {
+        TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap()
+    }
+
+    #[test]
+    fn test_try_dial_backs_off_delayed_dial() {
+        let target = tcp_transport();
+        target.clone().listen_and_accept().unwrap();
+        let target_addr = target.clone().local_addr().unwrap();
+
+        let mut delayed = DelayedTransport::new(tcp_transport());
+        delayed.dial_delay = Duration::from_millis(100);
+        delayed.failing_dials = AtomicUsize::new(1);
+        let delayed = Arc::new(delayed);
+
+        // the first dial fails, the second one comes after the backoff, of at least half a second with the jitter
+        let start = Instant::now();
+        delayed.try_dial(target_addr, 2).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(700), "dialed in {:?}", elapsed);
+        assert_eq!(delayed.dial_attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(delayed.clone().peer_count(), 1);
+
+        // out of attempts, the error is returned without waiting for a backoff
+        delayed.failing_dials.store(1, Ordering::SeqCst);
+        let start = Instant::now();
+        assert!(delayed.try_dial(target_addr, 0).is_err());
+        assert!(start.elapsed() < Duration::from_millis(500), "gave up in {:?}", start.elapsed());
+        assert_eq!(delayed.dial_attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,25 +1,445 @@
+use std::fmt::{self, Display, Formatter};
 use std::io;
+use std::sync::Arc;
 
 // use rust_distributed_file::read_all_from_stream;
 
 use super::message::Message;
 
+/// size of the length prefix written by `LengthPrefixedEncoder`
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// size of the crc32 appended by `ChecksumEncoder`
+const CHECKSUM_SIZE: usize = 4;
+/// default upper bound of a single frame accepted by `LengthPrefixedDecoder`
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+/// default size of the buffer `DefaultDecoder` reads into
+pub const DEFAULT_READ_BUF_SIZE: usize = 1024;
+
 pub trait Decoder: Send + Sync {
     fn decode(&self, r: &mut dyn io::Read, msg: &mut Message) -> Result<(), io::Error>;
+
+    /// whether the decoded message should skip ahead of the others waiting to be consumed,
+    /// e.g. control messages which must not wait behind bulk data
+    fn is_priority(&self, _msg: &Message) -> bool {
+        false
+    }
+
+    /// the largest payload a decoded message may have
+    fn max_payload_size(&self) -> usize {
+        DEFAULT_MAX_PAYLOAD_SIZE
+    }
 }
 
-pub struct DefaultDecoder {}
+/// the sending counterpart of `Decoder`  
+/// the encoder and decoder used on both ends of a connection must agree on the framing
+pub trait Encoder: Send + Sync {
+    fn encode(&self, buf: &[u8], w: &mut dyn io::Write) -> Result<(), io::Error>;
+}
+
+/// write the bytes as they are. pairs with `DefaultDecoder`
+pub struct DefaultEncoder {}
+
+impl Encoder for DefaultEncoder {
+    fn encode(&self, buf: &[u8], w: &mut dyn io::Write) -> Result<(), io::Error> {
+        w.write_all(buf)
+    }
+}
+
+/// prepend the length of the payload as a 4 bytes big endian integer. pairs with `LengthPrefixedDecoder`
+pub struct LengthPrefixedEncoder {}
+
+impl Encoder for LengthPrefixedEncoder {
+    fn encode(&self, buf: &[u8], w: &mut dyn io::Write) -> Result<(), io::Error> {
+        let len = u32::try_from(buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large to be framed"))?;
+        w.write_all(&len.to_be_bytes())?;
+        w.write_all(buf)
+    }
+}
+
+/// read a frame written by `LengthPrefixedEncoder`
+pub struct LengthPrefixedDecoder {
+    /// frames claiming to be larger than this are rejected before anything is allocated for them
+    pub max_payload_size: usize,
+}
+
+impl LengthPrefixedDecoder {
+    pub fn new() -> LengthPrefixedDecoder {
+        LengthPrefixedDecoder {
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+impl LengthPrefixedDecoder {
+    /// read the length prefix of the next frame, checking it against `max_payload_size`
+    fn read_len(&self, r: &mut dyn io::Read) -> Result<usize, io::Error> {
+        let mut len_buf = [0; LENGTH_PREFIX_SIZE];
+        r.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > self.max_payload_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds the limit of {} bytes", len, self.max_payload_size),
+            ));
+        }
+
+        Ok(len)
+    }
+}
+
+impl Default for LengthPrefixedDecoder {
+    fn default() -> Self {
+        LengthPrefixedDecoder::new()
+    }
+}
+
+impl Decoder for LengthPrefixedDecoder {
+    fn decode(&self, r: &mut dyn io::Read, msg: &mut Message) -> Result<(), io::Error> {
+        let len = self.read_len(r)?;
+        let mut buf = vec![0; len];
+        r.read_exact(&mut buf)?;
+        msg.payload = buf;
+
+        Ok(())
+    }
+
+    fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+}
+
+/// prepend the first byte of the buffer, the message type, to the length prefix of the rest.
+/// the decoder then knows what kind of message is coming before reading its body. pairs with `TypedFrameDecoder`
+pub struct TypedFrameEncoder {}
+
+impl Encoder for TypedFrameEncoder {
+    fn encode(&self, buf: &[u8], w: &mut dyn io::Write) -> Result<(), io::Error> {
+        let (msg_type, body) = buf
+            .split_first()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "a typed frame needs a message type"))?;
+        w.write_all(&[*msg_type])?;
+        LengthPrefixedEncoder {}.encode(body, w)
+    }
+}
+
+/// the header of a frame written by `TypedFrameEncoder`
+#[derive(Debug, PartialEq)]
+pub struct FrameHeader {
+    pub msg_type: u8,
+    /// size of the body following the header
+    pub len: usize,
+}
+
+/// read a frame written by `TypedFrameEncoder`. the message type is put back in front of the body,
+/// so the payload is the same buffer as the one given to the encoder
+pub struct TypedFrameDecoder {
+    /// frames claiming to be larger than this are rejected before anything is allocated for them
+    pub max_payload_size: usize,
+    /// the message types consumed before any other message, see `Decoder::is_priority`
+    pub priority_types: Vec<u8>,
+}
+
+impl TypedFrameDecoder {
+    pub fn new() -> TypedFrameDecoder {
+        TypedFrameDecoder {
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            priority_types: Vec::new(),
+        }
+    }
+
+    /// read the header of the next frame, leaving its body in the reader.
+    /// a read loop can use it to pick how to handle the body, e.g. stream a large one instead of buffering it
+    pub fn read_header(&self, r: &mut dyn io::Read) -> Result<FrameHeader, io::Error> {
+        let mut header = [0; 1 + LENGTH_PREFIX_SIZE];
+        r.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > self.max_payload_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds the limit of {} bytes", len, self.max_payload_size),
+            ));
+        }
+
+        Ok(FrameHeader { msg_type: header[0], len })
+    }
+}
+
+impl Default for TypedFrameDecoder {
+    fn default() -> Self {
+        TypedFrameDecoder::new()
+    }
+}
+
+impl Decoder for TypedFrameDecoder {
+    fn decode(&self, r: &mut dyn io::Read, msg: &mut Message) -> Result<(), io::Error> {
+        let header = self.read_header(r)?;
+        let mut buf = vec![0; 1 + header.len];
+        buf[0] = header.msg_type;
+        r.read_exact(&mut buf[1..])?;
+        msg.payload = buf;
+
+        Ok(())
+    }
+
+    fn is_priority(&self, msg: &Message) -> bool {
+        msg.payload.first().is_some_and(|msg_type| self.priority_types.contains(msg_type))
+    }
+
+    fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
+}
+
+/// append a crc32 of the buffer, then frame both with the inner encoder. pairs with `ChecksumDecoder`.  
+/// the checksum goes at the end so that the first byte is still the message type for `TypedFrameEncoder`
+pub struct ChecksumEncoder {
+    pub inner: Arc<dyn Encoder>,
+}
+
+impl ChecksumEncoder {
+    pub fn new(inner: Arc<dyn Encoder>) -> ChecksumEncoder {
+        ChecksumEncoder { inner }
+    }
+}
+
+impl Encoder for ChecksumEncoder {
+    fn encode(&self, buf: &[u8], w: &mut dyn io::Write) -> Result<(), io::Error> {
+        let mut checked = Vec::with_capacity(buf.len() + CHECKSUM_SIZE);
+        checked.extend_from_slice(buf);
+        checked.extend_from_slice(&crc32fast::hash(buf).to_be_bytes());
+        self.inner.encode(&checked, w)
+    }
+}
+
+/// the payload of a frame doesn't match its checksum, see `ChecksumDecoder`
+#[derive(Debug)]
+pub struct ErrChecksumMismatch;
+
+impl Display for ErrChecksumMismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "frame checksum mismatch")
+    }
+}
+
+impl std::error::Error for ErrChecksumMismatch {}
+
+/// return whether the error is a frame rejected by `ChecksumDecoder`. the frame was read whole, so the
+/// connection can go on with the next one
+pub fn is_checksum_mismatch(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<ErrChecksumMismatch>())
+}
+
+/// read a frame with the inner decoder and check the crc32 appended by `ChecksumEncoder`, which is removed
+/// from the payload. a mismatch fails with `ErrChecksumMismatch`
+pub struct ChecksumDecoder {
+    pub inner: Box<dyn Decoder>,
+}
+
+impl ChecksumDecoder {
+    pub fn new(inner: Box<dyn Decoder>) -> ChecksumDecoder {
+        ChecksumDecoder { inner }
+    }
+}
+
+impl Decoder for ChecksumDecoder {
+    fn decode(&self, r: &mut dyn io::Read, msg: &mut Message) -> Result<(), io::Error> {
+        self.inner.decode(r, msg)?;
+        let body_len = msg.payload.len().checked_sub(CHECKSUM_SIZE)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, ErrChecksumMismatch))?;
+        let mut checksum = [0; CHECKSUM_SIZE];
+        checksum.copy_from_slice(&msg.payload[body_len..]);
+        msg.payload.truncate(body_len);
+        if crc32fast::hash(&msg.payload) != u32::from_be_bytes(checksum) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ErrChecksumMismatch));
+        }
+
+        Ok(())
+    }
+
+    fn is_priority(&self, msg: &Message) -> bool {
+        self.inner.is_priority(msg)
+    }
+
+    fn max_payload_size(&self) -> usize {
+        self.inner.max_payload_size()
+    }
+}
+
+/// read whatever the reader has available, up to `read_buf_size` bytes, as a message.  
+/// without framing there is no telling where a message ends: one split across several reads by TCP is decoded
+/// as several messages, and messages sent back to back may come out as one. use `LengthPrefixedDecoder` or
+/// `TypedFrameDecoder` when messages must arrive whole
+pub struct DefaultDecoder {
+    /// the most bytes decoded into a single message
+    pub read_buf_size: usize,
+}
+
+impl DefaultDecoder {
+    pub fn new() -> DefaultDecoder {
+        DefaultDecoder {
+            read_buf_size: DEFAULT_READ_BUF_SIZE,
+        }
+    }
+}
+
+impl Default for DefaultDecoder {
+    fn default() -> Self {
+        DefaultDecoder::new()
+    }
+}
 
 impl Decoder for DefaultDecoder {
     fn decode(&self, r: &mut dyn io::Read, msg: &mut Message) -> Result<(), io::Error> {
-        // FIXME: it is not guaranteed that we will read all the bytes
-        let mut buf = vec![0; 1024];
-        let n = r.read(&mut buf).unwrap();
-        println!("[Decoder] Read {} b1ytes", n);
+        let mut buf = vec![0; self.read_buf_size];
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            // the peer has closed the connection
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        println!("[Decoder] Read {} bytes", n);
         msg.payload = buf[..n].to_vec();
         // let buf = read_all_from_stream(r).unwrap();
         // msg.payload = buf;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn test_length_prefixed_round_trip() {
+        let mut wire = Vec::new();
+        LengthPrefixedEncoder {}.encode(b"first", &mut wire).unwrap();
+        LengthPrefixedEncoder {}.encode(b"second message", &mut wire).unwrap();
+
+        // two frames back to back are split apart again
+        let decoder = LengthPrefixedDecoder::new();
+        let mut r = wire.as_slice();
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"first".to_vec());
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"second message".to_vec());
+        // nothing left
+        assert!(decoder.decode(&mut r, &mut msg).is_err());
+    }
+
+    /// hands out one byte per read, like a connection on which every byte arrives in its own segment
+    struct ByteByByte<'a>(&'a [u8]);
+
+    impl io::Read for ByteByByte<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match (self.0.split_first(), buf.first_mut()) {
+                (Some((byte, rest)), Some(slot)) => {
+                    *slot = *byte;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_frames_reassembled_from_partial_reads() {
+        let body: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        let store_frame = [&[1u8][..], &body].concat();
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+
+        let mut wire = Vec::new();
+        LengthPrefixedEncoder {}.encode(&body, &mut wire).unwrap();
+        LengthPrefixedEncoder {}.encode(b"next", &mut wire).unwrap();
+        let mut r = ByteByByte(&wire);
+        LengthPrefixedDecoder::new().decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, body);
+        LengthPrefixedDecoder::new().decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"next".to_vec());
+
+        let mut wire = Vec::new();
+        ChecksumEncoder::new(Arc::new(TypedFrameEncoder {})).encode(&store_frame, &mut wire).unwrap();
+        let decoder = ChecksumDecoder::new(Box::new(TypedFrameDecoder::new()));
+        decoder.decode(&mut ByteByByte(&wire), &mut msg).unwrap();
+        assert_eq!(msg.payload, store_frame);
+
+        let mut wire = Vec::new();
+        TypedFrameEncoder {}.encode(&store_frame, &mut wire).unwrap();
+        // running out in the middle of the body is still an error
+        let err = TypedFrameDecoder::new().decode(&mut ByteByByte(&wire[..100]), &mut msg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_default_decoder_read_buf_size() {
+        let decoder = DefaultDecoder { read_buf_size: 4 };
+        let mut r = b"0123456".as_slice();
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+
+        // without framing, the bytes are cut wherever the buffer is full
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"0123".to_vec());
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"456".to_vec());
+        assert_eq!(decoder.decode(&mut r, &mut msg).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_length_prefixed_rejects_oversized_frame() {
+        let mut wire = Vec::new();
+        LengthPrefixedEncoder {}.encode(&[0; 16], &mut wire).unwrap();
+
+        let decoder = LengthPrefixedDecoder { max_payload_size: 8 };
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+        let err = decoder.decode(&mut wire.as_slice(), &mut msg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_typed_frame_header_read_before_body() {
+        let store_frame = [&[1u8][..], &[7; 100]].concat();
+        let mut wire = Vec::new();
+        TypedFrameEncoder {}.encode(&store_frame, &mut wire).unwrap();
+        TypedFrameEncoder {}.encode(b"\0ping", &mut wire).unwrap();
+
+        // the type is known while the whole body is still unread
+        let decoder = TypedFrameDecoder::new();
+        let mut r = wire.as_slice();
+        assert_eq!(decoder.read_header(&mut r).unwrap(), FrameHeader { msg_type: 1, len: 100 });
+        assert_eq!(&r[..100], &[7; 100][..]);
+
+        // decoding gives back the buffers given to the encoder
+        let mut r = wire.as_slice();
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, store_frame);
+        decoder.decode(&mut r, &mut msg).unwrap();
+        assert_eq!(msg.payload, b"\0ping".to_vec());
+
+        // a frame needs at least its type
+        assert_eq!(TypedFrameEncoder {}.encode(&[], &mut wire).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_checksum_rejects_flipped_byte() {
+        let encoder = ChecksumEncoder::new(Arc::new(TypedFrameEncoder {}));
+        let decoder = ChecksumDecoder::new(Box::new(TypedFrameDecoder::new()));
+        let mut wire = Vec::new();
+        encoder.encode(b"\0some message", &mut wire).unwrap();
+
+        let mut msg = Message::new(SocketAddr::from(([127, 0, 0, 1], 3000)));
+        decoder.decode(&mut wire.as_slice(), &mut msg).unwrap();
+        assert_eq!(msg.payload, b"\0some message".to_vec());
+
+        // the byte after the type and the length prefix
+        wire[5] ^= 0x01;
+        let err = decoder.decode(&mut wire.as_slice(), &mut msg).unwrap_err();
+        assert!(is_checksum_mismatch(&err));
+        // other decoding errors are not mistaken for it
+        assert!(!is_checksum_mismatch(&decoder.decode(&mut [].as_slice(), &mut msg).unwrap_err()));
+    }
+}
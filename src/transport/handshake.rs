@@ -1,4 +1,31 @@
 use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::codec::Codec;
+
+/// size of the challenge sent by the accepting side
+const NONCE_SIZE: usize = 32;
+/// size of the HMAC-SHA256 answering the challenge
+const RESPONSE_SIZE: usize = 32;
+/// labels the answer of the dialing side in the signed data
+const DIALER: &[u8] = b"dialer";
+/// labels the answer of the accepting side in the signed data
+const ACCEPTOR: &[u8] = b"acceptor";
+/// the most bytes of tags a peer may advertise
+const MAX_TAGS_SIZE: usize = 64 * 1024;
+/// the most bytes of listen address a peer may advertise
+const MAX_ADDR_SIZE: usize = 64;
+/// the size of a weight, a u32
+const WEIGHT_SIZE: usize = 4;
+/// the most bytes of codecs a peer may advertise
+const MAX_CODECS_SIZE: usize = 256;
 
 #[derive(Debug)]
 pub struct ErrInvalidHandshake;
@@ -7,4 +34,115 @@ impl Display for ErrInvalidHandshake {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Invalid Handshake")
     }
-}
\ No newline at end of file
+}
+
+impl std::error::Error for ErrInvalidHandshake {}
+
+/// the accepting side of the shared secret handshake.  
+/// send a random nonce, check that the dialer answers with its own nonce signed together with ours, then sign both
+/// nonces back so that the dialer can check this side knows the secret too
+pub fn challenge(secret: &[u8], r: &mut dyn Read, w: &mut dyn Write) -> Result<(), ErrInvalidHandshake> {
+    let nonce = make_nonce()?;
+    w.write_all(&nonce).map_err(|_| ErrInvalidHandshake)?;
+    w.flush().map_err(|_| ErrInvalidHandshake)?;
+
+    let mut theirs = [0; NONCE_SIZE];
+    r.read_exact(&mut theirs).map_err(|_| ErrInvalidHandshake)?;
+    let mut response = [0; RESPONSE_SIZE];
+    r.read_exact(&mut response).map_err(|_| ErrInvalidHandshake)?;
+    // MacResult compares in constant time so that the expected answer cannot be guessed byte by byte
+    if sign(secret, DIALER, &nonce, &theirs) != MacResult::new(&response) {
+        return Err(ErrInvalidHandshake);
+    }
+
+    w.write_all(sign(secret, ACCEPTOR, &nonce, &theirs).code()).map_err(|_| ErrInvalidHandshake)?;
+    w.flush().map_err(|_| ErrInvalidHandshake)
+}
+
+/// the dialing side of the shared secret handshake. answer the nonce sent by `challenge` with a nonce of our own
+/// and check the signature the accepting side sends back
+pub fn respond(secret: &[u8], r: &mut dyn Read, w: &mut dyn Write) -> Result<(), ErrInvalidHandshake> {
+    let mut theirs = [0; NONCE_SIZE];
+    r.read_exact(&mut theirs).map_err(|_| ErrInvalidHandshake)?;
+    let nonce = make_nonce()?;
+    w.write_all(&nonce).map_err(|_| ErrInvalidHandshake)?;
+    w.write_all(sign(secret, DIALER, &theirs, &nonce).code()).map_err(|_| ErrInvalidHandshake)?;
+    w.flush().map_err(|_| ErrInvalidHandshake)?;
+
+    let mut response = [0; RESPONSE_SIZE];
+    r.read_exact(&mut response).map_err(|_| ErrInvalidHandshake)?;
+    match sign(secret, ACCEPTOR, &theirs, &nonce) == MacResult::new(&response) {
+        true => Ok(()),
+        false => Err(ErrInvalidHandshake),
+    }
+}
+
+/// send the tags of this node to the peer and return the ones the peer sent. both sides call it once connected
+pub fn exchange_tags(tags: &[String], r: &mut dyn Read, w: &mut dyn Write) -> Result<Vec<String>, ErrInvalidHandshake> {
+    exchange(tags, MAX_TAGS_SIZE, r, w)
+}
+
+/// send the address this node listens on to the peer and return the one the peer sent. both sides call it once
+/// connected
+pub fn exchange_listen_addr(addr: SocketAddr, r: &mut dyn Read, w: &mut dyn Write) -> Result<SocketAddr, ErrInvalidHandshake> {
+    exchange(&addr, MAX_ADDR_SIZE, r, w)
+}
+
+/// send the weight of this node to the peer and return the one the peer sent. both sides call it once connected
+pub fn exchange_weight(weight: u32, r: &mut dyn Read, w: &mut dyn Write) -> Result<u32, ErrInvalidHandshake> {
+    exchange(&weight, WEIGHT_SIZE, r, w)
+}
+
+/// send the ids of the codecs this node supports to the peer and return the ones the peer sent, leaving out the
+/// codecs this node doesn't know. both sides call it once connected
+pub fn exchange_codecs(codecs: &[Codec], r: &mut dyn Read, w: &mut dyn Write) -> Result<Vec<Codec>, ErrInvalidHandshake> {
+    let ids: Vec<u8> = codecs.iter().map(|codec| codec.id()).collect();
+    let theirs: Vec<u8> = exchange(&ids, MAX_CODECS_SIZE, r, w)?;
+
+    Ok(theirs.into_iter().filter_map(Codec::from_id).collect())
+}
+
+/// send the value to the peer, length prefixed, and return the value the peer sent, of at most `max_size` bytes
+fn exchange<V: Serialize + ?Sized, W: DeserializeOwned>(
+    value: &V,
+    max_size: usize,
+    r: &mut dyn Read,
+    w: &mut dyn Write,
+) -> Result<W, ErrInvalidHandshake> {
+    let buf = bincode::serialize(value).map_err(|_| ErrInvalidHandshake)?;
+    w.write_all(&(buf.len() as u32).to_be_bytes()).map_err(|_| ErrInvalidHandshake)?;
+    w.write_all(&buf).map_err(|_| ErrInvalidHandshake)?;
+    w.flush().map_err(|_| ErrInvalidHandshake)?;
+
+    let mut len = [0; 4];
+    r.read_exact(&mut len).map_err(|_| ErrInvalidHandshake)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_size {
+        return Err(ErrInvalidHandshake);
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf).map_err(|_| ErrInvalidHandshake)?;
+
+    bincode::deserialize(&buf).map_err(|_| ErrInvalidHandshake)
+}
+
+fn make_nonce() -> Result<[u8; NONCE_SIZE], ErrInvalidHandshake> {
+    let mut nonce = [0; NONCE_SIZE];
+    rustls::crypto::ring::default_provider()
+        .secure_random
+        .fill(&mut nonce)
+        .map_err(|_| ErrInvalidHandshake)?;
+
+    Ok(nonce)
+}
+
+/// sign the nonces of both sides along with the direction of the answer, so that an answer cannot be reflected back
+/// to the side that sent it
+fn sign(secret: &[u8], direction: &[u8], acceptor_nonce: &[u8], dialer_nonce: &[u8]) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), secret);
+    hmac.input(direction);
+    hmac.input(acceptor_nonce);
+    hmac.input(dialer_nonce);
+
+    hmac.result()
+}
@@ -1,7 +1,13 @@
+#[cfg(test)]
+pub mod delay;
+pub mod codec;
 pub mod encoding;
 pub mod message;
+pub mod pool;
+#[allow(clippy::module_inception)]
 pub mod transport;
 pub mod tcp;
+pub mod tls;
 
 /** TODO: can i make it generic to net protocol? */
 mod handshake;
\ No newline at end of file
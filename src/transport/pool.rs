@@ -0,0 +1,110 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// a fixed size pool of worker threads
+/// jobs are queued and picked up by the first idle worker, so the number of OS threads
+/// stays at `size` no matter how many jobs are submitted
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Mutex<Option<Sender<Job>>>,
+}
+
+impl ThreadPool {
+    /// create a pool with `size` workers. a size of 0 is bumped to 1
+    pub fn new(size: usize) -> ThreadPool {
+        let size = size.max(1);
+        let (sender, receiver): (Sender<Job>, Receiver<Job>) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    // the lock is released as soon as a job is taken off the queue
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            // a panicking job should not take the worker down with it
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                println!("[ThreadPool] job panicked");
+                            }
+                        }
+                        // the pool has been dropped
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Mutex::new(Some(sender)),
+        }
+    }
+
+    /// number of worker threads in the pool
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// queue a job to be run by the next idle worker
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &*self.sender.lock().unwrap() {
+            sender.send(Box::new(f)).unwrap();
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel lets idle workers exit their loop
+        self.sender.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_pool_runs_all_jobs() {
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let (done_tx, done_rx) = channel();
+
+        for _ in 0..10 {
+            let counter = counter.clone();
+            let done_tx = done_tx.clone();
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                done_tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..10 {
+            done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        assert_eq!(pool.size(), 2);
+        assert_eq!(counter.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_pool_survives_panicking_job() {
+        let pool = ThreadPool::new(1);
+        let (done_tx, done_rx) = channel();
+        pool.execute(|| panic!("boom"));
+        pool.execute(move || done_tx.send(()).unwrap());
+
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
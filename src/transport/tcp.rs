@@ -1,53 +1,247 @@
-use std::char::MAX;
 use std::collections::HashMap;
-use std::io::Write;
-use std::sync::mpsc::{channel, Receiver, Sender, RecvTimeoutError};
+use std::io::{Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, RecvError, RecvTimeoutError, SyncSender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{io, thread};
 use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
 
 use crate::transport::message::Message;
 use crate::transport::transport::Transport;
 
-use super::encoding::Decoder;
-use super::transport::{HandShakeFn, PeerLike};
+use super::codec::{self, Codec};
+use super::encoding::{is_checksum_mismatch, Decoder, DefaultEncoder, Encoder};
+use super::handshake;
+use super::pool::ThreadPool;
+use super::tls::{TlsConfig, TlsStream};
+use super::transport::{HandShakeFn, OnPeerDisconnectFn, OnPeerFn, PeerLike, DEFAULT_MAX_DIAL_BACKOFF};
+
+/// default number of worker threads serving connections, and so the most peers a node has at once.
+/// see `TcpTransportOpts::max_workers`
+pub const DEFAULT_MAX_WORKERS: usize = 32;
+/// the maximum time a peer has to answer the shared secret challenge
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// default number of received messages queued until they are consumed
+pub const DEFAULT_MSG_CHAN_CAPACITY: usize = 1024;
+/// how often the paused accept loop checks whether it has been resumed
+const ACCEPT_PAUSE_POLL: Duration = Duration::from_millis(50);
+/// default time `dial` waits for a peer to accept the connection
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// the largest piece written at once by a peer with a send rate, see `TcpTransportOpts::max_send_rate`
+const SEND_PACING_CHUNK: usize = 16 * 1024;
+
+/// limit the rate of the messages read from a peer, see `TcpTransportOpts::max_msg_rate`
+struct TokenBucket {
+    /// tokens added per second, and the most the bucket holds
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    /// since when the peer has been over the rate without a break
+    throttled_since: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled_at: Instant::now(),
+            throttled_since: None,
+        }
+    }
+
+    /// take a token for a message. return how long to wait before handling it, zero if a token was available
+    fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            self.throttled_since = None;
+            return Duration::ZERO;
+        }
+        self.throttled_since.get_or_insert(now);
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// how long the peer has been over the rate without a break
+    fn throttled_for(&self) -> Duration {
+        self.throttled_since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+}
+
+/// where the connection to a peer is in its lifecycle. only a `Ready` peer is sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// connected, going through the handshakes
+    Connecting,
+    /// done with the handshakes, messages can be sent
+    Ready,
+    /// being closed
+    Closing,
+    /// the connection is gone
+    Closed,
+}
 
 /// the peer struct is responsible for the connection between nodes
 pub struct TcpPeer {
     /// the underlying connection of the peer
     conn: TcpStream,
+    /// the encrypted stream over `conn` if tls is enabled. all data must go through it instead of `conn`
+    tls: Option<TlsStream>,
+    /// frames everything sent to the peer
+    encoder: Arc<dyn Encoder>,
     /// if dial and retrieve the connection => outbound = true  
     /// if accept and retrieve the connection => outbound = false
     outbound: bool,
+    /// the tags the peer advertised, see `TcpTransportOpts::tags`
+    tags: Vec<String>,
+    /// the weight the peer advertised, see `TcpTransportOpts::weight`
+    weight: u32,
+    /// the address the peer is known by: the one it listens on if it advertised it, see
+    /// `TcpTransportOpts::advertise_addr`, otherwise the one of the other end of the connection.
+    /// kept here since the connection can't tell it anymore once shut down
+    addr: Option<SocketAddr>,
+    /// bytes per second `send` stays under, see `TcpTransportOpts::max_send_rate`
+    max_send_rate: Option<u64>,
+    /// the codecs both sides support, ours first, if they were exchanged. see `TcpTransportOpts::codecs`
+    codecs: Option<Vec<Codec>>,
+    /// behind a mutex, since `close` only has a shared reference
+    state: Mutex<PeerState>,
 }
 
 impl TcpPeer {
     pub fn new(conn: TcpStream, outbound: bool) -> TcpPeer {
         TcpPeer {
+            tls: None,
+            encoder: Arc::new(DefaultEncoder {}),
+            outbound,
+            tags: Vec::new(),
+            weight: 1,
+            addr: conn.peer_addr().ok(),
+            max_send_rate: None,
+            codecs: None,
+            state: Mutex::new(PeerState::Connecting),
             conn,
+        }
+    }
+
+    /// create a peer whose traffic is encrypted by the given tls stream
+    pub fn with_tls(conn: TcpStream, tls: TlsStream, outbound: bool) -> TcpPeer {
+        TcpPeer {
+            tls: Some(tls),
+            encoder: Arc::new(DefaultEncoder {}),
             outbound,
+            tags: Vec::new(),
+            weight: 1,
+            addr: conn.peer_addr().ok(),
+            max_send_rate: None,
+            codecs: None,
+            state: Mutex::new(PeerState::Connecting),
+            conn,
+        }
+    }
+
+    /// frame everything sent to the peer with the given encoder
+    pub fn with_encoder(mut self, encoder: Arc<dyn Encoder>) -> TcpPeer {
+        self.encoder = encoder;
+        self
+    }
+
+    /// pace what is sent to the peer to stay under the rate, in bytes per second
+    pub fn with_send_rate(mut self, max_send_rate: Option<u64>) -> TcpPeer {
+        self.max_send_rate = max_send_rate;
+        self
+    }
+
+    pub fn state(&self) -> PeerState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: PeerState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        match &mut self.tls {
+            Some(tls) => tls.write_all(buf),
+            None => self.conn.write_all(buf),
         }
     }
 }
 
 impl PeerLike for TcpPeer {
     fn addr(&self) -> SocketAddr {
-        self.conn.peer_addr().unwrap()
+        match self.addr {
+            Some(addr) => addr,
+            None => self.conn.peer_addr().unwrap(),
+        }
     }
 
     fn close(&self) -> Result<(), io::Error> {
-        self.conn.shutdown(Shutdown::Both)
+        self.set_state(PeerState::Closing);
+        if let Some(tls) = &self.tls {
+            // best effort. the socket is shut down anyway
+            let _ = tls.close();
+        }
+        let res = self.conn.shutdown(Shutdown::Both);
+        self.set_state(PeerState::Closed);
+
+        res
     }
 
+    /// fail with `io::ErrorKind::NotConnected` unless the peer is `Ready`
     fn send(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        let state = self.state();
+        if state != PeerState::Ready {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is {:?}", self.addr(), state)));
+        }
         println!("Sending data to {}: {}", self.addr(), String::from_utf8_lossy(buf));
-        self.conn.write_all(buf)
+        let encoded;
+        let buf = match &self.codecs {
+            Some(codecs) => {
+                encoded = codec::encode(codecs, buf);
+                &encoded
+            },
+            None => buf,
+        };
+        // encode the whole frame first so that it goes out in a single write
+        let mut frame = Vec::with_capacity(buf.len());
+        self.encoder.encode(buf, &mut frame)?;
+        let rate = match self.max_send_rate {
+            Some(rate) => rate.max(1),
+            None => return self.write_all(&frame),
+        };
+
+        // a tenth of a second worth at most, so that the pace stays even at low rates
+        let chunk_size = ((rate / 10) as usize).clamp(1, SEND_PACING_CHUNK);
+        let start = Instant::now();
+        let mut written = 0;
+        for chunk in frame.chunks(chunk_size) {
+            self.write_all(chunk)?;
+            written += chunk.len();
+            let due = Duration::from_secs_f64(written as f64 / rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        Ok(())
     }
 
     fn is_outbound(&self) -> bool {
         self.outbound
     }
+
+    fn tags(&self) -> Vec<String> {
+        self.tags.clone()
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
 }
 
 /// defines the configuration of the tcp transport layer
@@ -56,6 +250,53 @@ pub struct TcpTransportOpts {
     /// allow the handshake function to be passed from the constructor
     pub shakehands: Option<HandShakeFn<TcpPeer>>,
     pub decoder: Box<dyn Decoder>,
+    /// frames the data sent to peers. must match the decoder used on the other end
+    pub encoder: Arc<dyn Encoder>,
+    /// maximum number of worker threads serving connections.  
+    /// each connected peer occupies a worker for as long as it is connected, so once they are all taken
+    /// incoming connections are closed right away and `dial` fails, until a peer disconnects.
+    /// this is a hard cap on the number of peers: with `DEFAULT_MAX_WORKERS` a node connects to at most 32 others,
+    /// so a larger cluster must raise it to at least the number of nodes it expects to talk to
+    pub max_workers: usize,
+    /// maximum number of received messages waiting to be consumed.  
+    /// once it is reached, the connections stop reading until a message is consumed, so a fast sender is slowed down
+    /// by tcp flow control instead of filling up the memory. messages are never dropped, but a slow consumer
+    /// stalls every connection, and the peers block in `send` once their socket buffers are full
+    pub msg_chan_capacity: usize,
+    /// encrypt and authenticate all connections with tls. plaintext tcp is used if None
+    pub tls: Option<TlsConfig>,
+    /// only accept peers proving they know this secret, with an HMAC challenge-response when they connect.  
+    /// all the nodes of a cluster must share the same secret. anyone can connect if None
+    pub secret: Option<Vec<u8>>,
+    /// advertise these tags to the peers when connecting, and learn theirs, e.g. to tell cold storage nodes
+    /// from edge caches. like `secret`, either all the nodes of a cluster exchange tags or none do
+    pub tags: Option<Vec<String>>,
+    /// how long `dial` waits for the connection to be accepted. without it, an unreachable address blocks for
+    /// the os default, often over a minute. must not be zero
+    pub connect_timeout: Duration,
+    /// tell the peers the address this node listens on when connecting, and know each peer by the address it
+    /// advertised rather than by the one of the connection, which is an ephemeral port for the peers that dialed in.
+    /// a peer connected twice, e.g. dialed and dialed back, then keeps its first connection only.
+    /// like `tags`, either all the nodes of a cluster advertise their address or none do
+    pub advertise_addr: bool,
+    /// messages per second each peer may send, in bursts of up to as many. the connection of a peer going faster
+    /// is not read until it is back under the rate, so that it can't flood the message channel. unlimited if None
+    pub max_msg_rate: Option<u32>,
+    /// drop a peer that keeps going over `max_msg_rate` for this long, rather than only slowing it down
+    pub rate_limit_drop_after: Option<Duration>,
+    /// bytes per second sent to each peer, so that broadcasting a large file doesn't take the whole uplink.
+    /// `send` writes in small pieces and sleeps in between to stay under it. unlimited if None
+    pub max_send_rate: Option<u64>,
+    /// advertise this weight to the peers when connecting, and learn theirs, so that the nodes with more room get
+    /// proportionally more of the keys. e.g. the disk size in hundreds of GB. like `tags`, either all the nodes of a
+    /// cluster advertise a weight or none do, in which case they all weigh 1
+    pub weight: Option<u32>,
+    /// advertise these codecs to the peers when connecting, best first, and send each message with the first one
+    /// the peer supports too that makes it smaller. a peer supporting none of them gets the messages as they are,
+    /// see `Codec::Identity`. like `tags`, either all the nodes of a cluster advertise codecs or none do
+    pub codecs: Option<Vec<Codec>>,
+    /// the longest `Transport::try_dial` waits between two attempts, so that the backoff doesn't grow to minutes
+    pub max_dial_backoff: Duration,
 }
 
 impl TcpTransportOpts {
@@ -64,45 +305,127 @@ impl TcpTransportOpts {
             listen_addr,
             shakehands: Option::None,
             decoder,
+            encoder: Arc::new(DefaultEncoder {}),
+            max_workers: DEFAULT_MAX_WORKERS,
+            msg_chan_capacity: DEFAULT_MSG_CHAN_CAPACITY,
+            tls: None,
+            secret: None,
+            tags: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            advertise_addr: false,
+            max_msg_rate: None,
+            rate_limit_drop_after: None,
+            max_send_rate: None,
+            weight: None,
+            codecs: None,
+            max_dial_backoff: DEFAULT_MAX_DIAL_BACKOFF,
         }
     }
 }
 
+/// a connection done with the handshakes, see `TcpTransport::handle_conn`
+type ReadyConn = (SocketAddr, Arc<RwLock<TcpPeer>>, Box<dyn Read + Send>);
+
+/// both ends of a channel of received messages
+type Lane<T> = (Mutex<SyncSender<T>>, Mutex<Receiver<T>>);
+
+/// replace the channel of the lane with a new one of `capacity`, moving over the messages left in the old one
+fn reattach_lane<T>(lane: &Lane<T>, capacity: usize) {
+    // the receiver is locked first, as in `consume`
+    let mut receiver = lane.1.lock().unwrap();
+    let (sender, new_receiver) = sync_channel(capacity);
+    for msg in std::iter::from_fn(|| receiver.try_recv().ok()) {
+        let _ = sender.try_send(msg);
+    }
+    *receiver = new_receiver;
+    *lane.0.lock().unwrap() = sender;
+}
+
+/// a pool worker taken by a connection, given back once dropped at the end of its job, even if the job panics
+struct WorkerSlot(Arc<TcpTransport>);
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.0.busy_workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 /// TCPTransport maintains the tcp transport layer and connection with other peer nodes
 pub struct TcpTransport {
     pub opts: TcpTransportOpts,
     listener: TcpListener,
-    msg_chan: (Mutex<Sender<Message>>, Mutex<Receiver<Message>>),
+    pool: ThreadPool,
+    /// number of pool workers taken by a connection, see `take_worker`
+    busy_workers: AtomicUsize,
+    /// the normal lane. None is only there to wake up `consume` when a message is put in the priority lane,
+    /// or on `wake`
+    msg_chan: Lane<Option<Message>>,
+    /// messages the decoder flagged with `Decoder::is_priority`, consumed before the normal lane
+    priority_chan: Lane<Message>,
 
     peers: RwLock<HashMap<SocketAddr, Arc<RwLock<TcpPeer>>>>,
-    on_peer: Arc<Mutex<Option<Box<dyn Fn(Arc<RwLock<TcpPeer>>) -> bool + Send + Sync + 'static>>>>,
+    on_peer: Arc<Mutex<Option<OnPeerFn<TcpPeer>>>>,
+    on_peer_disconnect: Mutex<Option<OnPeerDisconnectFn>>,
+    /// set by `pause_accept`
+    accept_paused: AtomicBool,
+    /// set once the accept loop started by `listen_and_accept` is running
+    accepting: AtomicBool,
+    /// set by `wake`, until `consume_blocking` returns for it
+    woken: AtomicBool,
 }
 
 // section: implement the transport layer
 
 impl TcpTransport {
-    /// create a new tcp transport layer
-    pub fn new(opts: TcpTransportOpts) -> Arc<TcpTransport> {
-        let listener = TcpListener::bind(&opts.listen_addr).unwrap();
-        let channel: (Sender<Message>, Receiver<Message>) = channel();
-        Arc::new(TcpTransport {
+    /// create a new tcp transport layer listening on `opts.listen_addr`  
+    /// fail if the address cannot be bound, e.g. with `AddrInUse` when the port is taken
+    pub fn new(opts: TcpTransportOpts) -> Result<Arc<TcpTransport>, io::Error> {
+        let listener = TcpListener::bind(&opts.listen_addr)?;
+        let channel: (SyncSender<Option<Message>>, Receiver<Option<Message>>) = sync_channel(opts.msg_chan_capacity);
+        let priority_channel: (SyncSender<Message>, Receiver<Message>) = sync_channel(opts.msg_chan_capacity);
+        let pool = ThreadPool::new(opts.max_workers);
+        Ok(Arc::new(TcpTransport {
             opts,
             listener,
+            pool,
+            busy_workers: AtomicUsize::new(0),
             msg_chan: (Mutex::new(channel.0), Mutex::new(channel.1)),
+            priority_chan: (Mutex::new(priority_channel.0), Mutex::new(priority_channel.1)),
             peers: RwLock::new(HashMap::new()),
             on_peer: Arc::new(Mutex::new(Option::None)),
-        })
+            on_peer_disconnect: Mutex::new(Option::None),
+            accept_paused: AtomicBool::new(false),
+            accepting: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+        }))
     }
 
     /// create a blocking loop to accept incoming connections
     fn start_accept(self: &Arc<Self>) {
+        self.accepting.store(true, Ordering::SeqCst);
         for stream in self.listener.incoming() {
+            // hold the connection until accepting is resumed. the next ones wait in the listen backlog
+            while self.accept_paused.load(Ordering::SeqCst) {
+                thread::sleep(ACCEPT_PAUSE_POLL);
+            }
             match stream {
                 Ok(stream) => {
-                    // received a new connection. handle the connection and unblock the thread
+                    // received a new connection. hand it over to the pool and unblock the thread.
+                    // it would wait for a worker as long as another peer stays connected, so it is refused instead
+                    let slot = match self.take_worker() {
+                        Some(slot) => slot,
+                        None => {
+                            println!("Error: refusing connection from {:?}, all {} workers are busy", stream.peer_addr(), self.pool.size());
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
                     let self_clone = self.clone();
-                    thread::spawn(move || {
-                        self_clone.clone().handle_conn(stream, false);
+                    self.pool.execute(move || {
+                        let _slot = slot;
+                        if let Some((peer_addr, peer, reader)) = self_clone.handle_conn(stream, false) {
+                            self_clone.read_loop(reader, peer_addr, peer);
+                        }
                     });
                 }
                 Err(e) => {
@@ -112,14 +435,128 @@ impl TcpTransport {
         }
     }
 
+    /// take a pool worker for a connection, unless they are all busy serving other connections
+    fn take_worker(self: &Arc<Self>) -> Option<WorkerSlot> {
+        let size = self.pool.size();
+        self.busy_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |busy| (busy < size).then_some(busy + 1))
+            .ok()
+            .map(|_| WorkerSlot(self.clone()))
+    }
+
     /// tcp layer for handling after the connection is established between nodes  
-    /// it handles the handshake and store the peer in the peers list
-    fn handle_conn(&self, conn: TcpStream, outbound: bool) {
-        let peer_addr = conn.peer_addr().unwrap();
-        let peer = Arc::new(RwLock::new(
-            TcpPeer::new(conn.try_clone().unwrap(), 
-            outbound)
-        )); // inbound connection
+    /// it handles the handshake and store the peer in the peers list.  
+    /// return the address of the peer, the peer and the stream to read from if it is ready
+    fn handle_conn(&self, conn: TcpStream, outbound: bool) -> Option<ReadyConn> {
+        let mut peer_addr = match conn.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                println!("Error getting peer address: {}", e);
+                return None;
+            }
+        };
+        // wrap the connection with tls first so that everything after, including the handshake, is encrypted
+        let (mut peer, mut reader, mut writer): (TcpPeer, Box<dyn Read + Send>, Box<dyn Write + Send>) = match &self.opts.tls {
+            Some(tls) => {
+                let stream = match outbound {
+                    true => tls.connect(conn.try_clone().unwrap(), peer_addr),
+                    false => tls.accept(conn.try_clone().unwrap()),
+                };
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("TLS handshake with {} failed: {}", peer_addr, e);
+                        let _ = conn.shutdown(Shutdown::Both);
+                        return None;
+                    }
+                };
+                let reader = Box::new(stream.try_clone().unwrap());
+                let writer = Box::new(stream.try_clone().unwrap());
+                (TcpPeer::with_tls(conn, stream, outbound).with_encoder(self.opts.encoder.clone()).with_send_rate(self.opts.max_send_rate), reader, writer)
+            },
+            None => {
+                let reader = Box::new(conn.try_clone().unwrap());
+                let writer = Box::new(conn.try_clone().unwrap());
+                (TcpPeer::new(conn, outbound).with_encoder(self.opts.encoder.clone()).with_send_rate(self.opts.max_send_rate), reader, writer)
+            },
+        };
+
+        // the tls stream polls the socket with its own read timeout, so only plain tcp gets one here
+        let plain = self.opts.tls.is_none();
+        if plain {
+            let _ = peer.conn.set_read_timeout(Some(AUTH_TIMEOUT));
+        }
+        // authenticate the peer before anything else goes through the connection
+        if let Some(secret) = &self.opts.secret {
+            let res = match outbound {
+                true => handshake::respond(secret, &mut reader, &mut writer),
+                false => handshake::challenge(secret, &mut reader, &mut writer),
+            };
+            if let Err(e) = res {
+                println!("Authentication with {} failed: {}", peer_addr, e);
+                let _ = peer.close();
+                return None;
+            }
+        }
+        if let Some(tags) = &self.opts.tags {
+            match handshake::exchange_tags(tags, &mut reader, &mut writer) {
+                Ok(tags) => peer.tags = tags,
+                Err(e) => {
+                    println!("Exchanging tags with {} failed: {}", peer_addr, e);
+                    let _ = peer.close();
+                    return None;
+                },
+            }
+        }
+        if let Some(weight) = self.opts.weight {
+            match handshake::exchange_weight(weight, &mut reader, &mut writer) {
+                Ok(weight) => peer.weight = weight,
+                Err(e) => {
+                    println!("Exchanging weights with {} failed: {}", peer_addr, e);
+                    let _ = peer.close();
+                    return None;
+                },
+            }
+        }
+        if let Some(codecs) = &self.opts.codecs {
+            match handshake::exchange_codecs(codecs, &mut reader, &mut writer) {
+                Ok(theirs) => peer.codecs = Some(codec::common_codecs(codecs, &theirs)),
+                Err(e) => {
+                    println!("Exchanging codecs with {} failed: {}", peer_addr, e);
+                    let _ = peer.close();
+                    return None;
+                },
+            }
+        }
+        if self.opts.advertise_addr {
+            let res = self.listener.local_addr()
+                .and_then(|addr| handshake::exchange_listen_addr(addr, &mut reader, &mut writer).map_err(io::Error::other));
+            match res {
+                Ok(addr) => {
+                    // a node listening on all interfaces is reached on the address it connected from
+                    let addr = match addr.ip().is_unspecified() {
+                        true => SocketAddr::new(peer_addr.ip(), addr.port()),
+                        false => addr,
+                    };
+                    peer.addr = Some(addr);
+                    peer_addr = addr;
+                },
+                Err(e) => {
+                    println!("Exchanging listen addresses with {} failed: {}", peer_addr, e);
+                    let _ = peer.close();
+                    return None;
+                },
+            }
+            if self.peers.read().unwrap().contains_key(&peer_addr) {
+                println!("Already connected to {}, dropping the new connection", peer_addr);
+                let _ = peer.close();
+                return None;
+            }
+        }
+        if plain {
+            let _ = peer.conn.set_read_timeout(None);
+        }
+        let peer = Arc::new(RwLock::new(peer));
 
         // perform the handshake
         match self.opts.shakehands {
@@ -127,8 +564,8 @@ impl TcpTransport {
                 match shakehands(&peer) {
                     Ok(_) => println!("Handshake with {} successful", peer.read().unwrap().addr()),
                     Err(_) => {
-                        peer.write().unwrap().close().unwrap();
-                        return;
+                        let _ = peer.write().unwrap().close();
+                        return None;
                     },
                 };
             },
@@ -136,6 +573,7 @@ impl TcpTransport {
                 println!("No handshake function provided");
             }
         }
+        peer.read().unwrap().set_state(PeerState::Ready);
 
         // call the on_peer function
         let on_peer = self.on_peer.lock().unwrap();
@@ -147,8 +585,8 @@ impl TcpTransport {
                     // remove the peer from the peers list
                     self.peers.write().unwrap().remove(&peer.read().unwrap().addr());
                     // close the peer
-                    peer.write().unwrap().close().unwrap();
-                    return;
+                    let _ = peer.write().unwrap().close();
+                    return None;
                 },
             };
         }
@@ -156,33 +594,108 @@ impl TcpTransport {
         // add the peer to the peers list
         self.peers.write().unwrap().insert(peer_addr, peer.clone());
 
-        // read from the connection
-        println!("Starting to read from connection: {}", peer.read().unwrap().addr());
+        Some((peer_addr, peer, reader))
+    }
+
+    /// a blocking loop reading messages from the connection until it is closed
+    fn read_loop(&self, mut conn: Box<dyn Read + Send>, peer_addr: SocketAddr, peer: Arc<RwLock<TcpPeer>>) {
+        println!("Starting to read from connection: {}", peer_addr);
+        let mut bucket = self.opts.max_msg_rate.map(TokenBucket::new);
         loop {
             let mut msg = Message::new(peer_addr);
-            match self.opts.decoder.decode(&mut conn.try_clone().unwrap(), &mut msg) {
+            match self.opts.decoder.decode(&mut conn, &mut msg) {
                 Ok(_) => {
                     println!("Received data from {}: {}", msg.from, String::from_utf8_lossy(&msg.payload));
                 }
+                // the frame was corrupted on the way, but the next one can still be read
+                Err(e) if is_checksum_mismatch(&e) => {
+                    println!("Error: dropping frame from {}: {}", peer_addr, e);
+                    continue;
+                }
                 Err(e) => {
                     println!("Error reading from connection: {}", e);
                     break;
                 }
             }
+            if self.opts.codecs.is_some() {
+                match codec::decode(&msg.payload) {
+                    Ok(payload) => msg.payload = payload,
+                    Err(e) => {
+                        println!("Error: dropping message from {}: {}", peer_addr, e);
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(bucket) = &mut bucket {
+                let wait = bucket.take();
+                if self.opts.rate_limit_drop_after.is_some_and(|limit| bucket.throttled_for() >= limit) {
+                    println!("Error: {} keeps going over the message rate, dropping it", peer_addr);
+                    let _ = peer.read().unwrap().close();
+                    break;
+                }
+                thread::sleep(wait);
+            }
+
+            // send the message to its lane. blocks while the lane is full
+            let sent = if self.opts.decoder.is_priority(&msg) {
+                let sender = self.priority_chan.0.lock().unwrap().clone();
+                let sent = sender.send(msg).is_ok();
+                // wake up a consumer waiting on the normal lane. if the lane is full it isn't waiting
+                let _ = self.msg_chan.0.lock().unwrap().try_send(None);
+                sent
+            } else {
+                let sender = self.msg_chan.0.lock().unwrap().clone();
+                sender.send(Some(msg)).is_ok()
+            };
+            if !sent {
+                println!("Message channel closed, dropping connection: {}", peer_addr);
+                break;
+            }
+        }
 
-            // send the message to the channel
-            let sender = self.msg_chan.0.lock().unwrap().clone();
-            sender.send(msg).unwrap(); // FIXME: handle error
+        peer.read().unwrap().set_state(PeerState::Closed);
+        // the address may be taken by another connection to the same peer by now
+        {
+            let mut peers = self.peers.write().unwrap();
+            if !peers.get(&peer_addr).is_some_and(|p| Arc::ptr_eq(p, &peer)) {
+                return;
+            }
+            peers.remove(&peer_addr);
+        }
+        if let Some(cb) = &*self.on_peer_disconnect.lock().unwrap() {
+            cb(peer_addr);
         }
     }
 }
 
 
+#[cfg(test)]
+impl TcpTransport {
+    /// leave both lanes without a sender, so that `consume` fails with `Disconnected` until `reattach`
+    pub(crate) fn disconnect_lanes(&self) {
+        *self.priority_chan.0.lock().unwrap() = sync_channel(0).0;
+        *self.msg_chan.0.lock().unwrap() = sync_channel(0).0;
+    }
+}
+
 impl Transport for TcpTransport {
     type Peer = TcpPeer;
     
     fn addr(self: Arc<Self>) -> String {
-        self.opts.listen_addr.clone()
+        // the bound address carries the actual port when listening on port 0
+        match self.listener.local_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => self.opts.listen_addr.clone(),
+        }
+    }
+
+    fn local_addr(self: Arc<Self>) -> Result<SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    fn max_payload_size(self: Arc<Self>) -> usize {
+        self.opts.decoder.max_payload_size()
     }
 
     fn listen_and_accept(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
@@ -194,7 +707,67 @@ impl Transport for TcpTransport {
     }
 
     fn consume(self: Arc<Self>) -> Result<Message, RecvTimeoutError> {
-        self.msg_chan.1.lock().unwrap().recv_timeout(Duration::from_secs(1))
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let priority = self.priority_chan.1.lock().unwrap();
+        let normal = self.msg_chan.1.lock().unwrap();
+        loop {
+            if let Ok(msg) = priority.try_recv() {
+                return Ok(msg);
+            }
+            match normal.recv_timeout(deadline.saturating_duration_since(Instant::now()))? {
+                Some(msg) => return Ok(msg),
+                // a priority message came in while waiting
+                None => continue,
+            }
+        }
+    }
+
+    fn consume_blocking(self: Arc<Self>) -> Result<Option<Message>, RecvError> {
+        let priority = self.priority_chan.1.lock().unwrap();
+        let normal = self.msg_chan.1.lock().unwrap();
+        loop {
+            if let Ok(msg) = priority.try_recv() {
+                return Ok(Some(msg));
+            }
+            if self.woken.swap(false, Ordering::SeqCst) {
+                return Ok(None);
+            }
+            match normal.recv()? {
+                Some(msg) => return Ok(Some(msg)),
+                // a priority message came in or `wake` was called while waiting
+                None => continue,
+            }
+        }
+    }
+
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        // if the lane is full, the consumer isn't waiting
+        let _ = self.msg_chan.0.lock().unwrap().try_send(None);
+    }
+
+    /// the messages still in the lanes are moved to the new ones. a connection still sending to an old lane is
+    /// dropped, as on a closed channel
+    fn reattach(self: Arc<Self>) {
+        reattach_lane(&self.priority_chan, self.opts.msg_chan_capacity);
+        reattach_lane(&self.msg_chan, self.opts.msg_chan_capacity);
+    }
+
+    fn drain(self: Arc<Self>) -> Vec<Message> {
+        let priority = self.priority_chan.1.lock().unwrap();
+        let normal = self.msg_chan.1.lock().unwrap();
+        let mut msgs: Vec<Message> = std::iter::from_fn(|| priority.try_recv().ok()).collect();
+        msgs.extend(std::iter::from_fn(|| normal.try_recv().ok()).flatten());
+        msgs
+    }
+
+    fn consume_batch(self: Arc<Self>, max: usize) -> Vec<Message> {
+        let priority = self.priority_chan.1.lock().unwrap();
+        let normal = self.msg_chan.1.lock().unwrap();
+        let mut msgs: Vec<Message> = std::iter::from_fn(|| priority.try_recv().ok()).take(max).collect();
+        // the wake ups are skipped, `woken` is still set for `consume_blocking`
+        msgs.extend(std::iter::from_fn(|| normal.try_recv().ok()).flatten().take(max - msgs.len()));
+        msgs
     }
 
     fn close(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>> {
@@ -202,11 +775,42 @@ impl Transport for TcpTransport {
         Ok(())
     }
 
+    fn close_peer(self: Arc<Self>, addr: SocketAddr) -> Result<(), io::Error> {
+        // removed first, so that the read loop ending on the closed connection doesn't fire the callback again
+        let peer = match self.peers.write().unwrap().remove(&addr) {
+            Some(peer) => peer,
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is not connected", addr))),
+        };
+        let res = peer.read().unwrap().close();
+        if let Some(cb) = &*self.on_peer_disconnect.lock().unwrap() {
+            cb(addr);
+        }
+
+        res
+    }
+
+    /// fail if all the pool workers are busy, since nothing would read the replies of the peer
     fn dial(self: &Arc<Self>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let slot = match self.take_worker() {
+            Some(slot) => slot,
+            None => return Err(Box::new(io::Error::other(format!("cannot dial {}, all {} workers are busy", addr, self.pool.size())))),
+        };
         // dial to a remote address
-        match TcpStream::connect(addr) {
+        match TcpStream::connect_timeout(&addr, self.opts.connect_timeout) {
             Ok(conn) => {
-                self.handle_conn(conn, true);
+                // the handshake is done on the calling thread so that the peer is ready once dial returns.
+                // the connection is then served by the pool
+                let (peer_addr, peer, reader) = match self.handle_conn(conn, true) {
+                    Some(ready) => ready,
+                    // the new connection is dropped when the peer was already connected, which is not a failure
+                    None if self.peers.read().unwrap().contains_key(&addr) => return Ok(()),
+                    None => return Err(Box::new(io::Error::other(format!("handshake with {} failed", addr)))),
+                };
+                let self_clone = self.clone();
+                self.pool.execute(move || {
+                    let _slot = slot;
+                    self_clone.read_loop(reader, peer_addr, peer);
+                });
                 Ok(())
             },
             Err(e) => {
@@ -215,69 +819,880 @@ impl Transport for TcpTransport {
         }
     }
 
-    fn try_dial(self: &Arc<Self>, addr: SocketAddr, max_attemps: u8) -> Result<(), Box<dyn std::error::Error>> {
-        // dial to a remote address with exponential backoff
-        let mut backoff = Duration::from_secs(1);
-        let mut attempts = 0;
-        loop {
-            match self.dial(addr) {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    if attempts >= max_attemps {
-                        // stop trying
-                        panic!("Error connecting to {}: {}", addr, e);
-                        return Err(e)
-                    } else {
-                        // exponential backoff
-                        println!("Error connecting to {}. Retrying in {} seconds", addr, backoff.as_secs());
-                        attempts += 1;
-                        thread::sleep(backoff);
-                        backoff *= 2;
-                    }
-                }
-            }
-        }
+    /// the peers that are not `Ready` are skipped
+    fn broadcast(self: Arc<Self>, buf: &[u8]) -> Vec<(SocketAddr, Result<(), io::Error>)> {
+        // release the peers list before sending so that a slow peer doesn't hold up connects and disconnects
+        let peers: Vec<(SocketAddr, Arc<RwLock<TcpPeer>>)> = self.peers.read().unwrap()
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.clone()))
+            .collect();
+        peers.into_iter()
+            .filter(|(_, peer)| peer.read().unwrap().state() == PeerState::Ready)
+            .map(|(addr, peer)| (addr, peer.write().unwrap().send(buf)))
+            .collect()
+    }
+
+    fn peer_count(self: Arc<Self>) -> usize {
+        self.peers.read().unwrap().len()
     }
 
-    fn register_on_peer(self: Arc<Self>, callback: Box<dyn Fn(Arc<RwLock<TcpPeer>>) -> bool + Sync + Send + 'static>) {
+    fn peer_addrs(self: Arc<Self>) -> Vec<SocketAddr> {
+        self.peers.read().unwrap().keys().copied().collect()
+    }
+
+    fn send_to(self: &Arc<Self>, addr: SocketAddr, buf: &[u8]) -> Result<(), io::Error> {
+        let peer = match self.peers.read().unwrap().get(&addr) {
+            Some(peer) => peer.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotConnected, format!("peer {} is not connected", addr))),
+        };
+        let mut peer = peer.write().unwrap();
+        peer.send(buf)
+    }
+
+    fn register_on_peer(self: Arc<Self>, callback: OnPeerFn<TcpPeer>) {
         let mut cb = self.on_peer.lock().unwrap();
         *cb = Some(callback);
     }
+
+    fn pause_accept(self: Arc<Self>) {
+        self.accept_paused.store(true, Ordering::SeqCst);
+    }
+
+    fn resume_accept(self: Arc<Self>) {
+        self.accept_paused.store(false, Ordering::SeqCst);
+    }
+
+    fn is_listening(self: Arc<Self>) -> bool {
+        self.accepting.load(Ordering::SeqCst) && !self.accept_paused.load(Ordering::SeqCst)
+    }
+
+    fn max_dial_backoff(self: Arc<Self>) -> Duration {
+        self.opts.max_dial_backoff
+    }
+
+    fn weight(self: Arc<Self>) -> u32 {
+        self.opts.weight.unwrap_or(1)
+    }
+
+    fn register_on_peer_disconnect(self: Arc<Self>, callback: OnPeerDisconnectFn) {
+        let mut cb = self.on_peer_disconnect.lock().unwrap();
+        *cb = Some(callback);
+    }
 }
 
 // section: tests
 
 #[cfg(test)]
 mod tests {
-    use crate::transport::encoding::DefaultDecoder;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::ThreadId;
+
+    use crate::transport::encoding::{ChecksumDecoder, ChecksumEncoder, DefaultDecoder, LengthPrefixedDecoder, LengthPrefixedEncoder, TypedFrameDecoder, TypedFrameEncoder};
+    use crate::transport::tls::test_certs::TestCa;
 
     use super::*;
 
+    fn wait_until(cond: impl Fn() -> bool) -> bool {
+        for _ in 0..50 {
+            if cond() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        cond()
+    }
+
     #[test]
     fn test_new_tcp_transport() {
-        let addr = String::from("localhost:3000");
+        let addr = String::from("127.0.0.1:0");
         let opts = TcpTransportOpts {
             listen_addr: addr.clone(),
             shakehands: Option::None,
-            decoder: Box::new(DefaultDecoder {}),
+            decoder: Box::new(DefaultDecoder::new()),
+            encoder: Arc::new(DefaultEncoder {}),
+            max_workers: DEFAULT_MAX_WORKERS,
+            msg_chan_capacity: DEFAULT_MSG_CHAN_CAPACITY,
+            tls: None,
+            secret: None,
+            tags: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            advertise_addr: false,
+            max_msg_rate: None,
+            rate_limit_drop_after: None,
+            max_send_rate: None,
+            weight: None,
+            codecs: None,
+            max_dial_backoff: DEFAULT_MAX_DIAL_BACKOFF,
         };
-        let transport = TcpTransport::new(opts);
+        let transport = TcpTransport::new(opts).unwrap();
         assert_eq!(transport.opts.listen_addr, addr);
     }
 
+    #[test]
+    fn test_local_addr_reports_bound_port() {
+        let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        let addr = transport.clone().local_addr().unwrap();
+
+        assert_ne!(addr.port(), 0);
+        assert_eq!(transport.clone().addr(), addr.to_string());
+        // the reported port accepts connections
+        transport.clone().listen_and_accept().unwrap();
+        assert!(TcpStream::connect(addr).is_ok());
+    }
+
     #[test]
     fn test_listen_and_accept() {
-        let addr = String::from("localhost:3000");
+        let addr = String::from("127.0.0.1:0");
         let opts = TcpTransportOpts {
             listen_addr: addr.clone(),
             shakehands: Option::None,
-            decoder: Box::new(DefaultDecoder {}),
+            decoder: Box::new(DefaultDecoder::new()),
+            encoder: Arc::new(DefaultEncoder {}),
+            max_workers: DEFAULT_MAX_WORKERS,
+            msg_chan_capacity: DEFAULT_MSG_CHAN_CAPACITY,
+            tls: None,
+            secret: None,
+            tags: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            advertise_addr: false,
+            max_msg_rate: None,
+            rate_limit_drop_after: None,
+            max_send_rate: None,
+            weight: None,
+            codecs: None,
+            max_dial_backoff: DEFAULT_MAX_DIAL_BACKOFF,
         };
 
-        let transport = TcpTransport::new(opts);
+        let transport = TcpTransport::new(opts).unwrap();
         // test if the listen_and_accept function is working
-        assert_eq!(transport.listen_and_accept().is_ok(), true);
+        assert!(transport.listen_and_accept().is_ok());
+    }
+
+    #[test]
+    fn test_connections_served_by_bounded_pool() {
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+        opts.max_workers = 2;
+        let transport = TcpTransport::new(opts).unwrap();
+        let local_addr = transport.listener.local_addr().unwrap();
+
+        // record which thread served each connection
+        let served: Arc<Mutex<Vec<ThreadId>>> = Arc::new(Mutex::new(Vec::new()));
+        let served_clone = served.clone();
+        transport.clone().register_on_peer(Box::new(move |_| {
+            served_clone.lock().unwrap().push(thread::current().id());
+            true
+        }));
+        transport.clone().listen_and_accept().unwrap();
+
+        // open more connections than workers, one after the other. each one is closed right away so that the worker
+        // is freed before the next one comes in
+        let conns = 6;
+        for i in 0..conns {
+            let conn = TcpStream::connect(local_addr).unwrap();
+            conn.shutdown(Shutdown::Both).unwrap();
+
+            let mut attempts = 0;
+            while (served.lock().unwrap().len() <= i || transport.busy_workers.load(Ordering::SeqCst) > 0) && attempts < 50 {
+                thread::sleep(Duration::from_millis(100));
+                attempts += 1;
+            }
+        }
+
+        let served = served.lock().unwrap();
+        assert_eq!(served.len(), conns);
+        let threads: HashSet<&ThreadId> = served.iter().collect();
+        assert!(threads.len() <= 2);
+    }
+
+    #[test]
+    fn test_connections_over_capacity_refused() {
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+        opts.max_workers = 1;
+        let transport = TcpTransport::new(opts).unwrap();
+        let local_addr = transport.listener.local_addr().unwrap();
+        let served = Arc::new(AtomicUsize::new(0));
+        let served_clone = served.clone();
+        transport.clone().register_on_peer(Box::new(move |_| {
+            served_clone.fetch_add(1, Ordering::SeqCst);
+            true
+        }));
+        transport.clone().listen_and_accept().unwrap();
+        let wait_served = |n: usize| {
+            let mut attempts = 0;
+            while served.load(Ordering::SeqCst) < n && attempts < 50 {
+                thread::sleep(Duration::from_millis(100));
+                attempts += 1;
+            }
+            served.load(Ordering::SeqCst) == n
+        };
+
+        // the only worker is taken by a connection that stays open
+        let first = TcpStream::connect(local_addr).unwrap();
+        assert!(wait_served(1));
+
+        // the next connection is closed rather than left waiting for the worker
+        let mut second = TcpStream::connect(local_addr).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(second.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+
+        // dialing out would leave nothing to read the replies of the peer
+        let remote = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        remote.clone().listen_and_accept().unwrap();
+        assert!(transport.dial(remote.listener.local_addr().unwrap()).is_err());
+        assert_eq!(transport.clone().peer_count(), 1);
+
+        // the worker is free again once the first connection is closed
+        first.shutdown(Shutdown::Both).unwrap();
+        let mut attempts = 0;
+        while transport.busy_workers.load(Ordering::SeqCst) > 0 && attempts < 50 {
+            thread::sleep(Duration::from_millis(100));
+            attempts += 1;
+        }
+        let _third = TcpStream::connect(local_addr).unwrap();
+        assert!(wait_served(2));
+    }
+
+    #[test]
+    fn test_tls_rejects_untrusted_peer() {
+        let make_transport = |tls: TlsConfig| {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+            opts.tls = Some(tls);
+            TcpTransport::new(opts).unwrap()
+        };
+        // the two nodes are signed by different CAs
+        let server = make_transport(TestCa::generate().node_config());
+        let client = make_transport(TestCa::generate().node_config());
+        server.clone().listen_and_accept().unwrap();
+
+        assert!(client.dial(server.listener.local_addr().unwrap()).is_err());
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(client.peers.read().unwrap().is_empty());
+        assert!(server.peers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_to_single_peer() {
+        let make_transport = || {
+            let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let sender = make_transport();
+        let target = make_transport();
+        let bystander = make_transport();
+        let target_addr = target.listener.local_addr().unwrap();
+        sender.dial(target_addr).unwrap();
+        sender.dial(bystander.listener.local_addr().unwrap()).unwrap();
+
+        sender.send_to(target_addr, b"only for you").unwrap();
+
+        assert_eq!(target.clone().consume().unwrap().payload, b"only for you".to_vec());
+        assert!(bystander.clone().consume().is_err());
+    }
+
+    #[test]
+    fn test_peer_sent_to_once_ready() {
+        let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut other_end, _) = listener.accept().unwrap();
+        other_end.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let addr = conn.peer_addr().unwrap();
+
+        // a peer still going through the handshakes
+        let peer = Arc::new(RwLock::new(TcpPeer::new(conn, true)));
+        transport.peers.write().unwrap().insert(addr, peer.clone());
+        assert_eq!(peer.read().unwrap().state(), PeerState::Connecting);
+        assert_eq!(transport.send_to(addr, b"too early").unwrap_err().kind(), io::ErrorKind::NotConnected);
+        assert!(transport.clone().broadcast(b"too early").is_empty());
+        let mut buf = [0; 64];
+        assert!(other_end.read(&mut buf).is_err());
+
+        peer.read().unwrap().set_state(PeerState::Ready);
+        transport.send_to(addr, b"ready").unwrap();
+        let results = transport.clone().broadcast(b"ready");
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_ok());
+        let mut received = [0; 10];
+        other_end.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"readyready");
+
+        peer.read().unwrap().close().unwrap();
+        assert_eq!(peer.read().unwrap().state(), PeerState::Closed);
+        assert_eq!(transport.send_to(addr, b"too late").unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_send_to_unknown_peer() {
+        let make_transport = || {
+            let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let sender = make_transport();
+        let target = make_transport();
+        let target_addr = target.listener.local_addr().unwrap();
+
+        // listening but never dialed, so not a peer
+        let err = sender.send_to(target_addr, b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(target.clone().consume().is_err());
+
+        sender.dial(target_addr).unwrap();
+        sender.send_to(target_addr, b"hello").unwrap();
+        assert_eq!(target.clone().consume().unwrap().payload, b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_length_prefixed_frames_over_tcp() {
+        let make_transport = || {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()));
+            opts.encoder = Arc::new(LengthPrefixedEncoder {});
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let sender = make_transport();
+        let receiver = make_transport();
+        let receiver_addr = receiver.listener.local_addr().unwrap();
+        sender.dial(receiver_addr).unwrap();
+
+        // messages sent back to back are received one by one, however they are split on the wire
+        let large = vec![7; 10_000];
+        sender.send_to(receiver_addr, b"small").unwrap();
+        sender.send_to(receiver_addr, &large).unwrap();
+
+        assert_eq!(receiver.clone().consume().unwrap().payload, b"small".to_vec());
+        assert_eq!(receiver.clone().consume().unwrap().payload, large);
+    }
+
+    #[test]
+    fn test_corrupted_frame_dropped() {
+        let receiver = TcpTransport::new(TcpTransportOpts::new(
+            String::from("127.0.0.1:0"),
+            Box::new(ChecksumDecoder::new(Box::new(LengthPrefixedDecoder::new()))),
+        )).unwrap();
+        receiver.clone().listen_and_accept().unwrap();
+        let encoder = ChecksumEncoder::new(Arc::new(LengthPrefixedEncoder {}));
+        let mut corrupted = Vec::new();
+        encoder.encode(b"mangled", &mut corrupted).unwrap();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        let mut intact = Vec::new();
+        encoder.encode(b"intact", &mut intact).unwrap();
+
+        let mut conn = TcpStream::connect(receiver.listener.local_addr().unwrap()).unwrap();
+        conn.write_all(&corrupted).unwrap();
+        conn.write_all(&intact).unwrap();
+
+        // the corrupted frame is skipped and the connection is kept
+        assert_eq!(receiver.clone().consume().unwrap().payload, b"intact".to_vec());
+    }
+
+    /// a decoder counting the frames it has decoded
+    struct CountingDecoder {
+        inner: LengthPrefixedDecoder,
+        decoded: Arc<AtomicUsize>,
+    }
+
+    impl Decoder for CountingDecoder {
+        fn decode(&self, r: &mut dyn Read, msg: &mut Message) -> Result<(), io::Error> {
+            self.inner.decode(r, msg)?;
+            self.decoded.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_full_channel_blocks_reader() {
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let decoder = CountingDecoder { inner: LengthPrefixedDecoder::new(), decoded: decoded.clone() };
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(decoder));
+        opts.msg_chan_capacity = 1;
+        let transport = TcpTransport::new(opts).unwrap();
+        let local_addr = transport.listener.local_addr().unwrap();
+        transport.clone().listen_and_accept().unwrap();
+
+        let mut conn = TcpStream::connect(local_addr).unwrap();
+        let mut frames = Vec::new();
+        for payload in [b"one", b"two", b"six"] {
+            LengthPrefixedEncoder {}.encode(payload, &mut frames).unwrap();
+        }
+        conn.write_all(&frames).unwrap();
+
+        // the first message fills the channel and the reader blocks sending the second one,
+        // so the third is left unread on the socket
+        assert!(wait_until(|| decoded.load(Ordering::SeqCst) == 2));
+        assert_eq!(decoded.load(Ordering::SeqCst), 2);
+
+        // consuming makes room for the reader to carry on
+        assert_eq!(transport.clone().consume().unwrap().payload, b"one".to_vec());
+        assert_eq!(transport.clone().consume().unwrap().payload, b"two".to_vec());
+        assert_eq!(transport.clone().consume().unwrap().payload, b"six".to_vec());
+        assert_eq!(decoded.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_priority_message_consumed_first() {
+        let mut decoder = TypedFrameDecoder::new();
+        decoder.priority_types = vec![0];
+        let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(decoder))).unwrap();
+        let local_addr = transport.listener.local_addr().unwrap();
+        transport.clone().listen_and_accept().unwrap();
+
+        let mut conn = TcpStream::connect(local_addr).unwrap();
+        let data = [&[1][..], &vec![7; 1024 * 1024]].concat();
+        let control = vec![0, 42];
+        let mut frames = Vec::new();
+        TypedFrameEncoder {}.encode(&data, &mut frames).unwrap();
+        TypedFrameEncoder {}.encode(&control, &mut frames).unwrap();
+        conn.write_all(&frames).unwrap();
+
+        // both messages are waiting by now, the control one behind the data one
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(transport.clone().consume().unwrap().payload, control);
+        assert_eq!(transport.clone().consume().unwrap().payload, data);
+    }
+
+    #[test]
+    fn test_rate_limit_drops_flooding_peer() {
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()));
+        opts.max_msg_rate = Some(10);
+        opts.rate_limit_drop_after = Some(Duration::from_millis(300));
+        let transport = TcpTransport::new(opts).unwrap();
+        let local_addr = transport.listener.local_addr().unwrap();
+        transport.clone().listen_and_accept().unwrap();
+
+        let mut conn = TcpStream::connect(local_addr).unwrap();
+        let mut frames = Vec::new();
+        for _ in 0..100 {
+            LengthPrefixedEncoder {}.encode(b"flood", &mut frames).unwrap();
+        }
+        conn.write_all(&frames).unwrap();
+
+        // the burst goes through at once, the rest only at the rate
+        thread::sleep(Duration::from_millis(150));
+        let burst = transport.clone().drain().len();
+        assert!((10..=12).contains(&burst), "{} messages", burst);
+        assert_eq!(transport.clone().peer_count(), 1);
+
+        // the peer keeps going over the rate, so it is dropped rather than read to the end
+        let start = Instant::now();
+        while transport.clone().peer_count() > 0 && start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(transport.clone().peer_count(), 0);
+        assert!(burst + transport.clone().drain().len() < 20);
+    }
+
+    #[test]
+    fn test_send_rate_paces_large_buffer() {
+        let receiver = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()))).unwrap();
+        let local_addr = receiver.listener.local_addr().unwrap();
+        receiver.clone().listen_and_accept().unwrap();
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()));
+        opts.encoder = Arc::new(LengthPrefixedEncoder {});
+        opts.max_send_rate = Some(100 * 1024);
+        let sender = TcpTransport::new(opts).unwrap();
+        sender.dial(local_addr).unwrap();
+
+        let buf = vec![7; 50 * 1024];
+        let start = Instant::now();
+        sender.send_to(local_addr, &buf).unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "sent in {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "sent in {:?}", elapsed);
+        assert_eq!(receiver.clone().consume().unwrap().payload, buf);
+    }
+
+    #[test]
+    fn test_bind_address_in_use() {
+        let first = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        let taken = first.listener.local_addr().unwrap().to_string();
+
+        let res = TcpTransport::new(TcpTransportOpts::new(taken, Box::new(DefaultDecoder::new())));
+        assert_eq!(res.err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+    }
+
+    #[test]
+    fn test_dial_host() {
+        let listener = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        let port = listener.listener.local_addr().unwrap().port();
+        listener.clone().listen_and_accept().unwrap();
+        let dialer = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+
+        // localhost may resolve to ::1 first, which nothing listens on. the next address is tried then
+        dialer.dial_host(&format!("localhost:{}", port)).unwrap();
+        assert_eq!(dialer.peers.read().unwrap().len(), 1);
+
+        assert!(dialer.dial_host("missing_port").is_err());
+    }
+
+    #[test]
+    fn test_dial_timeout() {
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+        opts.connect_timeout = Duration::from_millis(300);
+        let dialer = TcpTransport::new(opts).unwrap();
+
+        // reserved for documentation, nothing answers there. the network may also refuse it right away
+        let start = Instant::now();
+        assert!(dialer.dial(SocketAddr::from(([192, 0, 2, 1], 9))).is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(dialer.peers.read().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_peer_count() {
+        let make_transport = || {
+            let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let dialer = make_transport();
+        let remotes = [make_transport(), make_transport()];
+        assert_eq!(dialer.clone().peer_count(), 0);
+
+        let mut remote_addrs: Vec<SocketAddr> = remotes.iter().map(|r| r.listener.local_addr().unwrap()).collect();
+        for addr in remote_addrs.iter() {
+            dialer.dial(*addr).unwrap();
+        }
+
+        assert_eq!(dialer.clone().peer_count(), 2);
+        let mut addrs = dialer.clone().peer_addrs();
+        addrs.sort();
+        remote_addrs.sort();
+        assert_eq!(addrs, remote_addrs);
+    }
+
+    #[test]
+    fn test_close_peer() {
+        let make_transport = || {
+            let transport = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let dialer = make_transport();
+        let disconnected = Arc::new(Mutex::new(Vec::new()));
+        let d = disconnected.clone();
+        dialer.clone().register_on_peer_disconnect(Box::new(move |addr| d.lock().unwrap().push(addr)));
+        let remotes = [make_transport(), make_transport()];
+        let remote_addrs: Vec<SocketAddr> = remotes.iter().map(|r| r.listener.local_addr().unwrap()).collect();
+        for addr in remote_addrs.iter() {
+            dialer.dial(*addr).unwrap();
+        }
+
+        dialer.clone().close_peer(remote_addrs[0]).unwrap();
+        assert_eq!(dialer.clone().peer_addrs(), vec![remote_addrs[1]]);
+        assert_eq!(*disconnected.lock().unwrap(), vec![remote_addrs[0]]);
+        let err = dialer.clone().close_peer(remote_addrs[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        // the other side sees the connection go, and the read loop ending on it doesn't report it twice
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(remotes[0].clone().peer_count(), 0);
+        assert_eq!(remotes[1].clone().peer_count(), 1);
+        assert_eq!(disconnected.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_drain() {
+        let receiver = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()))).unwrap();
+        receiver.clone().listen_and_accept().unwrap();
+        // nothing received yet, and no waiting for it
+        assert!(receiver.clone().drain().is_empty());
+
+        let mut conn = TcpStream::connect(receiver.listener.local_addr().unwrap()).unwrap();
+        for payload in [&b"one"[..], b"two", b"three"] {
+            LengthPrefixedEncoder {}.encode(payload, &mut conn).unwrap();
+        }
+        let mut drained = Vec::new();
+        let mut attempts = 0;
+        while drained.len() < 3 && attempts < 50 {
+            thread::sleep(Duration::from_millis(100));
+            drained.extend(receiver.clone().drain().into_iter().map(|msg| msg.payload));
+            attempts += 1;
+        }
+        assert_eq!(drained, vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()]);
+    }
+
+    #[test]
+    fn test_consume_batch() {
+        let receiver = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        assert!(receiver.clone().consume_batch(64).is_empty());
+
+        let from = SocketAddr::from(([127, 0, 0, 1], 10087));
+        let sender = receiver.msg_chan.0.lock().unwrap().clone();
+        for i in 0..100u8 {
+            sender.send(Some(Message { from, payload: vec![i] })).unwrap();
+        }
+        // a wake up among them is skipped
+        receiver.clone().wake();
+        receiver.priority_chan.0.lock().unwrap().send(Message { from, payload: vec![255] }).unwrap();
+
+        // the priority message first, then the others in order, up to `max` at once
+        let batch: Vec<u8> = receiver.clone().consume_batch(64).into_iter().map(|msg| msg.payload[0]).collect();
+        assert_eq!(batch, [vec![255], (0..63).collect()].concat());
+        let batch: Vec<u8> = receiver.clone().consume_batch(64).into_iter().map(|msg| msg.payload[0]).collect();
+        assert_eq!(batch, (63..100).collect::<Vec<u8>>());
+        assert!(receiver.clone().consume_batch(64).is_empty());
+        // the wake up is still noticed
+        assert!(receiver.clone().consume_blocking().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wait_until_listening() {
+        let listener = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        assert!(!listener.clone().wait_until_listening(Duration::from_millis(50)));
+
+        listener.clone().listen_and_accept().unwrap();
+        assert!(listener.clone().wait_until_listening(Duration::from_secs(1)));
+        let dialer = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        dialer.dial(listener.listener.local_addr().unwrap()).unwrap();
+        dialer.send_to(listener.listener.local_addr().unwrap(), b"ready").unwrap();
+        assert_eq!(listener.clone().consume().unwrap().payload, b"ready".to_vec());
+    }
+
+    #[test]
+    fn test_pause_accept() {
+        let listener = TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap();
+        assert!(!listener.clone().is_listening());
+        listener.clone().listen_and_accept().unwrap();
+        assert!(listener.clone().wait_until_listening(Duration::from_secs(1)));
+        let listen_addr = listener.listener.local_addr().unwrap();
+        let dialers: Vec<Arc<TcpTransport>> = (0..2)
+            .map(|_| TcpTransport::new(TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()))).unwrap())
+            .collect();
+
+        // the connection is made by the os, but the listener doesn't take the peer in
+        listener.clone().pause_accept();
+        assert!(!listener.clone().is_listening());
+        dialers[0].dial(listen_addr).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(listener.clone().peer_count(), 0);
+
+        // the held connection is taken in, and new ones are accepted again
+        listener.clone().resume_accept();
+        assert!(listener.clone().is_listening());
+        dialers[1].dial(listen_addr).unwrap();
+        let mut attempts = 0;
+        while listener.clone().peer_count() < 2 && attempts < 50 {
+            thread::sleep(Duration::from_millis(100));
+            attempts += 1;
+        }
+        assert_eq!(listener.clone().peer_count(), 2);
+    }
+
+    fn make_transport_with_secret(secret: &[u8]) -> Arc<TcpTransport> {
+        let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+        opts.secret = Some(secret.to_vec());
+        let transport = TcpTransport::new(opts).unwrap();
+        transport.clone().listen_and_accept().unwrap();
+        transport
+    }
+
+    #[test]
+    fn test_shared_secret_handshake() {
+        let a = make_transport_with_secret(b"cluster secret");
+        let b = make_transport_with_secret(b"cluster secret");
+        let b_addr = b.listener.local_addr().unwrap();
+        a.dial(b_addr).unwrap();
+
+        // the accepting side registers the peer once the answer is checked
+        for _ in 0..50 {
+            if b.clone().peer_count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(b.clone().peer_count(), 1);
+        a.send_to(b_addr, b"hello").unwrap();
+        assert_eq!(b.clone().consume().unwrap().payload, b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_wrong_secret_rejected() {
+        let a = make_transport_with_secret(b"wrong secret");
+        let b = make_transport_with_secret(b"cluster secret");
+        // the connection is closed before the accepting side answers, so the dial fails
+        assert!(a.dial(b.listener.local_addr().unwrap()).is_err());
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(b.clone().peer_count(), 0);
+        assert_eq!(a.clone().peer_count(), 0);
+    }
+
+    #[test]
+    fn test_wrong_secret_on_accepting_side() {
+        let a = make_transport_with_secret(b"cluster secret");
+        let b = make_transport_with_secret(b"wrong secret");
+        // the dialer checks the answer of the accepting side as well
+        assert!(a.dial(b.listener.local_addr().unwrap()).is_err());
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(a.clone().peer_count(), 0);
+        assert_eq!(b.clone().peer_count(), 0);
+    }
+
+    #[test]
+    fn test_tags_exchanged() {
+        let make_transport = |tags: &[&str]| {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+            opts.tags = Some(tags.iter().map(|tag| tag.to_string()).collect());
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let a = make_transport(&["edge"]);
+        let b = make_transport(&["cold", "eu"]);
+        let b_addr = b.listener.local_addr().unwrap();
+        a.dial(b_addr).unwrap();
+
+        for _ in 0..50 {
+            if b.clone().peer_count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        // each side knows the tags of the other
+        assert_eq!(a.peers.read().unwrap()[&b_addr].read().unwrap().tags(), vec!["cold", "eu"]);
+        let a_addr = b.clone().peer_addrs()[0];
+        assert_eq!(b.peers.read().unwrap()[&a_addr].read().unwrap().tags(), vec!["edge"]);
+        // the connection is usable after the exchange
+        a.send_to(b_addr, b"hello").unwrap();
+        assert_eq!(b.clone().consume().unwrap().payload, b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_weight_exchanged() {
+        let make_transport = |weight: u32| {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+            opts.weight = Some(weight);
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let a = make_transport(3);
+        let b = make_transport(1);
+        let b_addr = b.listener.local_addr().unwrap();
+        a.dial(b_addr).unwrap();
+
+        for _ in 0..50 {
+            if b.clone().peer_count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(a.clone().weight(), 3);
+        assert_eq!(a.peers.read().unwrap()[&b_addr].read().unwrap().weight(), 1);
+        let a_addr = b.clone().peer_addrs()[0];
+        assert_eq!(b.peers.read().unwrap()[&a_addr].read().unwrap().weight(), 3);
+    }
+
+    #[test]
+    fn test_codecs_negotiated() {
+        let make_transport = |codecs: &[Codec]| {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(TypedFrameDecoder::new()));
+            opts.encoder = Arc::new(TypedFrameEncoder {});
+            opts.codecs = Some(codecs.to_vec());
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let compressing = make_transport(&[Codec::Deflate, Codec::Identity]);
+        let plain = make_transport(&[Codec::Identity]);
+        let other_compressing = make_transport(&[Codec::Deflate, Codec::Identity]);
+        let plain_addr = plain.listener.local_addr().unwrap();
+        let other_addr = other_compressing.listener.local_addr().unwrap();
+        compressing.dial(plain_addr).unwrap();
+        compressing.dial(other_addr).unwrap();
+        for _ in 0..50 {
+            if plain.clone().peer_count() == 1 && other_compressing.clone().peer_count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(compressing.peers.read().unwrap()[&plain_addr].read().unwrap().codecs, Some(vec![Codec::Identity]));
+        assert_eq!(compressing.peers.read().unwrap()[&other_addr].read().unwrap().codecs, Some(vec![Codec::Deflate, Codec::Identity]));
+
+        // a store message, compressible, goes both ways with the plaintext only peer
+        let store = [vec![0], vec![b'a'; 64 * 1024]].concat();
+        compressing.send_to(plain_addr, &store).unwrap();
+        assert_eq!(plain.clone().consume().unwrap().payload, store);
+        let compressing_addr = plain.clone().peer_addrs()[0];
+        plain.send_to(compressing_addr, &store).unwrap();
+        assert_eq!(compressing.clone().consume().unwrap().payload, store);
+
+        // and is compressed for the peer supporting it
+        compressing.send_to(other_addr, &store).unwrap();
+        assert_eq!(other_compressing.clone().consume().unwrap().payload, store);
+    }
+
+    #[test]
+    fn test_dial_back_keeps_single_peer() {
+        let make_transport = || {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(DefaultDecoder::new()));
+            opts.advertise_addr = true;
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let a = make_transport();
+        let b = make_transport();
+        let a_addr = a.listener.local_addr().unwrap();
+        let b_addr = b.listener.local_addr().unwrap();
+        a.dial(b_addr).unwrap();
+        for _ in 0..50 {
+            if b.clone().peer_count() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        // b knows a by its listen address rather than the ephemeral port it dialed from
+        assert_eq!(b.clone().peer_addrs(), vec![a_addr]);
+
+        // dialing back doesn't add a second entry on either side
+        b.dial(a_addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(a.clone().peer_addrs(), vec![b_addr]);
+        assert_eq!(b.clone().peer_addrs(), vec![a_addr]);
+
+        // the first connection is still the one used
+        b.send_to(a_addr, b"hello").unwrap();
+        let msg = a.clone().consume().unwrap();
+        assert_eq!(msg.from, b_addr);
+        assert_eq!(msg.payload, b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let make_transport = || {
+            let mut opts = TcpTransportOpts::new(String::from("127.0.0.1:0"), Box::new(LengthPrefixedDecoder::new()));
+            opts.encoder = Arc::new(LengthPrefixedEncoder {});
+            let transport = TcpTransport::new(opts).unwrap();
+            transport.clone().listen_and_accept().unwrap();
+            transport
+        };
+        let sender = make_transport();
+        let receivers = [make_transport(), make_transport()];
+        let mut receiver_addrs: Vec<SocketAddr> = receivers.iter().map(|r| r.listener.local_addr().unwrap()).collect();
+        for addr in receiver_addrs.iter() {
+            sender.dial(*addr).unwrap();
+        }
+
+        let results = sender.clone().broadcast(b"to everyone");
+
+        let mut addrs: Vec<SocketAddr> = results.iter().map(|(addr, _)| *addr).collect();
+        addrs.sort();
+        receiver_addrs.sort();
+        assert_eq!(addrs, receiver_addrs);
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+        for receiver in receivers {
+            assert_eq!(receiver.consume().unwrap().payload, b"to everyone".to_vec());
+        }
     }
 
     // TODO: test if a peer is added to the peers list
-}
\ No newline at end of file
+}
@@ -0,0 +1,227 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+
+/// the maximum time the handshake can take before the connection is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a blocked reader holds the connection before giving the writers a turn
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// certificates used to encrypt the connections between peers
+/// both sides of a connection present their certificate and verify the other one against the CA (mutual tls),
+/// so a peer without a certificate signed by the CA cannot connect at all
+#[derive(Clone)]
+pub struct TlsConfig {
+    server: Arc<ServerConfig>,
+    client: Arc<ClientConfig>,
+    /// the name expected in the certificate of a dialed peer.
+    /// if None, the ip address of the dialed peer is expected instead
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        ca_certs: Vec<CertificateDer<'static>>,
+    ) -> Result<TlsConfig, io::Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in ca_certs {
+            roots.add(cert).map_err(invalid_data)?;
+        }
+        let roots = Arc::new(roots);
+
+        let verifier = WebPkiClientVerifier::builder(roots.clone()).build().map_err(invalid_data)?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(cert_chain.clone(), key.clone_key())
+            .map_err(invalid_data)?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(cert_chain, key)
+            .map_err(invalid_data)?;
+
+        Ok(TlsConfig {
+            server: Arc::new(server),
+            client: Arc::new(client),
+            server_name: None,
+        })
+    }
+
+    /// load the certificate chain, private key and CA certificates from PEM files
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path, ca_path: &Path) -> Result<TlsConfig, io::Error> {
+        let cert_chain = CertificateDer::pem_file_iter(cert_path)
+            .map_err(invalid_data)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid_data)?;
+        let ca_certs = CertificateDer::pem_file_iter(ca_path)
+            .map_err(invalid_data)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+
+        TlsConfig::new(cert_chain, key, ca_certs)
+    }
+
+    /// set the name expected in the certificate of a dialed peer
+    pub fn with_server_name(mut self, server_name: String) -> TlsConfig {
+        self.server_name = Some(server_name);
+        self
+    }
+
+    /// perform the server side of the handshake on an accepted connection
+    pub fn accept(&self, sock: TcpStream) -> Result<TlsStream, io::Error> {
+        let conn = ServerConnection::new(self.server.clone()).map_err(invalid_data)?;
+        TlsStream::handshake(conn.into(), sock)
+    }
+
+    /// perform the client side of the handshake on a dialed connection
+    pub fn connect(&self, sock: TcpStream, addr: SocketAddr) -> Result<TlsStream, io::Error> {
+        let server_name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone()).map_err(invalid_data)?,
+            None => ServerName::IpAddress(addr.ip().into()),
+        };
+        let conn = ClientConnection::new(self.client.clone(), server_name).map_err(invalid_data)?;
+        TlsStream::handshake(conn.into(), sock)
+    }
+}
+
+/// an encrypted stream over a tcp connection
+/// the stream can be cloned so that one thread reads while others write, like a `TcpStream`
+pub struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    fn handshake(mut conn: Connection, mut sock: TcpStream) -> Result<TlsStream, io::Error> {
+        sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        // the reader must not hold the connection forever while waiting for data,
+        // otherwise nothing can be sent in the meantime
+        sock.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+
+        Ok(TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            sock,
+        })
+    }
+
+    pub fn try_clone(&self) -> Result<TlsStream, io::Error> {
+        Ok(TlsStream {
+            conn: self.conn.clone(),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    /// tell the other side that no more data will be sent
+    pub fn close(&self) -> Result<(), io::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.send_close_notify();
+        let mut sock = &self.sock;
+        while conn.wants_write() {
+            conn.write_tls(&mut sock)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut conn = self.conn.lock().unwrap();
+            match conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // no plaintext is buffered yet, read more from the socket
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+
+            match conn.read_tls(&mut self.sock) {
+                Ok(0) => return Ok(0),
+                Ok(_) => {
+                    conn.process_new_packets().map_err(invalid_data)?;
+                    // processing may produce records to send back (e.g. alerts or key updates)
+                    while conn.wants_write() {
+                        conn.write_tls(&mut self.sock)?;
+                    }
+                }
+                // nothing arrived within the poll interval, release the lock and try again
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let n = conn.writer().write(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().flush()?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.sock)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// helpers for creating self-signed certificates in tests
+#[cfg(test)]
+pub mod test_certs {
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use super::*;
+
+    /// a self-signed CA that can issue node certificates
+    pub struct TestCa {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        pub fn generate() -> TestCa {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key).unwrap();
+
+            TestCa { cert, key }
+        }
+
+        /// issue a certificate valid for 127.0.0.1 and build a config trusting this CA
+        pub fn node_config(&self) -> TlsConfig {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![String::from("127.0.0.1")]).unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            let private_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+
+            TlsConfig::new(vec![cert.der().clone()], private_key, vec![self.cert.der().clone()]).unwrap()
+        }
+    }
+}
@@ -1,12 +1,21 @@
 use std::{
     fmt::{self, Display, Formatter}, 
-    io, net::SocketAddr, 
+    io, net::{SocketAddr, ToSocketAddrs}, 
     sync::{
-        mpsc::RecvTimeoutError, Arc, RwLock
-    }
+        mpsc::{RecvError, RecvTimeoutError}, Arc, RwLock
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use super::{handshake::ErrInvalidHandshake, message::Message};
+use super::{encoding::DEFAULT_MAX_PAYLOAD_SIZE, handshake::ErrInvalidHandshake, message::Message};
+
+/// how long `Transport::try_dial` waits after the first failed attempt. the wait doubles after each one
+const TRY_DIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// the longest `Transport::try_dial` waits between two attempts, see `Transport::max_dial_backoff`
+pub const DEFAULT_MAX_DIAL_BACKOFF: Duration = Duration::from_secs(30);
+/// how often `Transport::wait_until_listening` checks whether the transport is listening
+const LISTENING_POLL: Duration = Duration::from_millis(10);
 
 /** an error type for connection close */
 #[derive(Debug)]
@@ -26,10 +35,24 @@ pub trait PeerLike: Send + Sync {
     fn close(&self) -> Result<(), io::Error>;
     fn send(&mut self, buf: &[u8]) -> Result<(), io::Error>;
     fn is_outbound(&self) -> bool;
+    /// the tags the peer advertised when it connected, e.g. "cold-storage"
+    fn tags(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// the share of the keys the peer asked for when it connected, relative to the other nodes. see `HashRing`
+    fn weight(&self) -> u32 {
+        1
+    }
 }
 
 pub type HandShakeFn<P> = fn(peer: &Arc<RwLock<P>>) -> Result<(), ErrInvalidHandshake>;
 
+/// callback fired when a new peer is connected. see `Transport::register_on_peer`
+pub type OnPeerFn<P> = Box<dyn Fn(Arc<RwLock<P>>) -> bool + Sync + Send + 'static>;
+
+/// callback fired with the address of a peer whose connection is gone. see `Transport::register_on_peer_disconnect`
+pub type OnPeerDisconnectFn = Box<dyn Fn(SocketAddr) + Sync + Send + 'static>;
+
 /// a top level interface for the transport layer  
 /// should be implemented by all transport layer
 pub trait Transport: Send + Sync + 'static {
@@ -39,22 +62,179 @@ pub trait Transport: Send + Sync + 'static {
 
     /// return the local address of the listener
     fn addr(self: Arc<Self>) -> String;
+    /// return the address the listener is actually bound to, e.g. with the port picked by the OS when listening on port 0
+    fn local_addr(self: Arc<Self>) -> Result<SocketAddr, io::Error>;
     /// clean up
     fn close(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>>; 
+    /// close the connection to a single peer and forget it, e.g. to evict a misbehaving one. the other peers are
+    /// kept. the disconnect callback is fired as for a connection that is gone, see `register_on_peer_disconnect`.  
+    /// fail with `io::ErrorKind::NotConnected` if no peer is known by that address
+    fn close_peer(self: Arc<Self>, addr: SocketAddr) -> Result<(), io::Error>;
     /// to receive a message from the transport layer
     fn consume(self: Arc<Self>) -> Result<Message, RecvTimeoutError>;
+    /// like `consume`, waiting for a message without a timeout so that an idle consumer isn't woken up for nothing.
+    /// return None when woken up by `wake` instead.  
+    /// the transports that can't wait without a timeout fall back on `consume`, returning None when it times out
+    fn consume_blocking(self: Arc<Self>) -> Result<Option<Message>, RecvError> {
+        match self.consume() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        }
+    }
+    /// make a `consume_blocking` call waiting for a message return None, e.g. so that it notices a shutdown.
+    /// a wake while nothing waits is kept for the next call
+    fn wake(self: Arc<Self>) {}
+    /// re-create the message channel once `consume` reported it disconnected, so that the next `consume` gets
+    /// the messages received from then on. called by the server before retrying, see `ConsumeRetryPolicy`.  
+    /// the transports whose channel can't be re-created do nothing
+    fn reattach(self: Arc<Self>) {}
+    /// return the messages received but not consumed yet, without waiting for more. e.g. to handle them
+    /// before shutting down
+    fn drain(self: Arc<Self>) -> Vec<Message>;
+    /// like `consume`, returning up to `max` of the messages already received at once rather than waiting for one,
+    /// e.g. to handle a burst in a tight loop under load. empty if there are none.  
+    /// the transports that can't take several messages at once return none, their messages are then consumed
+    /// one at a time
+    fn consume_batch(self: Arc<Self>, _max: usize) -> Vec<Message> {
+        Vec::new()
+    }
+    /// the largest payload a message received from a peer may have
+    fn max_payload_size(self: Arc<Self>) -> usize {
+        DEFAULT_MAX_PAYLOAD_SIZE
+    }
     /// start listening and accepting incoming connections
     fn listen_and_accept(self: Arc<Self>) -> Result<(), Box<dyn std::error::Error>>;
     /// dial a remote address
     /// this function should block the current thread until the connection is established 
     /// so that the caller can be sure that the connection is ready
     fn dial(self: &Arc<Self>, addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>>;
+    /// resolve a `host:port` string, e.g. "node1.internal:3000" or "[::1]:3000", and dial it.  
+    /// each resolved address is tried in turn until one connects
+    fn dial_host(self: &Arc<Self>, host: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut last_err: Box<dyn std::error::Error> =
+            Box::new(io::Error::new(io::ErrorKind::NotFound, format!("{} resolved to no address", host)));
+        for addr in host.to_socket_addrs()? {
+            match self.dial(addr) {
+                Ok(_) => return Ok(()),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
     /// dial a remote address with a maximum number of attempts
-    /// will perform an exponential backoff if the connection is not established
-    fn try_dial(self: &Arc<Self>, addr: SocketAddr, max_attemps: u8) -> Result<(), Box<dyn std::error::Error>>;
+    /// will perform an exponential backoff if the connection is not established, see `dial_backoff`
+    fn try_dial(self: &Arc<Self>, addr: SocketAddr, max_attemps: u8) -> Result<(), Box<dyn std::error::Error>> {
+        let mut attempts = 0;
+        loop {
+            match self.dial(addr) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    if attempts >= max_attemps {
+                        // stop trying
+                        println!("Error connecting to {}: {}", addr, e);
+                        return Err(e)
+                    } else {
+                        // exponential backoff
+                        let backoff = dial_backoff(attempts as u32, self.clone().max_dial_backoff(), jitter_factor());
+                        println!("Error connecting to {}. Retrying in {:?}", addr, backoff);
+                        attempts += 1;
+                        thread::sleep(backoff);
+                    }
+                }
+            }
+        }
+    }
+    /// send the buffer to a single connected peer over its existing connection.  
+    /// fail with `io::ErrorKind::NotConnected` if no peer is known by that address, without dialing it, so that
+    /// callers can tell a peer that is gone from a send that failed on the way
+    fn send_to(self: &Arc<Self>, addr: SocketAddr, buf: &[u8]) -> Result<(), io::Error>;
+    /// send the buffer to every connected peer. return the result of each send, by peer
+    fn broadcast(self: Arc<Self>, buf: &[u8]) -> Vec<(SocketAddr, Result<(), io::Error>)>;
+    /// return the number of connected peers
+    fn peer_count(self: Arc<Self>) -> usize;
+    /// return the addresses of the connected peers
+    fn peer_addrs(self: Arc<Self>) -> Vec<SocketAddr>;
     /// register a callback function to be called when a new peer is connected
     /// the returned boolean should indicate if the peer has been handled successfully. 
     /// if false, the peer will be closed and removed from the peers list
     /// TODO: can abstract the callback function?
-    fn register_on_peer(self: Arc<Self>, callback: Box<dyn Fn(Arc<RwLock<Self::Peer>>) -> bool + Sync + Send + 'static>);
-}
\ No newline at end of file
+    fn register_on_peer(self: Arc<Self>, callback: OnPeerFn<Self::Peer>);
+    /// stop taking new connections, e.g. for maintenance. the connected peers are kept and their messages still
+    /// processed. the incoming connections wait until `resume_accept`
+    fn pause_accept(self: Arc<Self>);
+    /// take new connections again after `pause_accept`
+    fn resume_accept(self: Arc<Self>);
+    /// whether new connections are taken, i.e. the accept loop started by `listen_and_accept` is running and
+    /// accepting is not paused
+    fn is_listening(self: Arc<Self>) -> bool;
+    /// the longest `try_dial` waits between two attempts. see `DEFAULT_MAX_DIAL_BACKOFF`
+    fn max_dial_backoff(self: Arc<Self>) -> Duration {
+        DEFAULT_MAX_DIAL_BACKOFF
+    }
+    /// the weight this node advertises to its peers, see `PeerLike::weight`
+    fn weight(self: Arc<Self>) -> u32 {
+        1
+    }
+    /// poll `is_listening` until it holds or the timeout is reached. return whether it holds, e.g. to dial a node
+    /// only once it is ready
+    fn wait_until_listening(self: Arc<Self>, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while !self.clone().is_listening() {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(LISTENING_POLL);
+        }
+
+        true
+    }
+    /// register a callback function to be called when the connection to a peer is gone,
+    /// either closed by the other side or failed. the peer has already been removed from the peers list
+    fn register_on_peer_disconnect(self: Arc<Self>, callback: OnPeerDisconnectFn);
+}
+
+/// how long `Transport::try_dial` waits after the failed attempt of the given number, counting from 0: one second
+/// doubled after each attempt, times `jitter` so that the nodes losing the same peer don't all dial it again at
+/// once, and never more than `max`
+pub fn dial_backoff(attempt: u32, max: Duration, jitter: f64) -> Duration {
+    let backoff = TRY_DIAL_BACKOFF.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+
+    backoff.mul_f64(jitter).min(max)
+}
+
+/// a random factor in [0.5, 1.5] for `dial_backoff`. 1 if no random bytes can be had
+fn jitter_factor() -> f64 {
+    let mut bytes = [0; 4];
+    match rustls::crypto::ring::default_provider().secure_random.fill(&mut bytes) {
+        Ok(_) => 0.5 + u32::from_be_bytes(bytes) as f64 / u32::MAX as f64,
+        Err(_) => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dial_backoff_jittered_and_capped() {
+        let max = Duration::from_secs(10);
+        for attempt in 0..8 {
+            // 1, 2, 4, 8, then capped
+            let base = Duration::from_secs(1 << attempt).min(max);
+            for _ in 0..20 {
+                let backoff = dial_backoff(attempt, max, jitter_factor());
+                assert!(backoff >= base / 2 && backoff <= base.mul_f64(1.5).min(max), "attempt {} waits {:?}", attempt, backoff);
+            }
+        }
+        assert_eq!(dial_backoff(2, max, 0.5), Duration::from_secs(2));
+        assert_eq!(dial_backoff(2, max, 1.5), Duration::from_secs(6));
+        assert_eq!(dial_backoff(3, max, 1.5), max);
+        assert_eq!(dial_backoff(40, max, 1.0), max);
+
+        // the nodes don't all wait the same
+        let waits: Vec<Duration> = (0..20).map(|_| dial_backoff(3, max, jitter_factor())).collect();
+        assert!(waits.iter().any(|wait| *wait != waits[0]));
+    }
+}